@@ -54,6 +54,7 @@
             root = ./.;
             fileset = lib.fileset.unions [
               (craneLibSlim.fileset.commonCargoSources ./.)
+              ./migrations
             ];
           };
         commonArgs = {
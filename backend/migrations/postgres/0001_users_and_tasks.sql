@@ -0,0 +1,19 @@
+CREATE TABLE users (
+    username TEXT PRIMARY KEY,
+    password_hash TEXT,
+    oidc_subject TEXT UNIQUE,
+    totp_secret TEXT,
+    recovery_code_hashes TEXT NOT NULL DEFAULT '[]' -- JSON array
+);
+
+CREATE TABLE tasks (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users (username) ON UPDATE CASCADE ON DELETE CASCADE,
+    category TEXT NOT NULL,
+    title TEXT NOT NULL,
+    text TEXT NOT NULL,
+    completed BIGINT NOT NULL, -- 0 or 1, to share the queries with SQLite
+    due BIGINT -- UNIX timestamp
+);
+
+CREATE INDEX tasks_username ON tasks (username);
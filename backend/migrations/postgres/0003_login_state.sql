@@ -0,0 +1,50 @@
+CREATE TABLE mfa_logins (
+    token_hash TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    device TEXT,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE oidc_states (
+    state TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    device TEXT,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE totp_steps (
+    username TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    PRIMARY KEY (username, step)
+);
+
+CREATE TABLE totp_enrollments (
+    username TEXT PRIMARY KEY,
+    secret TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE login_failures (
+    subject TEXT NOT NULL,
+    failed_at BIGINT NOT NULL -- UNIX timestamp in milliseconds
+);
+
+CREATE INDEX login_failures_subject ON login_failures (subject);
+
+CREATE TABLE lockout_levels (
+    subject TEXT PRIMARY KEY,
+    level BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE lockouts (
+    subject TEXT PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    entry TEXT NOT NULL -- JSON
+);
@@ -0,0 +1,3 @@
+ALTER TABLE tasks ADD COLUMN due_tz TEXT; -- IANA time zone name
+
+ALTER TABLE tasks ADD COLUMN all_day BIGINT NOT NULL DEFAULT 0; -- 0 or 1
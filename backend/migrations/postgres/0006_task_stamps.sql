@@ -0,0 +1,15 @@
+ALTER TABLE tasks ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0; -- UNIX timestamp
+
+ALTER TABLE tasks ADD COLUMN updated_at BIGINT NOT NULL DEFAULT 0; -- UNIX timestamp
+
+ALTER TABLE tasks ADD COLUMN completed_at BIGINT; -- UNIX timestamp
+
+ALTER TABLE tasks ADD COLUMN created_by TEXT; -- "session:<uuid>" or "api_token:<uuid>"
+
+ALTER TABLE tasks ADD COLUMN updated_by TEXT; -- "session:<uuid>" or "api_token:<uuid>"
+
+-- Existing tasks are taken to have been created, and completed if they are, now
+UPDATE tasks SET
+    created_at = CAST(EXTRACT(EPOCH FROM now()) AS BIGINT),
+    updated_at = CAST(EXTRACT(EPOCH FROM now()) AS BIGINT),
+    completed_at = CASE WHEN completed <> 0 THEN CAST(EXTRACT(EPOCH FROM now()) AS BIGINT) END;
@@ -0,0 +1,3 @@
+ALTER TABLE tasks ADD COLUMN parent_id TEXT REFERENCES tasks (id) ON DELETE RESTRICT; -- NULL for top-level tasks
+
+CREATE INDEX tasks_username_parent_id ON tasks (username, parent_id);
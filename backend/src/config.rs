@@ -0,0 +1,9 @@
+use std::{env, error::Error, fmt::Display, str::FromStr};
+
+pub fn env_parse<T: FromStr<Err: Display>>(name: &str, default: T) -> Result<T, Box<dyn Error>> {
+    env::var(name).map_or(Ok(default), |value| value.parse().map_err(|err| format!("Invalid {name}: {err}").into()))
+}
+
+pub fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value == "true")
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+// RFC 6902 JSON Patch, limited to flat documents such as tasks: paths may only point at top-level members
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+pub enum PatchError {
+    UnsupportedPath(String),
+    MissingMember(String),
+    TestFailed(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedPath(path) => {
+                write!(f, "Unsupported JSON Patch path {path:?}, paths must name a top-level field")
+            }
+            Self::MissingMember(path) => write!(f, "JSON Patch path {path:?} doesn't exist"),
+            Self::TestFailed(path) => write!(f, "JSON Patch test of {path:?} failed"),
+        }
+    }
+}
+
+// The member a JSON Pointer (RFC 6901) with a single reference token points at
+fn member(path: &str) -> Result<String, PatchError> {
+    match path.strip_prefix('/') {
+        Some(token) if !token.contains('/') => Ok(token.replace("~1", "/").replace("~0", "~")),
+        _ => Err(PatchError::UnsupportedPath(path.to_owned())),
+    }
+}
+
+fn take(document: &mut Map<String, Value>, path: &str) -> Result<Value, PatchError> {
+    document.remove(&member(path)?).ok_or_else(|| PatchError::MissingMember(path.to_owned()))
+}
+
+// Operations are applied in order and the document is left untouched if any of them fails
+pub fn apply(document: &Map<String, Value>, operations: Vec<Operation>) -> Result<Map<String, Value>, PatchError> {
+    let mut document = document.clone();
+    for operation in operations {
+        match operation {
+            Operation::Add {
+                path,
+                value,
+            } => {
+                document.insert(member(&path)?, value);
+            }
+            Operation::Remove {
+                path,
+            } => {
+                take(&mut document, &path)?;
+            }
+            Operation::Replace {
+                path,
+                value,
+            } => {
+                take(&mut document, &path)?;
+                document.insert(member(&path)?, value);
+            }
+            Operation::Move {
+                from,
+                path,
+            } => {
+                let value = take(&mut document, &from)?;
+                document.insert(member(&path)?, value);
+            }
+            Operation::Copy {
+                from,
+                path,
+            } => {
+                let value = document.get(&member(&from)?).cloned().ok_or(PatchError::MissingMember(from))?;
+                document.insert(member(&path)?, value);
+            }
+            Operation::Test {
+                path,
+                value,
+            } => {
+                if document.get(&member(&path)?) != Some(&value) {
+                    return Err(PatchError::TestFailed(path));
+                }
+            }
+        }
+    }
+    Ok(document)
+}
@@ -0,0 +1,274 @@
+use std::{collections::HashMap, env, error::Error, fs};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use jsonwebtoken::{
+    Algorithm,
+    DecodingKey,
+    EncodingKey,
+    Header,
+    Validation,
+    errors::{ErrorKind, Result as JWTResult},
+    jwk::{
+        AlgorithmParameters,
+        CommonParameters,
+        EllipticCurve,
+        Jwk,
+        JwkSet,
+        KeyAlgorithm,
+        OctetKeyPairParameters,
+        OctetKeyPairType,
+        PublicKeyUse,
+        RSAKeyParameters,
+        RSAKeyType,
+        ThumbprintHash,
+    },
+};
+use rsa::{
+    RsaPrivateKey,
+    RsaPublicKey,
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    traits::PublicKeyParts,
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    jwk: Option<Jwk>, // Only asymmetric keys are published
+}
+
+pub struct JwtKeys {
+    key_id: String,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verification_keys: HashMap<String, VerificationKey>,
+}
+
+impl JwtKeys {
+    // JWT_ALGORITHM: HS256 (default), RS256 or EdDSA
+    // JWT_SECRET: HMAC secret, required for HS256
+    // JWT_PRIVATE_KEY_FILE: PEM private key, required for RS256 and EdDSA
+    // JWT_KEY_ID: `kid` of the signing key, defaults to the RFC 7638 thumbprint (or "default" for HS256)
+    // JWT_VERIFICATION_KEYS: Retired keys that are still accepted, as comma separated `<kid>=<secret>` for HS256 or
+    // `<kid>=<PEM public key file>` otherwise
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let algorithm = match env::var("JWT_ALGORITHM").as_deref() {
+            Ok("HS256") | Err(_) => Algorithm::HS256,
+            Ok("RS256") => Algorithm::RS256,
+            Ok("EdDSA") => Algorithm::EdDSA,
+            Ok(other) => return Err(format!("Unsupported JWT_ALGORITHM {other:?}").into()),
+        };
+        let (encoding_key, verification_key) = if algorithm == Algorithm::HS256 {
+            let secret = env::var("JWT_SECRET").map_err(|_| "JWT_SECRET must be set when JWT_ALGORITHM is HS256")?;
+            if secret.is_empty() {
+                return Err("JWT_SECRET must not be empty".into());
+            }
+            (EncodingKey::from_secret(secret.as_bytes()), hmac_key(&secret))
+        } else {
+            let path = env::var("JWT_PRIVATE_KEY_FILE")
+                .map_err(|_| format!("JWT_PRIVATE_KEY_FILE must be set when JWT_ALGORITHM is {algorithm:?}"))?;
+            let pem = fs::read_to_string(&path).map_err(|err| format!("Failed to read {path}: {err}"))?;
+            let (encoding_key, jwk) = if algorithm == Algorithm::RS256 {
+                let private_key =
+                    RsaPrivateKey::from_pkcs8_pem(&pem).or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))?;
+                (EncodingKey::from_rsa_pem(pem.as_bytes())?, rsa_jwk(&private_key.to_public_key()))
+            } else {
+                let signing_key = SigningKey::from_pkcs8_pem(&pem)?;
+                (EncodingKey::from_ed_pem(pem.as_bytes())?, ed25519_jwk(&signing_key.verifying_key()))
+            };
+            (encoding_key, VerificationKey {
+                algorithm,
+                decoding_key: DecodingKey::from_jwk(&jwk)?,
+                jwk: Some(jwk),
+            })
+        };
+        let key_id = env::var("JWT_KEY_ID").unwrap_or_else(|_| {
+            verification_key
+                .jwk
+                .as_ref()
+                .map_or_else(|| "default".to_owned(), |jwk| jwk.thumbprint(ThumbprintHash::SHA256))
+        });
+        let mut verification_keys = match env::var("JWT_VERIFICATION_KEYS") {
+            Ok(keys) => retired_keys(&keys, algorithm)?,
+            Err(_) => HashMap::new(),
+        };
+        verification_keys.insert(key_id.clone(), verification_key);
+        Ok(Self {
+            key_id,
+            algorithm,
+            encoding_key,
+            verification_keys,
+        })
+    }
+
+    // HS256 with just `secret`, for tests that shouldn't depend on the environment
+    #[cfg(test)]
+    pub fn hs256(secret: &str) -> Self {
+        let key_id = "default".to_owned();
+        Self {
+            key_id: key_id.clone(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            verification_keys: HashMap::from([(key_id, hmac_key(secret))]),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> JWTResult<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.key_id.clone());
+        jsonwebtoken::encode(&header, claims, &self.encoding_key)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, jwt: &str) -> JWTResult<T> {
+        let header = jsonwebtoken::decode_header(jwt)?;
+        // JWTs issued before key IDs were introduced don't have one. They're taken to be signed with the only key there
+        // is, but once keys are rotated, there's no telling which one they're meant for.
+        let key_id = match header.kid.as_deref() {
+            Some(key_id) => key_id,
+            None if self.verification_keys.len() == 1 => &self.key_id,
+            None => return Err(ErrorKind::InvalidToken.into()),
+        };
+        let key = self.verification_keys.get(key_id).ok_or(ErrorKind::InvalidSignature)?;
+        jsonwebtoken::decode(jwt, &key.decoding_key, &Validation::new(key.algorithm)).map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification_keys
+                .iter()
+                .filter_map(|(key_id, key)| {
+                    let mut jwk = key.jwk.clone()?;
+                    jwk.common.key_id = Some(key_id.clone());
+                    Some(jwk)
+                })
+                .collect(),
+        }
+    }
+}
+
+// Parses JWT_VERIFICATION_KEYS, whose keys are secrets if `algorithm` is HS256 and public key files otherwise
+fn retired_keys(keys: &str, algorithm: Algorithm) -> Result<HashMap<String, VerificationKey>, Box<dyn Error>> {
+    let mut verification_keys = HashMap::new();
+    for entry in keys.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let expected = if algorithm == Algorithm::HS256 { "<kid>=<secret>" } else { "<kid>=<file>" };
+        let (kid, value) = entry
+            .split_once('=')
+            .filter(|(_, value)| !value.is_empty())
+            // Not quoting the entry, it may be a secret
+            .ok_or_else(|| format!("Invalid JWT_VERIFICATION_KEYS entry, expected {expected}"))?;
+        let key = if algorithm == Algorithm::HS256 {
+            hmac_key(value)
+        } else {
+            let pem = fs::read_to_string(value).map_err(|err| format!("Failed to read {value}: {err}"))?;
+            public_key_from_pem(&pem)?
+        };
+        verification_keys.insert(kid.to_owned(), key);
+    }
+    Ok(verification_keys)
+}
+
+fn public_key_from_pem(pem: &str) -> Result<VerificationKey, Box<dyn Error>> {
+    let (algorithm, jwk) =
+        if let Ok(public_key) = RsaPublicKey::from_public_key_pem(pem).or_else(|_| RsaPublicKey::from_pkcs1_pem(pem)) {
+            (Algorithm::RS256, rsa_jwk(&public_key))
+        } else {
+            (Algorithm::EdDSA, ed25519_jwk(&VerifyingKey::from_public_key_pem(pem)?))
+        };
+    Ok(VerificationKey {
+        algorithm,
+        decoding_key: DecodingKey::from_jwk(&jwk)?,
+        jwk: Some(jwk),
+    })
+}
+
+fn hmac_key(secret: &str) -> VerificationKey {
+    VerificationKey {
+        algorithm: Algorithm::HS256,
+        decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+        jwk: None,
+    }
+}
+
+fn rsa_jwk(public_key: &RsaPublicKey) -> Jwk {
+    Jwk {
+        common: signature_key_parameters(KeyAlgorithm::RS256),
+        algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+        }),
+    }
+}
+
+fn ed25519_jwk(public_key: &VerifyingKey) -> Jwk {
+    Jwk {
+        common: signature_key_parameters(KeyAlgorithm::EdDSA),
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(public_key.as_bytes()),
+        }),
+    }
+}
+
+fn signature_key_parameters(key_algorithm: KeyAlgorithm) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(key_algorithm),
+        ..CommonParameters::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Claims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "alice".to_owned(),
+            exp: i64::MAX,
+        }
+    }
+
+    // Signs with `secret` under `key_id`, or without a `kid` if it's None
+    fn hs256_jwt(secret: &str, key_id: Option<&str>) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = key_id.map(str::to_owned);
+        jsonwebtoken::encode(&header, &claims(), &EncodingKey::from_secret(secret.as_bytes())).expect("JWT")
+    }
+
+    #[test]
+    fn retired_secrets_are_accepted_by_kid() {
+        let mut keys = JwtKeys::hs256("new-secret");
+        keys.verification_keys
+            .extend(retired_keys("old=old-secret, older=older=secret", Algorithm::HS256).expect("Keys"));
+        assert_eq!(keys.decode::<Claims>(&keys.encode(&claims()).expect("JWT")).expect("Current key"), claims());
+        assert_eq!(keys.decode::<Claims>(&hs256_jwt("old-secret", Some("old"))).expect("Retired key"), claims());
+        assert_eq!(keys.decode::<Claims>(&hs256_jwt("older=secret", Some("older"))).expect("Retired key"), claims());
+        assert!(keys.decode::<Claims>(&hs256_jwt("old-secret", Some("default"))).is_err());
+        assert!(keys.decode::<Claims>(&hs256_jwt("old-secret", Some("unknown"))).is_err());
+        assert!(retired_keys("old", Algorithm::HS256).is_err());
+        assert!(retired_keys("old=", Algorithm::HS256).is_err());
+    }
+
+    #[test]
+    fn tokens_without_kid_are_only_accepted_without_retired_keys() {
+        let mut keys = JwtKeys::hs256("secret");
+        assert_eq!(keys.decode::<Claims>(&hs256_jwt("secret", None)).expect("Only key"), claims());
+        keys.verification_keys.extend(retired_keys("old=old-secret", Algorithm::HS256).expect("Keys"));
+        let err = keys.decode::<Claims>(&hs256_jwt("secret", None)).map(|_| ()).expect_err("Ambiguous key");
+        assert_eq!(*err.kind(), ErrorKind::InvalidToken);
+        assert!(keys.decode::<Claims>(&hs256_jwt("old-secret", None)).is_err());
+    }
+}
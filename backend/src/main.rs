@@ -1,52 +1,174 @@
-use std::{env, error::Error};
+mod config;
+mod json_patch;
+mod jwt;
+mod mfa;
+mod oidc;
+mod password;
+mod store;
+#[cfg(test)]
+mod tests;
+mod throttle;
+mod validation;
+
+use std::{
+    env,
+    error::Error,
+    iter,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
     Router,
+    body::{self, Body},
     extract::{
+        ConnectInfo,
+        FromRequestParts,
         Json,
         Path,
         Query,
+        Request,
         State,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
     },
-    http::StatusCode,
-    response::IntoResponse,
+    http::{
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+        StatusCode,
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, RETRY_AFTER, SEC_WEBSOCKET_PROTOCOL, USER_AGENT},
+        request::Parts,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
     routing,
 };
-use chrono::{Duration, Utc};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
 use futures::{SinkExt, StreamExt, stream::SplitSink};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, errors::Result as JWTResult};
-use redis::{AsyncCommands, Client as RedisClient};
-use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use jsonwebtoken::{errors::Result as JWTResult, jwk::JwkSet};
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::TcpListener,
+    task,
+    time::{self, Instant},
+};
 use tower_http::cors::{Any, CorsLayer};
+use tracing::warn;
 use uuid::Uuid;
 
-type Pool = bb8::Pool<RedisClient>;
+use crate::{
+    config::env_flag,
+    json_patch::PatchError,
+    jwt::JwtKeys,
+    mfa::TotpSettings,
+    oidc::{IdTokenClaims, OidcProvider},
+    password::PasswordHashing,
+    store::{
+        ApiToken,
+        ApiTokenRecord,
+        ApiTokenScope,
+        AuditEntry,
+        AuditEvent,
+        Author,
+        Event,
+        EventId,
+        JwtStatus,
+        MfaLogin,
+        Nullable,
+        OidcState,
+        RefreshToken,
+        Session,
+        SessionEvent,
+        StoreError,
+        Task,
+        TaskStore,
+        TaskUpdate,
+        User,
+        hierarchy::{self, TaskScope},
+    },
+    throttle::{LoginThrottle, Subject},
+    validation::{CredentialPolicy, ValidationFailure},
+};
+
+type HandlerResult<T> = Result<(StatusCode, T), HandlerError>;
+
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+
+const REFRESH_TOKEN_TTL_SECONDS: u64 = 30 * 24 * 60 * 60; // 30 days, renewed on every rotation
+
+const MFA_TOKEN_TTL_SECONDS: u64 = 5 * 60;
+
+const TOTP_ENROLLMENT_TTL_SECONDS: u64 = 10 * 60;
+
+const TOTP_STEP_TTL_SECONDS: u64 = 90; // Covers the step before and after the current one
+
+const OIDC_STATE_TTL_SECONDS: u64 = 10 * 60;
+
+const OIDC_USERNAME_ATTEMPTS: usize = 5;
+
+const TASK_UPDATE_ATTEMPTS: usize = 5; // Before giving up on a task that keeps changing between reading and updating it
+
+// Accounts without a local password confirm sensitive changes by having logged in recently
+const REAUTHENTICATION_WINDOW_SECONDS: i64 = 5 * 60;
 
-type HandlerResult<T> = Result<(StatusCode, T), (StatusCode, String)>;
+const API_TOKEN_PREFIX: &str = "tt_"; // Tells API tokens apart from JWTs
+
+const API_TOKEN_NAME_MAX_LENGTH: usize = 64;
+
+const AUDIT_LOG_LENGTH: usize = 10_000;
+
+const WEBSOCKET_JWT_EXPIRED_CODE: u16 = 4001; // Private-use close code (4000-4999)
+
+const WEBSOCKET_SESSION_REVOKED_CODE: u16 = 4002;
+
+const WEBSOCKET_ACCOUNT_DELETED_CODE: u16 = 4003;
+
+const WEBSOCKET_BEARER_PROTOCOL: &str = "bearer"; // Sec-WebSocket-Protocol: bearer, <jwt>
+
+const LEGACY_JWT_BODY_LIMIT: usize = 2 * 1024 * 1024; // Same as the default Json extractor limit
+
+enum HandlerError {
+    Message(StatusCode, String),
+    Validation(Vec<ValidationFailure>),
+    TooManyRequests { retry_after: u64, message: String },
+}
+
+#[derive(Serialize)]
+struct ValidationErrorResponse {
+    errors: Vec<ValidationFailure>,
+}
 
 #[derive(Clone)]
 struct AppState {
-    redis_client: RedisClient,
-    pool: Pool,
-    jwt_secret: String,
+    store: Arc<dyn TaskStore>,
+    jwt_keys: Arc<JwtKeys>,
+    credential_policy: Arc<CredentialPolicy>,
+    password_hashing: Arc<PasswordHashing>,
+    login_throttle: Arc<LoginThrottle>,
+    totp_settings: Arc<TotpSettings>,
+    oidc_provider: Option<Arc<OidcProvider>>,
+    trust_forwarded_for: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-struct User {
+// Any caller, authenticated by a JWT or an API token
+struct AuthUser {
     username: String,
-    password_hash: String,
+    credential: Credential,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Task {
-    id: Uuid,
-    category: String,
-    title: String,
-    text: String,
-    completed: bool,
-    due: Option<i32>, // UNIX timestamp
+enum Credential {
+    Session { session_id: Uuid, exp: i64 },
+    ApiToken { token_id: Uuid, scope: ApiTokenScope, expires_at: Option<i64> },
+}
+
+// Only callers authenticated by a JWT, API tokens can't manage the account they belong to
+struct SessionUser {
+    username: String,
+    jti: Uuid,
+    session_id: Uuid,
+    exp: i64, // expiration timestamp
 }
 
 #[derive(Serialize, Deserialize)]
@@ -54,96 +176,235 @@ struct Claims {
     sub: String, // username
     exp: i64,    // expiration timestamp
     iat: i64,    // issued at timestamp
+    jti: Uuid,   // JWT ID, used for revocation
+    sid: Uuid,   // session ID
+}
+
+struct TokenPair {
+    jwt: String,
+    refresh_token: String,
 }
 
 #[derive(Deserialize)]
 struct RegisterRequest {
     username: String,
     password: String,
+    device: Option<String>,
 }
 
 #[derive(Serialize)]
 struct RegisterResponse {
     jwt: String,
+    refresh_token: String,
 }
 
 #[derive(Deserialize)]
 struct LoginRequest {
-    username: Option<String>,
-    password: Option<String>,
-    jwt: Option<String>,
+    username: String,
+    password: String,
+    device: Option<String>,
 }
 
 #[derive(Serialize)]
 struct LoginResponse {
     jwt: String,
+    refresh_token: String,
     username: String,
 }
 
+#[derive(Serialize)]
+struct MfaRequiredResponse {
+    mfa_token: String,
+    expires_in: u64, // Seconds
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum LoginOutcome {
+    Authenticated(LoginResponse),
+    MfaRequired(MfaRequiredResponse),
+}
+
 #[derive(Deserialize)]
-struct LogoutRequest {
-    jwt: String,
+struct MfaLoginRequest {
+    mfa_token: String,
+    code: String, // TOTP or recovery code
 }
 
+type MfaLoginResponse = LoginResponse;
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+type RefreshResponse = LoginResponse;
+
 type LogoutResponse = ();
 
+#[derive(Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: Session,
+    current: bool,
+}
+
+type GetSessionsResponse = Vec<SessionResponse>;
+
+type RevokeSessionResponse = ();
+
+type RevokeAllSessionsResponse = ();
+
 #[derive(Deserialize)]
-struct GetAllTasksRequest {
-    jwt: String,
+struct ChangePasswordRequest {
+    old_password: Option<String>, // Only needed if the account has a password
+    new_password: String,
+}
+
+type ChangePasswordResponse = RegisterResponse;
+
+#[derive(Deserialize)]
+struct ChangeUsernameRequest {
+    username: String,
+    password: Option<String>,
+}
+
+type ChangeUsernameResponse = LoginResponse;
+
+#[derive(Deserialize)]
+struct DeleteAccountRequest {
+    password: Option<String>,
+}
+
+type DeleteAccountResponse = ();
+
+#[derive(Serialize)]
+struct EnrollTotpResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+struct ConfirmTotpRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct ConfirmTotpResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct DisableTotpRequest {
+    password: Option<String>,
+}
+
+type DisableTotpResponse = ();
+
+#[derive(Deserialize)]
+struct CreateApiTokenRequest {
+    name: String,
+    scope: ApiTokenScope,
+    expires_at: Option<i64>, // UNIX timestamp, never expires if null
+}
+
+#[derive(Serialize)]
+struct CreateApiTokenResponse {
+    token: String,
+    #[serde(flatten)]
+    api_token: ApiToken,
 }
 
+type GetApiTokensResponse = Vec<ApiToken>;
+
+type RevokeApiTokenResponse = ();
+
 type GetAllTasksResponse = Vec<Task>;
 
 #[derive(Deserialize)]
 struct CreateTaskRequest {
-    jwt: String,
     category: String,
     title: String,
     text: String,
     completed: bool,
-    due: Option<i32>, // UNIX timestamp
+    #[serde(default, deserialize_with = "deserialize_due")]
+    due: Option<i64>,
+    due_tz: Option<String>,
+    #[serde(default)]
+    all_day: bool,
+    parent_id: Option<Uuid>,
 }
 
 type CreateTaskResponse = ();
 
-#[derive(Deserialize)]
-struct GetTaskRequest {
-    jwt: String,
-}
-
 type GetTaskResponse = Task;
 
 #[derive(Deserialize)]
 struct UpdateTaskRequest {
-    jwt: String,
+    // Absent or null leaves these alone, they can't be cleared
     category: Option<String>,
     title: Option<String>,
     text: Option<String>,
     completed: Option<bool>,
-    due: Option<i32>, // UNIX timestamp
+    all_day: Option<bool>,
+    // Absent leaves these alone, null clears them
+    #[serde(default, deserialize_with = "deserialize_nullable_due")]
+    due: Nullable<i64>,
+    #[serde(default)]
+    due_tz: Nullable<String>,
+}
+
+// What a JSON Patch turns the task into
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchedTask {
+    id: Uuid,
+    category: String,
+    title: String,
+    text: String,
+    completed: bool,
+    #[serde(default, deserialize_with = "deserialize_due")]
+    due: Option<i64>,
+    #[serde(default)]
+    due_tz: Option<String>,
+    #[serde(default)]
+    all_day: bool,
+    parent_id: Option<Uuid>,
+    version: u64,
+    created_at: i64,
+    updated_at: i64,
+    completed_at: Option<i64>,
+    created_by: Option<Author>,
+    updated_by: Option<Author>,
+}
+
+// `due` is either a UNIX timestamp or an RFC 3339 string, a date-time or a full date (read as midnight UTC)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Due {
+    Timestamp(i64),
+    Rfc3339(String),
 }
 
 type UpdateTaskResponse = ();
 
 #[derive(Deserialize)]
-struct DeleteTaskRequest {
-    jwt: String,
+struct MoveTaskRequest {
+    parent_id: Option<Uuid>, // null moves the task to the top level
 }
 
-type DeleteTaskResponse = ();
+type MoveTaskResponse = ();
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(tag = "type")]
-#[allow(clippy::enum_variant_names)]
-enum Notification {
-    #[serde(rename = "task_created")]
-    TaskCreated { task: Task },
-    #[serde(rename = "task_updated")]
-    TaskUpdated { task: Task },
-    #[serde(rename = "task_deleted")]
-    TaskDeleted { task_id: Uuid },
+type GetSubtasksResponse = Vec<Task>;
+
+#[derive(Deserialize)]
+struct DeleteTaskQuery {
+    #[serde(default)]
+    with_subtasks: bool,
 }
 
+type DeleteTaskResponse = ();
+
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 enum ClientWebSocketMessage {
@@ -156,399 +417,1566 @@ enum ClientWebSocketMessage {
 enum ServerWebSocketMessage {
     #[serde(rename = "error")]
     Error { message: String },
+    #[serde(rename = "jwt_refreshed")]
+    JwtRefreshed { exp: i64 },
 }
 
 #[derive(Deserialize)]
-struct WebsocketQuery {
+struct OidcLoginQuery {
+    device: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OidcCallbackQuery {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OidcLoginFragment {
     jwt: String,
+    refresh_token: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct LegacyJwtQuery {
+    jwt: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebSocketQuery {
+    last_event_id: Option<EventId>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let redis_client =
-        RedisClient::open(env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned()))?;
-    let pool = Pool::builder().build(redis_client.clone()).await?;
-    let jwt_secret = env::var("JWT_SECRET").unwrap_or_else(|_| "dont-forget-to-remove-me".to_owned()); // TODO: Remove fallback to hardcoded secret!!!
+    tracing_subscriber::fmt().init();
+    let store = store::from_env().await?;
+    let jwt_keys = Arc::new(JwtKeys::from_env()?);
+    let credential_policy = Arc::new(CredentialPolicy::from_env()?);
+    let password_hashing = Arc::new(PasswordHashing::from_env()?);
+    let login_throttle = Arc::new(LoginThrottle::from_env()?);
+    let totp_settings = Arc::new(TotpSettings::from_env()?);
+    let oidc_provider = OidcProvider::from_env().await?.map(Arc::new);
+    let password_login = !env_flag("DISABLE_PASSWORD_LOGIN");
+    if !password_login && oidc_provider.is_none() {
+        return Err("DISABLE_PASSWORD_LOGIN requires OIDC_ISSUER_URL".into());
+    }
+    let trust_forwarded_for = env_flag("TRUST_FORWARDED_FOR");
     let cors = CorsLayer::new()
         .allow_origin([env::var("FRONTEND_URL").unwrap_or_else(|_| "127.0.0.1:3000".to_owned()).parse()?])
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([ETAG]); // Lets the frontend read task versions
+    let legacy_jwt_transport = env_flag("LEGACY_JWT_TRANSPORT"); // TODO: Remove in the next release
+    let mut router = routes(password_login);
+    if legacy_jwt_transport {
+        router = router.layer(middleware::from_fn(legacy_jwt_transport_middleware));
+    }
+    let router = router.layer(cors).with_state(AppState {
+        store,
+        jwt_keys,
+        credential_policy,
+        password_hashing,
+        login_throttle,
+        totp_settings,
+        oidc_provider,
+        trust_forwarded_for,
+    });
+    let listener = TcpListener::bind(env::var("BACKEND_URL").unwrap_or_else(|_| "127.0.0.1:6767".to_owned())).await?;
+    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
+
+// Every route, the password login ones only with password login enabled
+fn routes(password_login: bool) -> Router<AppState> {
     let router = Router::new()
-        .route("/auth/register", routing::post(register_handler))
-        .route("/auth/login", routing::post(login_handler))
+        .route("/auth/oidc/login", routing::get(oidc_login_handler))
+        .route("/auth/oidc/callback", routing::get(oidc_callback_handler))
+        .route("/auth/refresh", routing::post(refresh_handler))
         .route("/auth/logout", routing::post(logout_handler))
+        .route("/auth/sessions", routing::get(get_sessions_handler).delete(revoke_all_sessions_handler))
+        .route("/auth/sessions/{id}", routing::delete(revoke_session_handler))
+        .route("/auth/username", routing::post(change_username_handler))
+        .route("/auth/account", routing::delete(delete_account_handler))
+        .route("/auth/totp", routing::delete(disable_totp_handler))
+        .route("/auth/totp/enroll", routing::post(enroll_totp_handler))
+        .route("/auth/totp/confirm", routing::post(confirm_totp_handler))
+        .route("/auth/api-tokens", routing::get(get_api_tokens_handler).post(create_api_token_handler))
+        .route("/auth/api-tokens/{id}", routing::delete(revoke_api_token_handler))
         .route("/task", routing::get(get_all_tasks_handler).post(create_task_handler))
-        .route("/task/{id}", routing::get(get_task_handler).post(update_task_handler).delete(delete_task_handler))
+        .route(
+            "/task/{id}",
+            routing::get(get_task_handler)
+                .patch(update_task_handler)
+                .post(update_task_handler) // Kept for older clients
+                .delete(delete_task_handler),
+        )
+        .route("/task/{id}/subtasks", routing::get(get_subtasks_handler))
+        .route("/task/{id}/move", routing::post(move_task_handler))
         .route("/websocket", routing::get(websocket_handler))
-        .layer(cors)
-        .with_state(AppState {
-            redis_client,
-            pool,
-            jwt_secret,
-        });
-    let listener = TcpListener::bind(env::var("BACKEND_URL").unwrap_or_else(|_| "127.0.0.1:6767".to_owned())).await?;
-    axum::serve(listener, router).await?;
-    Ok(())
+        .route("/.well-known/jwks.json", routing::get(jwks_handler));
+    if !password_login {
+        return router;
+    }
+    router
+        .route("/auth/register", routing::post(register_handler))
+        .route("/auth/login", routing::post(login_handler))
+        .route("/auth/login/mfa", routing::post(mfa_login_handler))
+        .route("/auth/password", routing::post(change_password_handler))
+}
+
+impl From<(StatusCode, String)> for HandlerError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self::Message(status, message)
+    }
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Message(status, message) => (status, message).into_response(),
+            Self::Validation(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ValidationErrorResponse {
+                    errors,
+                }),
+            )
+                .into_response(),
+            Self::TooManyRequests {
+                retry_after,
+                message,
+            } => (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())], message).into_response(),
+        }
+    }
 }
 
 fn internal_error<E: Error>(err: E) -> (StatusCode, String) {
     (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
 }
 
-fn generate_jwt(secret: &str, username: &str) -> JWTResult<String> {
+fn store_error(err: StoreError) -> (StatusCode, String) {
+    match err {
+        StoreError::Conflict | StoreError::UsernameTaken | StoreError::HasSubtasks => {
+            (StatusCode::CONFLICT, err.to_string())
+        }
+        StoreError::VersionMismatch(_) => (StatusCode::PRECONDITION_FAILED, err.to_string()),
+        StoreError::UnknownParent | StoreError::ParentCycle => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+        StoreError::Backend(_) | StoreError::Json(_) => internal_error(err),
+    }
+}
+
+fn generate_jwt(keys: &JwtKeys, username: &str, session_id: Uuid) -> JWTResult<String> {
     let now = Utc::now();
-    let expiration = now + Duration::hours(1);
-    jsonwebtoken::encode(
-        &Header::default(),
-        &Claims {
-            sub: username.to_owned(),
-            exp: expiration.timestamp(),
-            iat: now.timestamp(),
+    let expiration = now + Duration::minutes(ACCESS_TOKEN_TTL_MINUTES);
+    keys.encode(&Claims {
+        sub: username.to_owned(),
+        exp: expiration.timestamp(),
+        iat: now.timestamp(),
+        jti: Uuid::new_v4(),
+        sid: session_id,
+    })
+}
+
+fn validate_jwt(keys: &JwtKeys, jwt: &str) -> Result<Claims, (StatusCode, String)> {
+    keys.decode(jwt).map_err(|err| (StatusCode::UNAUTHORIZED, format!("Invalid JWT: {err}")))
+}
+
+async fn authorize_jwt(state: &AppState, jwt: &str) -> Result<Claims, (StatusCode, String)> {
+    let jwt_data = validate_jwt(&state.jwt_keys, jwt)?;
+    match state.store.jwt_status(jwt_data.jti, jwt_data.sid).await.map_err(store_error)? {
+        JwtStatus::Valid => Ok(jwt_data),
+        JwtStatus::Revoked => Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned())),
+        JwtStatus::SessionRevoked => Err((StatusCode::UNAUTHORIZED, "Session has been revoked".to_owned())),
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Only trust X-Forwarded-For behind a reverse proxy, its last entry is the one appended by the proxy
+fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    trust_forwarded_for
+        .then(|| headers.get("x-forwarded-for")?.to_str().ok()?.rsplit(',').next()?.trim().parse().ok())
+        .flatten()
+        .unwrap_or_else(|| peer.ip())
+}
+
+async fn audit(state: &AppState, event: AuditEvent) -> Result<(), (StatusCode, String)> {
+    let entry = AuditEntry {
+        timestamp: Utc::now().timestamp(),
+        event,
+    };
+    state.store.append_audit_entry(&entry, AUDIT_LOG_LENGTH).await.map_err(store_error)
+}
+
+// Password hashes are deliberately slow, so keep them off the async worker threads
+async fn verify_password(password: String, password_hash: Option<String>) -> Result<bool, (StatusCode, String)> {
+    let Some(password_hash) = password_hash else {
+        return Ok(false);
+    };
+    task::spawn_blocking(move || PasswordHashing::verify(&password, &password_hash))
+        .await
+        .map_err(internal_error)?
+        .map_err(internal_error)
+}
+
+async fn hash_password(state: &AppState, password: String) -> Result<String, (StatusCode, String)> {
+    let password_hashing = Arc::clone(&state.password_hashing);
+    task::spawn_blocking(move || password_hashing.hash(&password))
+        .await
+        .map_err(internal_error)?
+        .map_err(internal_error)
+}
+
+// Upgrades the hash of a password that was just verified to the current algorithm and parameters. Skipped if the
+// password changed meanwhile.
+async fn rehash_password(state: &AppState, user: &User, password: String) -> Result<(), (StatusCode, String)> {
+    let Some(old_password_hash) = user.password_hash.as_deref() else {
+        return Ok(());
+    };
+    if !state.password_hashing.needs_rehash(old_password_hash) {
+        return Ok(());
+    }
+    let new_password_hash = hash_password(state, password).await?;
+    state
+        .store
+        .replace_password_hash(&user.username, Some(old_password_hash), &new_password_hash)
+        .await
+        .map_err(store_error)?;
+    Ok(())
+}
+
+async fn get_user(state: &AppState, username: &str) -> Result<User, (StatusCode, String)> {
+    state
+        .store
+        .get_user(username)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "User not found".to_owned()))
+}
+
+// For endpoints that make the user confirm it's them: by re-entering their password, or if they don't have one, by
+// having logged in recently. Wrong passwords count towards the same lockouts as failed logins.
+async fn verify_user(
+    state: &AppState,
+    auth: &SessionUser,
+    ip: IpAddr,
+    password: Option<String>,
+) -> Result<User, HandlerError> {
+    let user = get_user(state, &auth.username).await?;
+    if user.password_hash.is_none() {
+        let session = state
+            .store
+            .get_session(auth.session_id)
+            .await
+            .map_err(store_error)?
+            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Session has been revoked".to_owned()))?;
+        if Utc::now().timestamp() - session.created_at > REAUTHENTICATION_WINDOW_SECONDS {
+            return Err((StatusCode::FORBIDDEN, "Log in again to confirm this change".to_owned()).into());
+        }
+        return Ok(user);
+    }
+    let subjects = [Subject::Account(&auth.username), Subject::Ip(ip)];
+    if let Some(retry_after) = state.login_throttle.locked_out(&*state.store, &subjects).await.map_err(store_error)? {
+        return Err(HandlerError::TooManyRequests {
+            retry_after,
+            message: "Too many failed login attempts".to_owned(),
+        });
+    }
+    if !verify_password(password.unwrap_or_default(), user.password_hash.clone()).await? {
+        return Err(record_login_failure(state, &subjects, ip).await?.map_or_else(
+            || (StatusCode::FORBIDDEN, "Invalid password".to_owned()).into(),
+            |retry_after| HandlerError::TooManyRequests {
+                retry_after,
+                message: "Too many failed login attempts".to_owned(),
+            },
+        ));
+    }
+    state.login_throttle.record_success(&*state.store, &auth.username).await.map_err(store_error)?;
+    Ok(user)
+}
+
+// Records a failed password or second factor and returns the error to respond with
+async fn login_failure(
+    state: &AppState,
+    subjects: &[Subject<'_>],
+    ip: IpAddr,
+) -> Result<HandlerError, (StatusCode, String)> {
+    Ok(record_login_failure(state, subjects, ip).await?.map_or_else(
+        || (StatusCode::UNAUTHORIZED, "Invalid username or password".to_owned()).into(),
+        |retry_after| HandlerError::TooManyRequests {
+            retry_after,
+            message: "Too many failed login attempts".to_owned(),
+        },
+    ))
+}
+
+// Returns how long the subjects are locked out for in seconds, if the failure locked any of them out
+async fn record_login_failure(
+    state: &AppState,
+    subjects: &[Subject<'_>],
+    ip: IpAddr,
+) -> Result<Option<u64>, (StatusCode, String)> {
+    let lockouts = state.login_throttle.record_failure(&*state.store, subjects).await.map_err(store_error)?;
+    for &(subject, lockout_seconds) in &lockouts {
+        let event = match subject {
+            Subject::Account(username) => AuditEvent::AccountLocked {
+                username: username.to_owned(),
+                ip,
+                lockout_seconds,
+            },
+            Subject::Ip(ip) => AuditEvent::IpLocked {
+                ip,
+                lockout_seconds,
+            },
+        };
+        audit(state, event).await?;
+    }
+    Ok(lockouts.iter().map(|&(_, lockout_seconds)| lockout_seconds).max())
+}
+
+// The password was correct, the opaque MFA token stands in for it until the second factor is verified
+async fn start_mfa_login(
+    state: &AppState,
+    username: &str,
+    device: Option<String>,
+) -> Result<String, (StatusCode, String)> {
+    let mfa_token = random_token();
+    let mfa_login = MfaLogin {
+        username: username.to_owned(),
+        device,
+    };
+    state
+        .store
+        .create_mfa_login(&hash_token(&mfa_token), &mfa_login, MFA_TOKEN_TTL_SECONDS)
+        .await
+        .map_err(store_error)?;
+    Ok(mfa_token)
+}
+
+// Accepts a TOTP code whose time step wasn't used yet, or an unused recovery code (which is then consumed)
+async fn verify_second_factor(state: &AppState, user: &User, code: &str) -> Result<bool, (StatusCode, String)> {
+    let Some(secret) = &user.totp_secret else {
+        return Ok(false);
+    };
+    if let Some(step) = state.totp_settings.verify(secret, &user.username, code).map_err(internal_error)? {
+        return state.store.claim_totp_step(&user.username, step, TOTP_STEP_TTL_SECONDS).await.map_err(store_error);
+    }
+    state.store.consume_recovery_code(&user.username, &mfa::hash_recovery_code(code)).await.map_err(store_error)
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+// Maps the provider's subject to a user, provisioning one on the first login. preferred_username is only a suggestion
+// for the new username, it's never used to link an existing account.
+async fn oidc_user(state: &AppState, claims: IdTokenClaims) -> Result<String, (StatusCode, String)> {
+    if let Some(username) = state.store.oidc_subject_username(&claims.sub).await.map_err(store_error)? {
+        return Ok(username);
+    }
+    let mut candidates = Vec::new();
+    if let Some(preferred_username) = claims.preferred_username {
+        let mut failures = Vec::new();
+        state.credential_policy.validate_username(&preferred_username, &mut failures);
+        if failures.is_empty() {
+            candidates.push(preferred_username);
+        }
+    }
+    candidates.extend((0..OIDC_USERNAME_ATTEMPTS).map(|_| format!("user{:08x}", rand::random::<u32>())));
+    for username in candidates {
+        let user = User {
+            username: username.clone(),
+            password_hash: None,
+            oidc_subject: Some(claims.sub.clone()),
+            totp_secret: None,
+            recovery_code_hashes: Vec::new(),
+        };
+        if state.store.create_user(&user).await.map_err(store_error)? {
+            return Ok(username);
+        }
+        // Either the username is taken, or a concurrent login with the same subject was faster
+        if let Some(username) = state.store.oidc_subject_username(&claims.sub).await.map_err(store_error)? {
+            return Ok(username);
+        }
+    }
+    Err((StatusCode::CONFLICT, "Couldn't find a free username".to_owned()))
+}
+
+fn device_label(headers: &HeaderMap, device: Option<String>) -> Option<String> {
+    device.or_else(|| headers.get(USER_AGENT)?.to_str().ok().map(ToOwned::to_owned))
+}
+
+// A session starts at login/registration and lives as long as its refresh tokens keep being rotated. Access tokens
+// carry the session ID, so deleting the session revokes them immediately.
+async fn create_session(
+    state: &AppState,
+    username: &str,
+    device: Option<String>,
+) -> Result<TokenPair, (StatusCode, String)> {
+    let now = Utc::now().timestamp();
+    let session = Session {
+        id: Uuid::new_v4(),
+        device,
+        created_at: now,
+        refreshed_at: now,
+    };
+    state.store.create_session(username, &session, REFRESH_TOKEN_TTL_SECONDS).await.map_err(store_error)?;
+    issue_tokens(state, username, session.id).await
+}
+
+// Refresh tokens are opaque random strings, only their hash is stored. Rotating a token keeps the session, so reusing
+// an already rotated token can revoke all of it.
+async fn issue_tokens(state: &AppState, username: &str, session_id: Uuid) -> Result<TokenPair, (StatusCode, String)> {
+    let refresh_token = random_token();
+    state
+        .store
+        .create_refresh_token(&hash_token(&refresh_token), username, session_id, REFRESH_TOKEN_TTL_SECONDS)
+        .await
+        .map_err(store_error)?;
+    let jwt = generate_jwt(&state.jwt_keys, username, session_id).map_err(internal_error)?;
+    Ok(TokenPair {
+        jwt,
+        refresh_token,
+    })
+}
+
+async fn touch_session(state: &AppState, session_id: Uuid) -> Result<bool, (StatusCode, String)> {
+    let Some(mut session) = state.store.get_session(session_id).await.map_err(store_error)? else {
+        return Ok(false);
+    };
+    session.refreshed_at = Utc::now().timestamp();
+    state.store.update_session(&session, REFRESH_TOKEN_TTL_SECONDS).await.map_err(store_error)?;
+    Ok(true)
+}
+
+fn jwt_deadline(exp: i64) -> Instant {
+    let ttl = (exp - Utc::now().timestamp()).max(0).cast_unsigned();
+    Instant::now() + time::Duration::from_secs(ttl)
+}
+
+async fn authorize_api_token(state: &AppState, token: &str) -> Result<AuthUser, (StatusCode, String)> {
+    let record = state
+        .store
+        .get_api_token(&hash_token(token))
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Invalid API token".to_owned()))?;
+    Ok(AuthUser {
+        username: record.username,
+        credential: Credential::ApiToken {
+            token_id: record.token.id,
+            scope: record.token.scope,
+            expires_at: record.token.expires_at,
         },
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
+    })
 }
 
-fn validate_jwt(secret: &str, jwt: &str) -> Result<Claims, (StatusCode, String)> {
-    jsonwebtoken::decode::<Claims>(jwt, &DecodingKey::from_secret(secret.as_bytes()), &Validation::default())
-        .map(|data| data.claims)
-        .map_err(|err| (StatusCode::UNAUTHORIZED, format!("Invalid JWT: {err}")))
+impl AuthUser {
+    fn require_write(&self) -> Result<(), (StatusCode, String)> {
+        match self.credential {
+            Credential::ApiToken {
+                scope: ApiTokenScope::ReadOnly, ..
+            } => Err((StatusCode::FORBIDDEN, "API token is read-only".to_owned())),
+            _ => Ok(()),
+        }
+    }
+
+    const fn author(&self) -> Author {
+        match self.credential {
+            Credential::Session {
+                session_id, ..
+            } => Author::Session(session_id),
+            Credential::ApiToken {
+                token_id, ..
+            } => Author::ApiToken(token_id),
+        }
+    }
+}
+
+impl Credential {
+    // What revocation events refer to
+    const fn id(&self) -> Uuid {
+        match self {
+            Self::Session {
+                session_id, ..
+            } => *session_id,
+            Self::ApiToken {
+                token_id, ..
+            } => *token_id,
+        }
+    }
+
+    const fn expires_at(&self) -> Option<i64> {
+        match self {
+            Self::Session {
+                exp, ..
+            } => Some(*exp),
+            Self::ApiToken {
+                expires_at, ..
+            } => *expires_at,
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(&parts.headers)
+            .or_else(|| websocket_protocol_token(&parts.headers))
+            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Missing bearer token".to_owned()))?;
+        if token.starts_with(API_TOKEN_PREFIX) {
+            return authorize_api_token(state, &token).await;
+        }
+        let jwt_data = authorize_jwt(state, &token).await?;
+        Ok(Self {
+            username: jwt_data.sub,
+            credential: Credential::Session {
+                session_id: jwt_data.sid,
+                exp: jwt_data.exp,
+            },
+        })
+    }
+}
+
+impl FromRequestParts<AppState> for SessionUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let jwt = bearer_token(&parts.headers)
+            .or_else(|| websocket_protocol_token(&parts.headers))
+            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Missing bearer token".to_owned()))?;
+        if jwt.starts_with(API_TOKEN_PREFIX) {
+            return Err((StatusCode::FORBIDDEN, "API tokens can't be used to manage the account".to_owned()));
+        }
+        let jwt_data = authorize_jwt(state, &jwt).await?;
+        Ok(Self {
+            username: jwt_data.sub,
+            jti: jwt_data.jti,
+            session_id: jwt_data.sid,
+            exp: jwt_data.exp,
+        })
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_owned())
+}
+
+fn websocket_protocol_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = value.split(',').map(str::trim);
+    protocols.any(|protocol| protocol == WEBSOCKET_BEARER_PROTOCOL).then(|| protocols.next())?.map(ToOwned::to_owned)
+}
+
+// Deprecated: Accepts the JWT from the `jwt` query parameter or JSON body field and moves it to the Authorization
+// header, so the AuthUser extractor only has to know about one transport.
+async fn legacy_jwt_transport_middleware(request: Request, next: Next) -> Result<Response, (StatusCode, String)> {
+    if request.headers().contains_key(AUTHORIZATION) {
+        return Ok(next.run(request).await);
+    }
+    let (mut parts, body) = request.into_parts();
+    let query_jwt = Query::<LegacyJwtQuery>::try_from_uri(&parts.uri).ok().and_then(|Query(query)| query.jwt);
+    let is_json =
+        parts.headers.get(CONTENT_TYPE).is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    let (jwt, body) = if query_jwt.is_none() && is_json {
+        let bytes = body::to_bytes(body, LEGACY_JWT_BODY_LIMIT)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("Failed to read request body: {err}")))?;
+        let jwt = serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .and_then(|value| value.get("jwt")?.as_str().map(ToOwned::to_owned));
+        (jwt, Body::from(bytes))
+    } else {
+        (query_jwt, body)
+    };
+    let deprecated = if let Some(jwt) = jwt {
+        let value = HeaderValue::try_from(format!("Bearer {jwt}"))
+            .map_err(|err| (StatusCode::UNAUTHORIZED, format!("Invalid JWT: {err}")))?;
+        parts.headers.insert(AUTHORIZATION, value);
+        true
+    } else {
+        false
+    };
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    if deprecated {
+        response.headers_mut().insert("deprecation", HeaderValue::from_static("true"));
+    }
+    Ok(response)
 }
 
-// TODO: More payload checks!
 async fn register_handler(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<RegisterRequest>,
 ) -> HandlerResult<Json<RegisterResponse>> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let user_key = format!("user:{}", payload.username);
-    if conn.exists(&user_key).await.map_err(internal_error)? {
-        return Err((StatusCode::CONFLICT, "Username already exists".to_owned()));
+    let mut failures = Vec::new();
+    state.credential_policy.validate_username(&payload.username, &mut failures);
+    state.credential_policy.validate_password(&payload.password, &payload.username, &mut failures);
+    if !failures.is_empty() {
+        return Err(HandlerError::Validation(failures));
+    }
+    // Only a shortcut to skip hashing, create_user is what actually guards against duplicate usernames
+    if state.store.get_user(&payload.username).await.map_err(store_error)?.is_some() {
+        return Err((StatusCode::CONFLICT, "Username already exists".to_owned()).into());
     }
-    let user_json = serde_json::to_string(&User {
+    let user = User {
         username: payload.username.clone(),
-        password_hash: bcrypt::hash(&payload.password, bcrypt::DEFAULT_COST).map_err(internal_error)?,
-    })
-    .map_err(internal_error)?;
-    conn.set::<_, _, ()>(&user_key, user_json).await.map_err(internal_error)?;
-    let jwt = generate_jwt(&state.jwt_secret, &payload.username).map_err(internal_error)?;
+        password_hash: Some(hash_password(&state, payload.password).await?),
+        oidc_subject: None,
+        totp_secret: None,
+        recovery_code_hashes: Vec::new(),
+    };
+    if !state.store.create_user(&user).await.map_err(store_error)? {
+        return Err((StatusCode::CONFLICT, "Username already exists".to_owned()).into());
+    }
+    let tokens = create_session(&state, &payload.username, device_label(&headers, payload.device)).await?;
     Ok((
         StatusCode::OK,
         Json(RegisterResponse {
-            jwt,
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
         }),
     ))
 }
 
 async fn login_handler(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> HandlerResult<Json<LoginResponse>> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    if let Some(jwt) = payload.jwt {
-        let blacklist_key = format!("blacklist:{jwt}");
-        if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-            return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-        }
-        let jwt_data = validate_jwt(&state.jwt_secret, &jwt)?;
-        let username = jwt_data.sub;
-        let user_key = format!("user:{username}");
-        if !conn.exists(&user_key).await.map_err(internal_error)? {
-            return Err((StatusCode::UNAUTHORIZED, "User not found".to_owned()));
-        }
-        let jwt = generate_jwt(&state.jwt_secret, &username).map_err(internal_error)?;
-        let ttl = jwt_data.exp - Utc::now().timestamp();
-        if ttl > 0 {
-            conn.set_ex::<_, _, ()>(&blacklist_key, "1", ttl.cast_unsigned()).await.map_err(internal_error)?;
-        }
-        Ok((
-            StatusCode::OK,
-            Json(LoginResponse {
-                jwt,
-                username,
-            }),
-        ))
-    } else if let (Some(username), Some(password)) = (payload.username, payload.password) {
-        let user_key = format!("user:{username}");
-        let user_json: Option<String> = conn.get(&user_key).await.map_err(internal_error)?;
-        let user_json =
-            user_json.ok_or_else(|| (StatusCode::UNAUTHORIZED, "Invalid username or password".to_owned()))?;
-        let user: User = serde_json::from_str(&user_json).map_err(internal_error)?;
-        if !bcrypt::verify(password, &user.password_hash).map_err(internal_error)? {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid username or password".to_owned()));
-        }
-        let jwt = generate_jwt(&state.jwt_secret, &username).map_err(internal_error)?;
-        Ok((
+) -> HandlerResult<Json<LoginOutcome>> {
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let subjects = [Subject::Account(&payload.username), Subject::Ip(ip)];
+    if let Some(retry_after) = state.login_throttle.locked_out(&*state.store, &subjects).await.map_err(store_error)? {
+        return Err(HandlerError::TooManyRequests {
+            retry_after,
+            message: "Too many failed login attempts".to_owned(),
+        });
+    }
+    let user = state.store.get_user(&payload.username).await.map_err(store_error)?;
+    // Without a password hash, because the user doesn't exist or only logs in through OIDC, the password is verified
+    // against a dummy hash all the same, so the response time doesn't tell which usernames exist
+    let password_hash = user.as_ref().and_then(|user| user.password_hash.clone());
+    let verified_hash = password_hash.clone().unwrap_or_else(|| state.password_hashing.dummy_hash().to_owned());
+    let verified = verify_password(payload.password.clone(), Some(verified_hash)).await?;
+    let Some(user) = user.filter(|_| verified && password_hash.is_some()) else {
+        return Err(login_failure(&state, &subjects, ip).await?);
+    };
+    state.login_throttle.record_success(&*state.store, &payload.username).await.map_err(store_error)?;
+    rehash_password(&state, &user, payload.password).await?;
+    let device = device_label(&headers, payload.device);
+    if user.totp_secret.is_some() {
+        let mfa_token = start_mfa_login(&state, &payload.username, device).await?;
+        return Ok((
             StatusCode::OK,
-            Json(LoginResponse {
-                jwt,
-                username,
-            }),
-        ))
-    } else {
-        Err((StatusCode::BAD_REQUEST, "Must provide either JWT or username/password".to_owned()))
+            Json(LoginOutcome::MfaRequired(MfaRequiredResponse {
+                mfa_token,
+                expires_in: MFA_TOKEN_TTL_SECONDS,
+            })),
+        ));
+    }
+    let tokens = create_session(&state, &payload.username, device).await?;
+    Ok((
+        StatusCode::OK,
+        Json(LoginOutcome::Authenticated(LoginResponse {
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+            username: payload.username,
+        })),
+    ))
+}
+
+async fn mfa_login_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<MfaLoginRequest>,
+) -> HandlerResult<Json<MfaLoginResponse>> {
+    let token_hash = hash_token(&payload.mfa_token);
+    let Some(MfaLogin {
+        username,
+        device,
+    }) = state.store.get_mfa_login(&token_hash).await.map_err(store_error)?
+    else {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid or expired MFA token".to_owned()).into());
+    };
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let subjects = [Subject::Account(&username), Subject::Ip(ip)];
+    if let Some(retry_after) = state.login_throttle.locked_out(&*state.store, &subjects).await.map_err(store_error)? {
+        return Err(HandlerError::TooManyRequests {
+            retry_after,
+            message: "Too many failed login attempts".to_owned(),
+        });
+    }
+    let Some(user) = state.store.get_user(&username).await.map_err(store_error)? else {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid or expired MFA token".to_owned()).into());
+    };
+    if !verify_second_factor(&state, &user, &payload.code).await? {
+        return Err(login_failure(&state, &subjects, ip).await?);
     }
+    // MFA tokens are single use
+    if !state.store.consume_mfa_login(&token_hash).await.map_err(store_error)? {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid or expired MFA token".to_owned()).into());
+    }
+    state.login_throttle.record_success(&*state.store, &username).await.map_err(store_error)?;
+    let tokens = create_session(&state, &username, device).await?;
+    Ok((
+        StatusCode::OK,
+        Json(MfaLoginResponse {
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+            username,
+        }),
+    ))
 }
 
-async fn logout_handler(
+async fn oidc_login_handler(
     State(state): State<AppState>,
-    Json(payload): Json<LogoutRequest>,
-) -> HandlerResult<LogoutResponse> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let jwt_data = validate_jwt(&state.jwt_secret, &payload.jwt)?;
-    let blacklist_key = format!("blacklist:{}", payload.jwt);
-    let ttl = jwt_data.exp - Utc::now().timestamp();
+    headers: HeaderMap,
+    Query(query): Query<OidcLoginQuery>,
+) -> HandlerResult<Redirect> {
+    let oidc_provider = state
+        .oidc_provider
+        .as_ref()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "OIDC login is not configured".to_owned()))?;
+    let state_token = random_token();
+    let oidc_state = OidcState {
+        nonce: random_token(),
+        code_verifier: random_token(),
+        device: device_label(&headers, query.device),
+    };
+    state.store.create_oidc_state(&state_token, &oidc_state, OIDC_STATE_TTL_SECONDS).await.map_err(store_error)?;
+    let authorization_url = oidc_provider.authorization_url(&state_token, &oidc_state.nonce, &oidc_state.code_verifier);
+    Ok((StatusCode::SEE_OTHER, Redirect::to(&authorization_url)))
+}
+
+async fn oidc_callback_handler(
+    State(state): State<AppState>,
+    Query(query): Query<OidcCallbackQuery>,
+) -> HandlerResult<Redirect> {
+    let oidc_provider = state
+        .oidc_provider
+        .as_ref()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "OIDC login is not configured".to_owned()))?;
+    // The state is single use, whatever the provider's answer is
+    let Some(OidcState {
+        nonce,
+        code_verifier,
+        device,
+    }) = state.store.take_oidc_state(&query.state).await.map_err(store_error)?
+    else {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid or expired OIDC state".to_owned()).into());
+    };
+    if let Some(error) = query.error {
+        return Err((StatusCode::UNAUTHORIZED, format!("Identity provider error: {error}")).into());
+    }
+    let code = query.code.ok_or_else(|| (StatusCode::BAD_REQUEST, "Missing authorization code".to_owned()))?;
+    let claims = oidc_provider
+        .authenticate(&code, &code_verifier, &nonce)
+        .await
+        .map_err(|err| (StatusCode::UNAUTHORIZED, format!("OIDC login failed: {err}")))?;
+    let username = oidc_user(&state, claims).await?;
+    let tokens = create_session(&state, &username, device).await?;
+    let fragment = serde_urlencoded::to_string(OidcLoginFragment {
+        jwt: tokens.jwt,
+        refresh_token: tokens.refresh_token,
+        username,
+    })
+    .map_err(internal_error)?;
+    Ok((StatusCode::SEE_OTHER, Redirect::to(&oidc_provider.post_login_url(&fragment))))
+}
+
+async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshRequest>,
+) -> HandlerResult<Json<RefreshResponse>> {
+    let Some(RefreshToken {
+        username,
+        session_id,
+        rotated,
+    }) = state.store.rotate_refresh_token(&hash_token(&payload.refresh_token)).await.map_err(store_error)?
+    else {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid refresh token".to_owned()).into());
+    };
+    if rotated {
+        state.store.revoke_session(&username, session_id).await.map_err(store_error)?;
+        return Err((StatusCode::UNAUTHORIZED, "Refresh token reuse detected, session revoked".to_owned()).into());
+    }
+    if !touch_session(&state, session_id).await? {
+        return Err((StatusCode::UNAUTHORIZED, "Session has been revoked".to_owned()).into());
+    }
+    let tokens = issue_tokens(&state, &username, session_id).await?;
+    Ok((
+        StatusCode::OK,
+        Json(RefreshResponse {
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+            username,
+        }),
+    ))
+}
+
+async fn logout_handler(State(state): State<AppState>, auth: SessionUser) -> HandlerResult<LogoutResponse> {
+    let ttl = auth.exp - Utc::now().timestamp();
     if ttl > 0 {
-        conn.set_ex::<_, _, ()>(&blacklist_key, "1", ttl.cast_unsigned()).await.map_err(internal_error)?;
+        state.store.revoke_jwt(auth.jti, ttl.cast_unsigned()).await.map_err(store_error)?;
     }
+    state.store.revoke_session(&auth.username, auth.session_id).await.map_err(store_error)?;
     Ok((StatusCode::OK, ()))
 }
 
-async fn get_all_tasks_handler(
+async fn get_sessions_handler(
     State(state): State<AppState>,
-    Query(query): Query<GetAllTasksRequest>,
-) -> HandlerResult<Json<GetAllTasksResponse>> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", query.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &query.jwt)?;
-    let username = jwt_data.sub;
-    let task_ids_key = format!("task_ids:{username}");
-    let task_ids: Vec<String> = conn.smembers(&task_ids_key).await.map_err(internal_error)?;
-    let mut tasks = Vec::with_capacity(task_ids.len());
-    for task_id in task_ids {
-        let task_key = format!("task:{username}:{task_id}");
-        let task_json: Option<String> = conn.get(&task_key).await.map_err(internal_error)?;
-        if let Some(json) = task_json {
-            let task: Task = serde_json::from_str(&json).map_err(internal_error)?;
-            tasks.push(task);
+    auth: SessionUser,
+) -> HandlerResult<Json<GetSessionsResponse>> {
+    let sessions = state.store.list_sessions(&auth.username).await.map_err(store_error)?;
+    let sessions = sessions
+        .into_iter()
+        .map(|session| SessionResponse {
+            current: session.id == auth.session_id,
+            session,
+        })
+        .collect();
+    Ok((StatusCode::OK, Json(sessions)))
+}
+
+async fn revoke_session_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+    Path(session_id): Path<Uuid>,
+) -> HandlerResult<RevokeSessionResponse> {
+    if !state.store.revoke_session(&auth.username, session_id).await.map_err(store_error)? {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_owned()).into());
+    }
+    Ok((StatusCode::OK, ()))
+}
+
+async fn revoke_all_sessions_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+) -> HandlerResult<RevokeAllSessionsResponse> {
+    state.store.revoke_all_sessions(&auth.username).await.map_err(store_error)?;
+    Ok((StatusCode::OK, ()))
+}
+
+async fn change_password_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: SessionUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> HandlerResult<Json<ChangePasswordResponse>> {
+    let mut failures = Vec::new();
+    state.credential_policy.validate_password(&payload.new_password, &auth.username, &mut failures);
+    if !failures.is_empty() {
+        return Err(HandlerError::Validation(failures));
+    }
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let user = verify_user(&state, &auth, ip, payload.old_password).await?;
+    let new_password_hash = hash_password(&state, payload.new_password).await?;
+    // Only the hash is written, and only over the one just verified, so concurrent changes of the rest of the user
+    // aren't undone and of concurrent password changes one wins
+    let replaced = state
+        .store
+        .replace_password_hash(&auth.username, user.password_hash.as_deref(), &new_password_hash)
+        .await
+        .map_err(store_error)?;
+    if !replaced {
+        return Err((StatusCode::CONFLICT, "Password was changed meanwhile".to_owned()).into());
+    }
+    // Log out everywhere else, the caller gets a fresh session. API tokens go too, a leaked password may have been used
+    // to create them.
+    state.store.revoke_all_sessions(&auth.username).await.map_err(store_error)?;
+    for api_token in state.store.list_api_tokens(&auth.username).await.map_err(store_error)? {
+        state.store.revoke_api_token(&auth.username, api_token.id).await.map_err(store_error)?;
+    }
+    let tokens = create_session(&state, &auth.username, device_label(&headers, None)).await?;
+    Ok((
+        StatusCode::OK,
+        Json(ChangePasswordResponse {
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+        }),
+    ))
+}
+
+async fn change_username_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: SessionUser,
+    Json(payload): Json<ChangeUsernameRequest>,
+) -> HandlerResult<Json<ChangeUsernameResponse>> {
+    let mut failures = Vec::new();
+    state.credential_policy.validate_username(&payload.username, &mut failures);
+    if !failures.is_empty() {
+        return Err(HandlerError::Validation(failures));
+    }
+    if payload.username == auth.username {
+        return Err((StatusCode::BAD_REQUEST, "New username must differ from the current one".to_owned()).into());
+    }
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let mut user = verify_user(&state, &auth, ip, payload.password).await?;
+    user.username.clone_from(&payload.username);
+    state.store.rename_user(&auth.username, &user).await.map_err(store_error)?;
+    let tokens = create_session(&state, &payload.username, device_label(&headers, None)).await?;
+    Ok((
+        StatusCode::OK,
+        Json(ChangeUsernameResponse {
+            jwt: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+            username: payload.username,
+        }),
+    ))
+}
+
+async fn delete_account_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: SessionUser,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> HandlerResult<DeleteAccountResponse> {
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let user = verify_user(&state, &auth, ip, payload.password).await?;
+    state.store.delete_user(&user).await.map_err(store_error)?;
+    Ok((StatusCode::OK, ()))
+}
+
+async fn enroll_totp_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+) -> HandlerResult<Json<EnrollTotpResponse>> {
+    let user = get_user(&state, &auth.username).await?;
+    if user.totp_secret.is_some() {
+        return Err((StatusCode::CONFLICT, "TOTP is already enabled".to_owned()).into());
+    }
+    let (secret, otpauth_uri) = state.totp_settings.generate(&auth.username).map_err(internal_error)?;
+    // Only takes effect once confirmed with a code, so a scanning mistake can't lock the user out
+    state.store.set_totp_enrollment(&auth.username, &secret, TOTP_ENROLLMENT_TTL_SECONDS).await.map_err(store_error)?;
+    Ok((
+        StatusCode::OK,
+        Json(EnrollTotpResponse {
+            secret,
+            otpauth_uri,
+        }),
+    ))
+}
+
+async fn confirm_totp_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+    Json(payload): Json<ConfirmTotpRequest>,
+) -> HandlerResult<Json<ConfirmTotpResponse>> {
+    let secret = state
+        .store
+        .get_totp_enrollment(&auth.username)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "No pending TOTP enrollment".to_owned()))?;
+    let mut user = get_user(&state, &auth.username).await?;
+    if state.totp_settings.verify(&secret, &auth.username, &payload.code).map_err(internal_error)?.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Invalid TOTP code".to_owned()).into());
+    }
+    let recovery_codes = mfa::generate_recovery_codes();
+    user.totp_secret = Some(secret);
+    user.recovery_code_hashes = recovery_codes.iter().map(|code| mfa::hash_recovery_code(code)).collect();
+    state.store.save_user(&user).await.map_err(store_error)?;
+    state.store.delete_totp_enrollment(&auth.username).await.map_err(store_error)?;
+    Ok((
+        StatusCode::OK,
+        Json(ConfirmTotpResponse {
+            recovery_codes,
+        }),
+    ))
+}
+
+async fn disable_totp_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: SessionUser,
+    Json(payload): Json<DisableTotpRequest>,
+) -> HandlerResult<DisableTotpResponse> {
+    let ip = client_ip(&headers, peer, state.trust_forwarded_for);
+    let mut user = verify_user(&state, &auth, ip, payload.password).await?;
+    if user.totp_secret.is_none() {
+        return Err((StatusCode::CONFLICT, "TOTP is not enabled".to_owned()).into());
+    }
+    user.totp_secret = None;
+    user.recovery_code_hashes.clear();
+    state.store.save_user(&user).await.map_err(store_error)?;
+    Ok((StatusCode::OK, ()))
+}
+
+async fn create_api_token_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> HandlerResult<Json<CreateApiTokenResponse>> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > API_TOKEN_NAME_MAX_LENGTH {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("API token name must be between 1 and {API_TOKEN_NAME_MAX_LENGTH} characters long"),
+        )
+            .into());
+    }
+    let now = Utc::now().timestamp();
+    if payload.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err((StatusCode::BAD_REQUEST, "API token expiration must be in the future".to_owned()).into());
+    }
+    let token = format!("{API_TOKEN_PREFIX}{}", random_token());
+    let api_token = ApiToken {
+        id: Uuid::new_v4(),
+        name: name.to_owned(),
+        scope: payload.scope,
+        created_at: now,
+        expires_at: payload.expires_at,
+    };
+    let record = ApiTokenRecord {
+        username: auth.username,
+        token: api_token,
+    };
+    state.store.create_api_token(&hash_token(&token), &record).await.map_err(store_error)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateApiTokenResponse {
+            token,
+            api_token: record.token,
+        }),
+    ))
+}
+
+async fn get_api_tokens_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+) -> HandlerResult<Json<GetApiTokensResponse>> {
+    let api_tokens = state.store.list_api_tokens(&auth.username).await.map_err(store_error)?;
+    Ok((StatusCode::OK, Json(api_tokens)))
+}
+
+async fn revoke_api_token_handler(
+    State(state): State<AppState>,
+    auth: SessionUser,
+    Path(token_id): Path<Uuid>,
+) -> HandlerResult<RevokeApiTokenResponse> {
+    if !state.store.revoke_api_token(&auth.username, token_id).await.map_err(store_error)? {
+        return Err((StatusCode::NOT_FOUND, "API token not found".to_owned()).into());
+    }
+    Ok((StatusCode::OK, ()))
+}
+
+fn deserialize_due<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    let Some(due) = Option::<Due>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    match due {
+        Due::Timestamp(due) => Ok(Some(due)),
+        Due::Rfc3339(due) => DateTime::parse_from_rfc3339(&due)
+            .map(|due| due.timestamp())
+            .or_else(|_| {
+                NaiveDate::parse_from_str(&due, "%Y-%m-%d")
+                    .map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp())
+            })
+            .map(Some)
+            .map_err(|_| {
+                D::Error::custom(format!(
+                    "invalid due date {due:?}, expected a UNIX timestamp or an RFC 3339 date or date-time"
+                ))
+            }),
+    }
+}
+
+fn deserialize_nullable_due<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Nullable<i64>, D::Error> {
+    deserialize_due(deserializer).map(Nullable::from)
+}
+
+fn task_etag(task: &Task) -> String {
+    format!("\"{}\"", task.version)
+}
+
+// If-Match is either "*", which any existing task matches, or an ETag from a task response
+fn if_match_version(headers: &HeaderMap) -> Result<Option<u64>, (StatusCode, String)> {
+    let Some(if_match) = headers.get(IF_MATCH) else {
+        return Ok(None);
+    };
+    let if_match = if_match.to_str().ok().map(str::trim);
+    if if_match == Some("*") {
+        return Ok(None);
+    }
+    if_match
+        .and_then(|if_match| if_match.strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+        .map(Some)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "If-Match must be \"*\" or an ETag of the task".to_owned()))
+}
+
+fn invalid_body<E: Error>(err: E) -> (StatusCode, String) {
+    (StatusCode::UNPROCESSABLE_ENTITY, format!("Failed to deserialize the JSON body into the target type: {err}"))
+}
+
+// Plain JSON and JSON Merge Patch (RFC 7396) bodies mean the same for a flat task: absent fields are left alone
+fn merge_patch_update(
+    payload: serde_json::Value,
+    updated_at: i64,
+    updated_by: Author,
+) -> Result<TaskUpdate, (StatusCode, String)> {
+    let payload: UpdateTaskRequest = serde_json::from_value(payload).map_err(invalid_body)?;
+    Ok(TaskUpdate {
+        category: payload.category,
+        title: payload.title,
+        text: payload.text,
+        completed: payload.completed,
+        due: payload.due,
+        due_tz: payload.due_tz,
+        all_day: payload.all_day,
+        updated_at,
+        updated_by,
+    })
+}
+
+fn json_patch_update(
+    task: &Task,
+    payload: serde_json::Value,
+    updated_at: i64,
+    updated_by: Author,
+) -> Result<TaskUpdate, (StatusCode, String)> {
+    let operations: Vec<json_patch::Operation> = serde_json::from_value(payload).map_err(invalid_body)?;
+    let serde_json::Value::Object(document) = serde_json::to_value(task).map_err(internal_error)? else {
+        unreachable!("Tasks serialize to JSON objects");
+    };
+    let patched = json_patch::apply(&document, operations).map_err(|err| match err {
+        PatchError::TestFailed(_) => (StatusCode::CONFLICT, err.to_string()),
+        PatchError::UnsupportedPath(_) | PatchError::MissingMember(_) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, err.to_string())
         }
+    })?;
+    let patched: PatchedTask = serde_json::from_value(serde_json::Value::Object(patched)).map_err(invalid_body)?;
+    if patched.parent_id != task.parent_id {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "Tasks are moved with POST /task/{id}/move".to_owned()));
     }
+    // What the server keeps track of itself
+    let patched_stamps = (
+        patched.id,
+        patched.version,
+        patched.created_at,
+        patched.updated_at,
+        patched.completed_at,
+        patched.created_by,
+        patched.updated_by,
+    );
+    if patched_stamps
+        != (
+            task.id,
+            task.version,
+            task.created_at,
+            task.updated_at,
+            task.completed_at,
+            task.created_by,
+            task.updated_by,
+        )
+    {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Task ID, version, timestamps and authors can't be changed".to_owned(),
+        ));
+    }
+    // Only what the patch changed is written, so concurrent updates of the other fields aren't undone
+    Ok(TaskUpdate {
+        category: (patched.category != task.category).then_some(patched.category),
+        title: (patched.title != task.title).then_some(patched.title),
+        text: (patched.text != task.text).then_some(patched.text),
+        completed: (patched.completed != task.completed).then_some(patched.completed),
+        due: Nullable::change(task.due.as_ref(), patched.due),
+        due_tz: Nullable::change(task.due_tz.as_ref(), patched.due_tz),
+        all_day: (patched.all_day != task.all_day).then_some(patched.all_day),
+        updated_at,
+        updated_by,
+    })
+}
+
+async fn get_all_tasks_handler(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> HandlerResult<Json<GetAllTasksResponse>> {
+    let tasks = state.store.list_tasks(&auth.username).await.map_err(store_error)?;
     Ok((StatusCode::OK, Json(tasks)))
 }
 
 async fn create_task_handler(
     State(state): State<AppState>,
+    auth: AuthUser,
     Json(payload): Json<CreateTaskRequest>,
 ) -> HandlerResult<CreateTaskResponse> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", payload.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &payload.jwt)?;
-    let username = jwt_data.sub;
-    let task_id = Uuid::new_v4();
+    auth.require_write()?;
+    let now = Utc::now().timestamp();
+    let author = auth.author();
+    let username = auth.username;
     let task = Task {
-        id: task_id,
+        id: Uuid::new_v4(),
         category: payload.category,
         title: payload.title,
         text: payload.text,
         completed: payload.completed,
         due: payload.due,
+        due_tz: payload.due_tz,
+        all_day: payload.all_day,
+        parent_id: payload.parent_id,
+        version: 0,
+        created_at: now,
+        updated_at: now,
+        completed_at: payload.completed.then_some(now),
+        created_by: Some(author),
+        updated_by: Some(author),
     };
-    let task_key = format!("task:{username}:{task_id}");
-    let task_json = serde_json::to_string(&task).map_err(internal_error)?;
-    conn.set::<_, _, ()>(&task_key, task_json).await.map_err(internal_error)?;
-    let task_ids_key = format!("task_ids:{username}");
-    conn.sadd::<_, _, ()>(&task_ids_key, task_id.to_string()).await.map_err(internal_error)?;
-    let channel = format!("notifications:{username}");
-    let notification_json = serde_json::to_string(&Notification::TaskCreated {
-        task,
-    })
-    .map_err(internal_error)?;
-    conn.publish::<_, _, ()>(&channel, notification_json).await.map_err(internal_error)?;
+    let mut failures = Vec::new();
+    validation::validate_deadline(&task, &mut failures);
+    if !failures.is_empty() {
+        return Err(HandlerError::Validation(failures));
+    }
+    // Only subtasks affect other tasks
+    if let Some(parent_id) = task.parent_id {
+        let scope = TaskScope::around([parent_id]);
+        hierarchy::change_tasks(&*state.store, &username, &scope, now, author, |tree| tree.create(task.clone()))
+            .await
+            .map_err(store_error)?;
+    } else {
+        state.store.create_task(&username, &task).await.map_err(store_error)?;
+    }
     Ok((StatusCode::CREATED, ()))
 }
 
 async fn get_task_handler(
     State(state): State<AppState>,
+    auth: AuthUser,
     Path(task_id): Path<Uuid>,
-    Json(payload): Json<GetTaskRequest>,
-) -> HandlerResult<Json<GetTaskResponse>> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", payload.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &payload.jwt)?;
-    let username = jwt_data.sub;
-    let task_key = format!("task:{username}:{task_id}");
-    let task_json: Option<String> = conn.get(&task_key).await.map_err(internal_error)?;
-    let task_json = task_json.ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
-    let task: Task = serde_json::from_str(&task_json).map_err(internal_error)?;
-    Ok((StatusCode::OK, Json(task)))
+) -> HandlerResult<([(HeaderName, String); 1], Json<GetTaskResponse>)> {
+    let task = state
+        .store
+        .get_task(&auth.username, task_id)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
+    Ok((StatusCode::OK, ([(ETAG, task_etag(&task))], Json(task))))
 }
 
 async fn update_task_handler(
     State(state): State<AppState>,
+    auth: AuthUser,
+    Path(task_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> HandlerResult<([(HeaderName, String); 1], UpdateTaskResponse)> {
+    auth.require_write()?;
+    let now = Utc::now().timestamp();
+    let author = auth.author();
+    let username = auth.username;
+    let expected_version = if_match_version(&headers)?;
+    let json_patch =
+        headers.get(CONTENT_TYPE).is_some_and(|value| value.as_bytes().starts_with(b"application/json-patch+json"));
+    // A JSON Patch (RFC 6902) is applied to the current task, which deadline validation needs too
+    let reads_task = json_patch || ["due", "due_tz", "all_day"].iter().any(|field| payload.get(field).is_some());
+    for _ in 0..TASK_UPDATE_ATTEMPTS {
+        let current_task = if reads_task {
+            let task = state
+                .store
+                .get_task(&username, task_id)
+                .await
+                .map_err(store_error)?
+                .ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
+            task.check_version(expected_version).map_err(store_error)?;
+            Some(task)
+        } else {
+            None
+        };
+        // An update worked out from the current task is only written while the task is still at that version
+        let write_version = current_task.as_ref().map_or(expected_version, |task| Some(task.version));
+        let update = match &current_task {
+            Some(task) if json_patch => json_patch_update(task, payload.clone(), now, author)?,
+            _ => merge_patch_update(payload.clone(), now, author)?,
+        };
+        // The deadline fields are only valid together, so they're checked against the task they'd end up in
+        if let Some(mut task) = current_task
+            && (!update.due.is_unchanged() || !update.due_tz.is_unchanged() || update.all_day.is_some())
+        {
+            update.apply(&mut task);
+            let mut failures = Vec::new();
+            validation::validate_deadline(&task, &mut failures);
+            if !failures.is_empty() {
+                return Err(HandlerError::Validation(failures));
+            }
+        }
+        // Completing or reopening a task can complete or reopen the tasks around it
+        let task = if let Some(completed) = update.completed {
+            // Only completing a task reaches into its subtree
+            let scope = TaskScope::around([task_id]);
+            let scope = if completed { scope.with_subtrees() } else { scope };
+            hierarchy::change_tasks(&*state.store, &username, &scope, now, author, |tree| {
+                tree.update(task_id, &update, write_version)
+            })
+            .await
+        } else {
+            state.store.update_task(&username, task_id, &update, write_version).await
+        };
+        match task {
+            // Changed since it was read, without the client asking for a version, so it's worked out again
+            Err(StoreError::VersionMismatch(_)) if reads_task && expected_version.is_none() => {}
+            task => {
+                let task =
+                    task.map_err(store_error)?.ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
+                return Ok((StatusCode::OK, ([(ETAG, task_etag(&task))], ())));
+            }
+        }
+    }
+    Err(store_error(StoreError::Conflict).into())
+}
+
+async fn move_task_handler(
+    State(state): State<AppState>,
+    auth: AuthUser,
     Path(task_id): Path<Uuid>,
-    Json(payload): Json<UpdateTaskRequest>,
-) -> HandlerResult<UpdateTaskResponse> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", payload.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &payload.jwt)?;
-    let username = jwt_data.sub;
-    let task_key = format!("task:{username}:{task_id}");
-    let task_json: Option<String> = conn.get(&task_key).await.map_err(internal_error)?;
-    let task_json = task_json.ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
-    let mut task: Task = serde_json::from_str(&task_json).map_err(internal_error)?;
-    if let Some(category) = payload.category {
-        task.category = category;
-    }
-    if let Some(title) = payload.title {
-        task.title = title;
-    }
-    if let Some(text) = payload.text {
-        task.text = text;
-    }
-    if let Some(completed) = payload.completed {
-        task.completed = completed;
-    }
-    if let Some(due) = payload.due {
-        task.due = Some(due);
-    }
-    let task_json = serde_json::to_string(&task).map_err(internal_error)?;
-    conn.set::<_, _, ()>(&task_key, task_json).await.map_err(internal_error)?;
-    let channel = format!("notifications:{username}");
-    let notification_json = serde_json::to_string(&Notification::TaskUpdated {
-        task,
+    headers: HeaderMap,
+    Json(payload): Json<MoveTaskRequest>,
+) -> HandlerResult<([(HeaderName, String); 1], MoveTaskResponse)> {
+    auth.require_write()?;
+    let now = Utc::now().timestamp();
+    let author = auth.author();
+    let expected_version = if_match_version(&headers)?;
+    let scope = TaskScope::around(iter::once(task_id).chain(payload.parent_id));
+    let task = hierarchy::change_tasks(&*state.store, &auth.username, &scope, now, author, |tree| {
+        tree.move_task(task_id, payload.parent_id, expected_version)
     })
-    .map_err(internal_error)?;
-    conn.publish::<_, _, ()>(&channel, notification_json).await.map_err(internal_error)?;
-    Ok((StatusCode::OK, ()))
+    .await
+    .map_err(store_error)?
+    .ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
+    Ok((StatusCode::OK, ([(ETAG, task_etag(&task))], ())))
+}
+
+async fn get_subtasks_handler(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(task_id): Path<Uuid>,
+) -> HandlerResult<Json<GetSubtasksResponse>> {
+    if state.store.get_task(&auth.username, task_id).await.map_err(store_error)?.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Task not found".to_owned()).into());
+    }
+    let subtasks = state.store.list_subtasks(&auth.username, task_id).await.map_err(store_error)?;
+    Ok((StatusCode::OK, Json(subtasks)))
 }
 
 async fn delete_task_handler(
     State(state): State<AppState>,
+    auth: AuthUser,
     Path(task_id): Path<Uuid>,
-    Json(payload): Json<DeleteTaskRequest>,
+    Query(query): Query<DeleteTaskQuery>,
+    headers: HeaderMap,
 ) -> HandlerResult<DeleteTaskResponse> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", payload.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &payload.jwt)?;
-    let username = jwt_data.sub;
-    let task_key = format!("task:{username}:{task_id}");
-    if !conn.exists(&task_key).await.map_err(internal_error)? {
-        return Err((StatusCode::NOT_FOUND, "Task not found".to_owned()));
-    }
-    conn.del::<_, ()>(&task_key).await.map_err(internal_error)?;
-    let task_ids_key = format!("task_ids:{username}");
-    conn.srem::<_, _, ()>(&task_ids_key, task_id.to_string()).await.map_err(internal_error)?;
-    let channel = format!("notifications:{username}");
-    let notification_json = serde_json::to_string(&Notification::TaskDeleted {
-        task_id,
+    auth.require_write()?;
+    let now = Utc::now().timestamp();
+    let author = auth.author();
+    let expected_version = if_match_version(&headers)?;
+    let username = auth.username;
+    let task = state
+        .store
+        .get_task(&username, task_id)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Task not found".to_owned()))?;
+    task.check_version(expected_version).map_err(store_error)?;
+    // A top-level task without subtasks is deleted on its own, as no other task changes with it
+    if task.parent_id.is_none() && state.store.list_subtasks(&username, task_id).await.map_err(store_error)?.is_empty()
+    {
+        match state.store.delete_task(&username, task_id, Some(task.version)).await {
+            // Changed since it was read, without the client asking for a version, so it's deleted the general way
+            Err(StoreError::VersionMismatch(_)) if expected_version.is_none() => {}
+            deleted => {
+                if !deleted.map_err(store_error)? {
+                    return Err((StatusCode::NOT_FOUND, "Task not found".to_owned()).into());
+                }
+                return Ok((StatusCode::OK, ()));
+            }
+        }
+    }
+    let scope = TaskScope::around([task_id]);
+    let scope = if query.with_subtasks { scope.with_subtrees() } else { scope };
+    let deleted = hierarchy::change_tasks(&*state.store, &username, &scope, now, author, |tree| {
+        tree.delete(task_id, expected_version, query.with_subtasks)
     })
-    .map_err(internal_error)?;
-    conn.publish::<_, _, ()>(&channel, notification_json).await.map_err(internal_error)?;
+    .await
+    .map_err(store_error)?;
+    if !deleted {
+        return Err((StatusCode::NOT_FOUND, "Task not found".to_owned()).into());
+    }
     Ok((StatusCode::OK, ()))
 }
 
+async fn jwks_handler(State(state): State<AppState>) -> Json<JwkSet> {
+    Json(state.jwt_keys.jwks())
+}
+
 async fn websocket_handler(
     websocket: WebSocketUpgrade,
     State(state): State<AppState>,
-    Query(query): Query<WebsocketQuery>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let mut conn = state.pool.get().await.map_err(internal_error)?;
-    let blacklist_key = format!("blacklist:{}", query.jwt);
-    if conn.exists(&blacklist_key).await.map_err(internal_error)? {
-        return Err((StatusCode::UNAUTHORIZED, "JWT has been revoked".to_owned()));
-    }
-    let jwt_data = validate_jwt(&state.jwt_secret, &query.jwt)?;
-    let username = jwt_data.sub;
-    let channel = format!("notifications:{username}");
-    Ok(websocket.on_upgrade(|socket| async move {
+    auth: AuthUser,
+    Query(query): Query<WebSocketQuery>,
+) -> impl IntoResponse {
+    let username = auth.username;
+    let mut credential_id = auth.credential.id();
+    let mut expires_at = auth.credential.expires_at();
+    websocket.protocols([WEBSOCKET_BEARER_PROTOCOL]).on_upgrade(move |socket| async move {
         let (mut sender, mut receiver) = socket.split();
-        let mut pubsub = match state.redis_client.get_async_pubsub().await {
-            Ok(pubsub) => pubsub,
+        let mut events = match state.store.subscribe(&username, query.last_event_id).await {
+            Ok(events) => events,
             Err(err) => {
-                send_error(&mut sender, format!("Failed to parse notification: {err}")).await;
+                send_error(&mut sender, format!("Failed to subscribe to notifications: {err}")).await;
                 if let Err(err) = sender.send(Message::Close(None)).await {
-                    eprintln!("WebSocket connection close send error: {err}");
+                    warn!("WebSocket connection close send error: {err}");
                 }
                 return;
             }
         };
-        if let Err(err) = pubsub.subscribe(channel).await {
-            send_error(&mut sender, format!("Failed to subscribe to notifications: {err}")).await;
-            if let Err(err) = sender.send(Message::Close(None)).await {
-                eprintln!("WebSocket connection close send error: {err}");
-            }
-            return;
-        }
-        let mut pubsub_stream = pubsub.on_message();
-        loop {
+        let expiry = time::sleep_until(expires_at.map_or_else(Instant::now, jwt_deadline));
+        tokio::pin!(expiry);
+        let close_frame = loop {
             tokio::select! {
                 msg = receiver.next() => {
                     match msg {
                         Some(Ok(Message::Text(text))) => {
                             match serde_json::from_str::<ClientWebSocketMessage>(&text) {
                                 Ok(ClientWebSocketMessage::RefreshJwt { jwt }) => {
-                                    // TODO
-                                    println!("JWT refresh request {jwt:?}");
-                                }
-                                Err(err) => {
-                                    send_error(&mut sender, format!("Internal error: {err}")).await;
-                                    break;
+                                    if let Some(jwt_data) = refresh_websocket_jwt(&state, &mut sender, &username, &jwt).await {
+                                        credential_id = jwt_data.sid;
+                                        expires_at = Some(jwt_data.exp);
+                                        expiry.as_mut().reset(jwt_deadline(jwt_data.exp));
+                                    }
                                 }
+                                // The client's mistake, which doesn't spoil the connection
+                                Err(err) => send_error(&mut sender, format!("Invalid message: {err}")).await,
                             }
                         }
                         Some(Ok(Message::Ping(data))) => {
                             if let Err(err) = sender.send(Message::Pong(data)).await {
-                                eprintln!("WebSocket PONG send error: {err}");
-                                break;
+                                warn!("WebSocket PONG send error: {err}");
+                                break None;
                             }
                         }
-                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(Message::Close(_))) | None => break None,
                         Some(Err(err)) => {
-                            eprintln!("WebSocket error: {err}");
-                            break;
+                            warn!("WebSocket error: {err}");
+                            break None;
                         },
                         _ => {}
                     }
                 }
-                msg = pubsub_stream.next() => {
-                    if let Some(msg) = msg {
-                        let payload: Result<String, _> = msg.get_payload();
-                        match payload {
-                            Ok(notification_json) => {
+                event = events.next() => {
+                    if let Some(event) = event {
+                        match event {
+                            Ok(Event::Session(session_event)) => {
+                                if let Some(close_frame) = session_close_frame(&session_event, credential_id) {
+                                    send_error(&mut sender, close_frame.reason.to_string()).await;
+                                    break Some(close_frame);
+                                }
+                            }
+                            Ok(Event::Notification(notification)) => {
+                                let notification_json =
+                                    serde_json::to_string(&notification).expect("Failed to serialize NotificationEvent");
                                 if let Err(err) = sender.send(Message::Text(notification_json.into())).await {
-                                    eprintln!("WebSocket notification JSON send error: {err}");
-                                    break;
+                                    warn!("WebSocket notification JSON send error: {err}");
+                                    break None;
                                 }
                             }
                             Err(err) => {
                                 send_error(&mut sender, format!("Failed to parse notification: {err}")).await;
-                                break;
+                                break None;
                             }
                         }
                     } else {
                         send_error(&mut sender, "Notification stream closed".to_owned()).await;
-                        break;
+                        break None;
                     }
                 }
+                () = &mut expiry, if expires_at.is_some() => {
+                    send_error(&mut sender, "Token expired".to_owned()).await;
+                    break Some(CloseFrame {
+                        code: WEBSOCKET_JWT_EXPIRED_CODE,
+                        reason: "Token expired".into(),
+                    });
+                }
             }
+        };
+        if let Err(err) = sender.send(Message::Close(close_frame)).await {
+            warn!("WebSocket connection close send error: {err}");
+        }
+    })
+}
+
+async fn refresh_websocket_jwt(
+    state: &AppState,
+    sender: &mut SplitSink<WebSocket, Message>,
+    username: &str,
+    jwt: &str,
+) -> Option<Claims> {
+    match authorize_jwt(state, jwt).await {
+        Ok(jwt_data) if jwt_data.sub == username => {
+            send_message(sender, &ServerWebSocketMessage::JwtRefreshed {
+                exp: jwt_data.exp,
+            })
+            .await;
+            Some(jwt_data)
         }
-        if let Err(err) = sender.send(Message::Close(None)).await {
-            eprintln!("WebSocket connection close send error: {err}");
+        Ok(_) => {
+            send_error(sender, "JWT belongs to a different user".to_owned()).await;
+            None
         }
-    }))
+        Err((_, message)) => {
+            send_error(sender, message).await;
+            None
+        }
+    }
 }
 
-async fn send_error(sender: &mut SplitSink<WebSocket, Message>, message: String) {
-    let json = serde_json::to_string(&ServerWebSocketMessage::Error {
-        message,
+// `credential_id` is the socket's session or API token ID
+fn session_close_frame(event: &SessionEvent, credential_id: Uuid) -> Option<CloseFrame> {
+    let (code, reason) = match *event {
+        SessionEvent::SessionRevoked {
+            session_id,
+        } if session_id == credential_id => (WEBSOCKET_SESSION_REVOKED_CODE, "Session revoked"),
+        SessionEvent::ApiTokenRevoked {
+            token_id,
+        } if token_id == credential_id => (WEBSOCKET_SESSION_REVOKED_CODE, "API token revoked"),
+        SessionEvent::SessionRevoked {
+            ..
+        }
+        | SessionEvent::ApiTokenRevoked {
+            ..
+        } => return None,
+        SessionEvent::AllSessionsRevoked => (WEBSOCKET_SESSION_REVOKED_CODE, "Session revoked"),
+        SessionEvent::AccountDeleted => (WEBSOCKET_ACCOUNT_DELETED_CODE, "Account deleted"),
+    };
+    Some(CloseFrame {
+        code,
+        reason: reason.into(),
     })
-    .expect("Failed to serialize ServerWebSocketMessage::Error");
+}
+
+async fn send_message(sender: &mut SplitSink<WebSocket, Message>, message: &ServerWebSocketMessage) {
+    let json = serde_json::to_string(message).expect("Failed to serialize ServerWebSocketMessage");
     if let Err(err) = sender.send(Message::Text(json.into())).await {
-        eprintln!("WebSocket error JSON send error: {err}");
+        warn!("WebSocket message JSON send error: {err}");
     }
 }
+
+async fn send_error(sender: &mut SplitSink<WebSocket, Message>, message: String) {
+    send_message(sender, &ServerWebSocketMessage::Error {
+        message,
+    })
+    .await;
+}
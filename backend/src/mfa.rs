@@ -0,0 +1,71 @@
+use std::{env, error::Error};
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP, TotpUrlError};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECONDS: u64 = 30;
+const TOTP_SKEW_STEPS: u64 = 1; // Steps before and after the current one that are accepted too, for clock drift
+
+pub struct TotpSettings {
+    issuer: String,
+}
+
+impl TotpSettings {
+    // TOTP_ISSUER: Shown next to the account in authenticator apps, defaults to "Task Tracker"
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "Task Tracker".to_owned());
+        if issuer.contains(':') {
+            return Err("TOTP_ISSUER must not contain ':'".into());
+        }
+        Ok(Self {
+            issuer,
+        })
+    }
+
+    // Returns a new Base32 secret together with its otpauth:// URI (meant to be rendered as a QR code)
+    pub fn generate(&self, username: &str) -> Result<(String, String), TotpUrlError> {
+        let totp = self.totp(&Secret::generate_secret(), username)?;
+        Ok((totp.get_secret_base32(), totp.get_url()))
+    }
+
+    // Returns the time step the code matched, callers have to make sure every step is only accepted once (RFC 6238
+    // section 5.2)
+    pub fn verify(&self, secret: &str, username: &str, code: &str) -> Result<Option<u64>, TotpUrlError> {
+        let totp = self.totp(&Secret::Encoded(secret.to_owned()), username)?;
+        let current = Utc::now().timestamp().cast_unsigned() / TOTP_STEP_SECONDS;
+        let mut window = current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS;
+        Ok(window.find(|step| totp.check(code.trim(), step * TOTP_STEP_SECONDS)))
+    }
+
+    // Without skew of its own, so every check covers exactly one step
+    fn totp(&self, secret: &Secret, username: &str) -> Result<TOTP, TotpUrlError> {
+        let secret = secret.to_bytes().map_err(|_| TotpUrlError::Secret(String::new()))?;
+        TOTP::new(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            0,
+            TOTP_STEP_SECONDS,
+            secret,
+            Some(self.issuer.clone()),
+            username.to_owned(),
+        )
+    }
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = format!("{:010x}", rand::random::<u64>() & 0xff_ffff_ffff);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+// Recovery codes are random, so unlike passwords they don't need a slow hash. Dashes, spaces and case are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code.chars().filter(char::is_ascii_alphanumeric).map(|char| char.to_ascii_lowercase()).collect();
+    format!("{:x}", Sha256::digest(code.as_bytes()))
+}
@@ -0,0 +1,386 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    sync::{PoisonError, RwLock},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, errors::Error as JWTError, jwk::JwkSet};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum OidcError {
+    Http(reqwest::Error),
+    Jwt(JWTError),
+    Provider(String),
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    nonce: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+pub struct OidcProvider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    post_login_url: Url,
+    scopes: String,
+    authorization_endpoint: Url,
+    token_endpoint: String,
+    jwks_uri: String,
+    jwks: RwLock<JwkSet>,
+    http: Client,
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "Request to the identity provider failed: {err}"),
+            Self::Jwt(err) => write!(f, "Invalid ID token: {err}"),
+            Self::Provider(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for OidcError {}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<JWTError> for OidcError {
+    fn from(err: JWTError) -> Self {
+        Self::Jwt(err)
+    }
+}
+
+impl OidcProvider {
+    // OIDC_ISSUER_URL: Enables OIDC login, the discovery document is fetched from it on startup
+    // OIDC_CLIENT_ID: Required when OIDC_ISSUER_URL is set
+    // OIDC_CLIENT_SECRET: Optional, public clients rely on PKCE alone
+    // OIDC_REDIRECT_URL: Callback registered at the provider, defaults to "http://localhost:6767/auth/oidc/callback"
+    // OIDC_POST_LOGIN_URL: Frontend URL that receives the tokens in its fragment, defaults to "http://localhost:3000/"
+    // OIDC_SCOPES: Defaults to "openid profile"
+    pub async fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let Ok(issuer) = env::var("OIDC_ISSUER_URL") else {
+            return Ok(None);
+        };
+        let client_id = env::var("OIDC_CLIENT_ID").map_err(|_| "OIDC_CLIENT_ID must be set when OIDC_ISSUER_URL is")?;
+        Ok(Some(Self::discover(issuer, client_id).await?))
+    }
+
+    // Fetches the provider's discovery document and keys, the remaining settings are read as for from_env
+    async fn discover(issuer: String, client_id: String) -> Result<Self, Box<dyn Error>> {
+        let http = Client::new();
+        let discovery_url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = http.get(&discovery_url).send().await?.error_for_status()?.json().await?;
+        // OpenID Connect Discovery 1.0 section 4.3
+        if metadata.issuer != issuer {
+            return Err(format!("Discovery document issuer {:?} doesn't match OIDC_ISSUER_URL", metadata.issuer).into());
+        }
+        let jwks = http.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?;
+        Ok(Self {
+            issuer,
+            client_id,
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: env::var("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|_| "http://localhost:6767/auth/oidc/callback".to_owned()),
+            post_login_url: env::var("OIDC_POST_LOGIN_URL").as_deref().unwrap_or("http://localhost:3000/").parse()?,
+            scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid profile".to_owned()),
+            authorization_endpoint: metadata.authorization_endpoint.parse()?,
+            token_endpoint: metadata.token_endpoint,
+            jwks_uri: metadata.jwks_uri,
+            jwks: RwLock::new(jwks),
+            http,
+        })
+    }
+
+    pub fn authorization_url(&self, state: &str, nonce: &str, code_verifier: &str) -> String {
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let mut url = self.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        url.into()
+    }
+
+    // The fragment never reaches any server, so the tokens don't end up in access logs
+    pub fn post_login_url(&self, fragment: &str) -> String {
+        let mut url = self.post_login_url.clone();
+        url.set_fragment(Some(fragment));
+        url.into()
+    }
+
+    // Exchanges the authorization code and returns the claims of the verified ID token
+    pub async fn authenticate(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<IdTokenClaims, OidcError> {
+        let mut request = self.http.post(&self.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("code_verifier", code_verifier),
+            ("client_id", &self.client_id),
+        ]);
+        if let Some(client_secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(client_secret));
+        }
+        let response: TokenResponse = request.send().await?.error_for_status()?.json().await?;
+        let claims = self.verify_id_token(&response.id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::Provider("ID token nonce doesn't match".to_owned()));
+        }
+        Ok(claims)
+    }
+
+    async fn verify_id_token(&self, id_token: &str) -> Result<IdTokenClaims, OidcError> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        // The client secret must never double as an HMAC key, only accept the provider's public keys
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(OidcError::Provider(format!("Unsupported ID token algorithm {:?}", header.alg)));
+        }
+        let kid = header.kid.as_deref();
+        let decoding_key = if let Some(decoding_key) = self.decoding_key(kid)? {
+            decoding_key
+        } else {
+            // Providers rotate their keys, so refetch the JWKS once before giving up
+            let jwks = self.http.get(&self.jwks_uri).send().await?.error_for_status()?.json().await?;
+            *self.jwks.write().unwrap_or_else(PoisonError::into_inner) = jwks;
+            self.decoding_key(kid)?.ok_or_else(|| OidcError::Provider(format!("Unknown ID token key {kid:?}")))?
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        Ok(jsonwebtoken::decode(id_token, &decoding_key, &validation)?.claims)
+    }
+
+    fn decoding_key(&self, kid: Option<&str>) -> Result<Option<DecodingKey>, OidcError> {
+        let jwks = self.jwks.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(kid.map_or_else(|| jwks.keys.first(), |kid| jwks.find(kid)).map(DecodingKey::from_jwk).transpose()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{Form, Json, Router, extract::State, http::StatusCode, routing};
+    use chrono::Utc;
+    use ed25519_dalek::{SigningKey, pkcs8::EncodePrivateKey};
+    use jsonwebtoken::{
+        EncodingKey,
+        Header,
+        jwk::{
+            AlgorithmParameters,
+            CommonParameters,
+            EllipticCurve,
+            Jwk,
+            KeyAlgorithm,
+            OctetKeyPairParameters,
+            OctetKeyPairType,
+        },
+    };
+    use serde_json::{Value, json};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "task-tracker";
+    const NONCE: &str = "nonce";
+    const CODE_VERIFIER: &str = "code-verifier";
+
+    // An identity provider that answers the next token request for the pending authorization with an ID token of the
+    // pending claims, signed with whichever of its keys is current
+    struct MockProvider {
+        issuer: String,
+        keys: Mutex<Vec<(String, SigningKey)>>,
+        code_challenge: Mutex<Option<String>>,
+        claims: Mutex<Value>,
+    }
+
+    impl MockProvider {
+        async fn start() -> (Arc<Self>, OidcProvider) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("Provider listener");
+            let issuer = format!("http://{}", listener.local_addr().expect("Provider address"));
+            let mock = Arc::new(Self {
+                issuer: issuer.clone(),
+                keys: Mutex::new(vec![("first".to_owned(), SigningKey::from_bytes(&[1; 32]))]),
+                code_challenge: Mutex::new(None),
+                claims: Mutex::new(Value::Null),
+            });
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", routing::get(discovery_handler))
+                .route("/jwks", routing::get(jwks_handler))
+                .route("/token", routing::post(token_handler))
+                .with_state(mock.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            let provider = OidcProvider::discover(issuer, CLIENT_ID.to_owned()).await.expect("Discovery");
+            (mock, provider)
+        }
+
+        fn claims(&self) -> Value {
+            let now = Utc::now().timestamp();
+            json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "sub": "subject",
+                "nonce": NONCE,
+                "iat": now,
+                "exp": now + 300,
+                "preferred_username": "alice",
+            })
+        }
+
+        // Goes through the authorization code flow, with the claims the provider puts in the ID token and the code
+        // verifier the client sends along with the code
+        async fn log_in(
+            &self,
+            provider: &OidcProvider,
+            claims: Value,
+            code_verifier: &str,
+        ) -> Result<IdTokenClaims, OidcError> {
+            let authorization_url: Url =
+                provider.authorization_url("state", NONCE, CODE_VERIFIER).parse().expect("Authorization URL");
+            let query: HashMap<_, _> = authorization_url.query_pairs().into_owned().collect();
+            assert_eq!(query["client_id"], CLIENT_ID);
+            assert_eq!(query["nonce"], NONCE);
+            assert_eq!(query["code_challenge_method"], "S256");
+            *self.code_challenge.lock().unwrap_or_else(PoisonError::into_inner) = Some(query["code_challenge"].clone());
+            *self.claims.lock().unwrap_or_else(PoisonError::into_inner) = claims;
+            provider.authenticate("code", code_verifier, NONCE).await
+        }
+
+        fn rotate_key(&self) {
+            self.keys
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(("second".to_owned(), SigningKey::from_bytes(&[2; 32])));
+        }
+    }
+
+    async fn discovery_handler(State(mock): State<Arc<MockProvider>>) -> Json<Value> {
+        Json(json!({
+            "issuer": mock.issuer,
+            "authorization_endpoint": format!("{}/authorize", mock.issuer),
+            "token_endpoint": format!("{}/token", mock.issuer),
+            "jwks_uri": format!("{}/jwks", mock.issuer),
+        }))
+    }
+
+    async fn jwks_handler(State(mock): State<Arc<MockProvider>>) -> Json<JwkSet> {
+        let keys = mock.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        Json(JwkSet {
+            keys: keys
+                .iter()
+                .map(|(key_id, signing_key)| Jwk {
+                    common: CommonParameters {
+                        key_id: Some(key_id.clone()),
+                        key_algorithm: Some(KeyAlgorithm::EdDSA),
+                        ..CommonParameters::default()
+                    },
+                    algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes()),
+                    }),
+                })
+                .collect(),
+        })
+    }
+
+    // The code is only exchanged for the verifier its challenge was derived from, RFC 7636 section 4.6
+    async fn token_handler(
+        State(mock): State<Arc<MockProvider>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        let invalid_grant = || (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" })));
+        let code_challenge = mock.code_challenge.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let code_verifier = form.get("code_verifier").ok_or_else(invalid_grant)?;
+        if form.get("grant_type").map(String::as_str) != Some("authorization_code")
+            || form.get("code").map(String::as_str) != Some("code")
+            || form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+            || code_challenge != Some(URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())))
+        {
+            return Err(invalid_grant());
+        }
+        let (key_id, signing_key) =
+            mock.keys.lock().unwrap_or_else(PoisonError::into_inner).last().cloned().expect("Provider key");
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(key_id);
+        let encoding_key = EncodingKey::from_ed_der(signing_key.to_pkcs8_der().expect("PKCS #8 key").as_bytes());
+        let claims = mock.claims.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let id_token = jsonwebtoken::encode(&header, &claims, &encoding_key).expect("ID token");
+        Ok(Json(json!({ "id_token": id_token })))
+    }
+
+    #[tokio::test]
+    async fn a_verified_id_token_logs_in() {
+        let (mock, provider) = MockProvider::start().await;
+        let claims = mock.log_in(&provider, mock.claims(), CODE_VERIFIER).await.expect("Login");
+        assert_eq!(claims.sub, "subject");
+        assert_eq!(claims.preferred_username.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn the_code_is_only_exchanged_with_its_code_verifier() {
+        let (mock, provider) = MockProvider::start().await;
+        let login = mock.log_in(&provider, mock.claims(), "other-code-verifier").await;
+        assert!(matches!(login, Err(OidcError::Http(_))));
+    }
+
+    #[tokio::test]
+    async fn id_tokens_of_another_login_client_or_issuer_are_rejected() {
+        let (mock, provider) = MockProvider::start().await;
+        let mut claims = mock.claims();
+        claims["nonce"] = json!("other-nonce");
+        let login = mock.log_in(&provider, claims, CODE_VERIFIER).await;
+        assert!(matches!(login, Err(OidcError::Provider(_))));
+        for (claim, value) in [
+            ("aud", json!("other-client")),
+            ("iss", json!("https://other-issuer.example")),
+            ("exp", json!(Utc::now().timestamp() - 3600)),
+        ] {
+            let mut claims = mock.claims();
+            claims[claim] = value;
+            let login = mock.log_in(&provider, claims, CODE_VERIFIER).await;
+            assert!(matches!(login, Err(OidcError::Jwt(_))), "{claim}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rotated_provider_keys_are_fetched() {
+        let (mock, provider) = MockProvider::start().await;
+        mock.rotate_key();
+        let claims = mock.log_in(&provider, mock.claims(), CODE_VERIFIER).await.expect("Login");
+        assert_eq!(claims.sub, "subject");
+    }
+}
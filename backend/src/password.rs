@@ -0,0 +1,135 @@
+use std::{error::Error, fmt};
+
+use argon2::{
+    Algorithm,
+    Argon2,
+    Params,
+    PasswordHash,
+    PasswordHasher,
+    PasswordVerifier,
+    Version,
+    password_hash::{Error as PhcError, SaltString},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bcrypt::BcryptError;
+
+use crate::config::env_parse;
+
+#[derive(Debug)]
+pub enum PasswordError {
+    Bcrypt(BcryptError),
+    Phc(PhcError),
+}
+
+// New hashes are Argon2id PHC strings, e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`. bcrypt hashes from before
+// are still accepted until they get rehashed on login.
+pub struct PasswordHashing {
+    params: Params,
+    dummy_hash: String, // Of a random password, verified against when there's no hash to verify against
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bcrypt(err) => write!(f, "bcrypt: {err}"),
+            Self::Phc(err) => write!(f, "Argon2: {err}"),
+        }
+    }
+}
+
+impl Error for PasswordError {}
+
+impl From<BcryptError> for PasswordError {
+    fn from(err: BcryptError) -> Self {
+        Self::Bcrypt(err)
+    }
+}
+
+impl From<PhcError> for PasswordError {
+    fn from(err: PhcError) -> Self {
+        Self::Phc(err)
+    }
+}
+
+impl PasswordHashing {
+    // ARGON2_MEMORY_KIB: Defaults to 19456 (19 MiB, the OWASP recommendation)
+    // ARGON2_ITERATIONS: Defaults to 2
+    // ARGON2_PARALLELISM: Defaults to 1
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let params = Params::new(
+            env_parse("ARGON2_MEMORY_KIB", 19456)?,
+            env_parse("ARGON2_ITERATIONS", 2)?,
+            env_parse("ARGON2_PARALLELISM", 1)?,
+            None,
+        )
+        .map_err(|err| format!("Invalid Argon2 parameters: {err}"))?;
+        let mut password_hashing = Self {
+            params,
+            dummy_hash: String::new(),
+        };
+        password_hashing.dummy_hash = password_hashing.hash(&URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()))?;
+        Ok(password_hashing)
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())?;
+        Ok(self.argon2().hash_password(password.as_bytes(), &salt)?.to_string())
+    }
+
+    pub fn verify(password: &str, password_hash: &str) -> Result<bool, PasswordError> {
+        if is_bcrypt(password_hash) {
+            return Ok(bcrypt::verify(password, password_hash)?);
+        }
+        // The parameters are read from the hash itself, so hashes with outdated parameters still verify
+        match Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(password_hash)?) {
+            Ok(()) => Ok(true),
+            Err(PhcError::Password) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Takes as long to verify against as the hashes of passwords set now
+    pub fn dummy_hash(&self) -> &str {
+        &self.dummy_hash
+    }
+
+    // Whether the hash was made with another algorithm or other parameters than new hashes are
+    pub fn needs_rehash(&self, password_hash: &str) -> bool {
+        if is_bcrypt(password_hash) {
+            return true;
+        }
+        let Ok(password_hash) = PasswordHash::new(password_hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&password_hash) else {
+            return true;
+        };
+        password_hash.algorithm != Algorithm::Argon2id.ident()
+            || password_hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.params.m_cost()
+            || params.t_cost() != self.params.t_cost()
+            || params.p_cost() != self.params.p_cost()
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+// bcrypt hashes use the Modular Crypt Format with the `$2a$`, `$2b$`, `$2x$` or `$2y$` prefix, not PHC
+fn is_bcrypt(password_hash: &str) -> bool {
+    password_hash.starts_with("$2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verifying against the dummy hash has to cost what verifying against a user's hash does
+    #[test]
+    fn the_dummy_hash_is_made_like_new_hashes() {
+        let password_hashing = PasswordHashing::from_env().expect("Password hashing");
+        assert!(!password_hashing.needs_rehash(password_hashing.dummy_hash()));
+        assert!(!PasswordHashing::verify("", password_hashing.dummy_hash()).expect("Dummy hash"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::{StreamExt, stream};
+use tokio::sync::broadcast::{self, Sender, error::RecvError};
+
+use crate::store::{
+    Event,
+    EventId,
+    EventSink,
+    EventStream,
+    NOTIFICATION_HISTORY_LENGTH,
+    NOTIFICATION_HISTORY_SECONDS,
+    Notification,
+    NotificationEvent,
+    SessionEvent,
+    StoreError,
+    StoreResult,
+};
+
+const EVENT_CHANNEL_CAPACITY: usize = 256; // Events a slow WebSocket may fall behind by before it's closed
+
+struct Channel {
+    sender: Sender<Event>,
+    history: VecDeque<NotificationEvent>, // Oldest first
+    last_event_id: EventId,
+}
+
+// Fans events out to the WebSockets of this process only, for backends that aren't shared between several instances
+#[derive(Default)]
+pub struct Broadcaster {
+    channels: Mutex<HashMap<String, Channel>>, // By username
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis().cast_unsigned()
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            history: VecDeque::new(),
+            last_event_id: EventId {
+                millis: 0,
+                sequence: 0,
+            },
+        }
+    }
+
+    fn expire_history(&mut self, now_millis: u64) {
+        let oldest_millis = now_millis.saturating_sub(NOTIFICATION_HISTORY_SECONDS * 1000);
+        while self.history.front().is_some_and(|event| event.event_id.millis < oldest_millis) {
+            self.history.pop_front();
+        }
+    }
+}
+
+impl Broadcaster {
+    fn channels(&self) -> MutexGuard<'_, HashMap<String, Channel>> {
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Creates the user's channel if needed
+    fn with_channel<T>(&self, username: &str, f: impl FnOnce(&mut Channel) -> T) -> T {
+        f(self.channels().entry(username.to_owned()).or_insert_with(Channel::new))
+    }
+}
+
+// Publishing to this process can't fail
+#[async_trait]
+impl EventSink for Broadcaster {
+    // Notifications are kept for subscribers that reconnect later
+    async fn publish_notification(&self, username: &str, notification: &Notification) -> StoreResult<()> {
+        let now_millis = now_millis();
+        self.with_channel(username, |channel| {
+            channel.last_event_id = channel.last_event_id.next(now_millis);
+            let event = NotificationEvent {
+                event_id: channel.last_event_id,
+                notification: notification.clone(),
+            };
+            channel.expire_history(now_millis);
+            if channel.history.len() == NOTIFICATION_HISTORY_LENGTH {
+                channel.history.pop_front();
+            }
+            channel.history.push_back(event.clone());
+            channel.sender.send(Event::Notification(Box::new(event))).ok();
+        });
+        Ok(())
+    }
+
+    // Like Redis' PUBLISH, session events nobody is subscribed to are dropped
+    async fn publish_session_event(&self, username: &str, session_event: &SessionEvent) -> StoreResult<()> {
+        if let Some(channel) = self.channels().get(username) {
+            channel.sender.send(Event::Session(session_event.clone())).ok();
+        }
+        Ok(())
+    }
+
+    async fn forget(&self, username: &str) -> StoreResult<()> {
+        self.channels().remove(username);
+        Ok(())
+    }
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream> {
+        // Subscribing and copying the history under the same lock means no notification is missed or sent twice
+        let (receiver, missed) = self.with_channel(username, |channel| {
+            let missed = last_event_id.map_or_else(Vec::new, |last_event_id| {
+                channel.history.iter().filter(|event| event.event_id > last_event_id).cloned().collect()
+            });
+            (channel.sender.subscribe(), missed)
+        });
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), receiver)),
+                // The subscriber can't tell what it missed, so end its stream with an error instead of skipping ahead
+                Err(RecvError::Lagged(skipped)) => Some((
+                    Err(StoreError::Backend(Box::new(io::Error::other(format!("Missed {skipped} events"))))),
+                    receiver,
+                )),
+                Err(RecvError::Closed) => None,
+            }
+        });
+        Ok(stream::iter(missed.into_iter().map(|event| Ok(Event::Notification(Box::new(event))))).chain(live).boxed())
+    }
+
+    // Drops expired history and the channels of users without any subscriber or history left
+    fn prune(&self) {
+        let now_millis = now_millis();
+        self.channels().retain(|_, channel| {
+            channel.expire_history(now_millis);
+            channel.sender.receiver_count() > 0 || !channel.history.is_empty()
+        });
+    }
+}
@@ -0,0 +1,694 @@
+// A Redis server inside the test process, implementing just the commands RedisStore sends, so the Redis paths are
+// tested by default without a Redis server. Transactions behave like Redis': a command rejected while queued aborts
+// the EXEC, and a WATCHed key written by anyone else makes the EXEC return nil. Blocking reads and pub/sub delivery
+// aren't implemented, PUBLISH only counts the messages.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    io,
+    mem,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::Utc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Clone)]
+pub struct FakeRedis {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    values: HashMap<String, Value>,
+    expirations: HashMap<String, i64>, // Unix milliseconds
+    // Bumped on every write, a WATCHed key whose version changed aborts the EXEC
+    versions: HashMap<String, u64>,
+    next_version: u64,
+    published: usize,
+    failing_commands: Vec<String>,
+    conflicting_keys: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Hash(BTreeMap<String, String>),
+    Set(BTreeSet<String>),
+    List(VecDeque<String>),
+    SortedSet(Vec<(f64, String)>),
+    Stream(Vec<(String, Vec<String>)>),
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Option<Vec<Self>>),
+}
+
+// What a connection is in the middle of: the keys it WATCHes with their versions, and the commands of an open MULTI
+#[derive(Default)]
+struct Connection {
+    watched: Vec<(String, u64)>,
+    queued: Option<Vec<Vec<String>>>,
+    aborted: bool,
+}
+
+type CommandResult = Result<Reply, String>;
+
+const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+impl FakeRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Fake Redis listener");
+        let url = format!("redis://{}", listener.local_addr().expect("Fake Redis address"));
+        let fake = Self {
+            url,
+            state: Arc::default(),
+        };
+        let state = fake.state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, state.clone()));
+            }
+        });
+        fake
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Rejects the next `command` queued in a transaction, like Redis rejects a malformed command, which aborts the
+    // transaction as a whole
+    pub fn fail_next(&self, command: &str) {
+        self.state().failing_commands.push(command.to_ascii_uppercase());
+    }
+
+    // Writes `key` right before the next EXEC, like another client would between a WATCH and the EXEC
+    pub fn conflict_next(&self, key: &str) {
+        self.state().conflicting_keys.push(key.to_owned());
+    }
+
+    // Every key with its value and expiration, to compare what a command changed
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        let mut state = self.state();
+        state.expire();
+        let snapshot = state
+            .values
+            .iter()
+            .map(|(key, value)| (key.clone(), format!("{value:?}, expires at {:?}", state.expirations.get(key))))
+            .collect();
+        drop(state);
+        snapshot
+    }
+
+    // Messages sent with PUBLISH so far
+    pub fn published(&self) -> usize {
+        self.state().published
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+    let mut connection = Connection::default();
+    while let Some(args) = read_command(&mut reader).await? {
+        let reply = {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.handle(&mut connection, args)
+        };
+        let mut encoded = Vec::new();
+        reply.encode(&mut encoded);
+        writer.write_all(&encoded).await?;
+        // Pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+// Clients send every command as an array of bulk strings
+async fn read_command(reader: &mut BufReader<impl AsyncReadExt + Unpin>) -> io::Result<Option<Vec<String>>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid RESP command");
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let count: usize = line.trim_end().strip_prefix('*').and_then(|count| count.parse().ok()).ok_or_else(invalid)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let len: usize = line.trim_end().strip_prefix('$').and_then(|len| len.parse().ok()).ok_or_else(invalid)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).map_err(|_| invalid())?);
+    }
+    Ok(Some(args))
+}
+
+impl Reply {
+    const OK: Self = Self::Status("OK");
+
+    fn bulk(value: impl Into<String>) -> Self {
+        Self::Bulk(Some(value.into()))
+    }
+
+    fn array(values: impl IntoIterator<Item = Self>) -> Self {
+        Self::Array(Some(values.into_iter().collect()))
+    }
+
+    fn bulks(values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::array(values.into_iter().map(Self::bulk))
+    }
+
+    fn integer(value: usize) -> Self {
+        Self::Integer(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Status(status) => out.extend(format!("+{status}\r\n").as_bytes()),
+            Self::Error(message) => out.extend(format!("-{message}\r\n").as_bytes()),
+            Self::Integer(value) => out.extend(format!(":{value}\r\n").as_bytes()),
+            Self::Bulk(None) => out.extend(b"$-1\r\n"),
+            Self::Bulk(Some(value)) => out.extend(format!("${}\r\n{value}\r\n", value.len()).as_bytes()),
+            Self::Array(None) => out.extend(b"*-1\r\n"),
+            Self::Array(Some(values)) => {
+                out.extend(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+            }
+        }
+    }
+}
+
+impl Value {
+    const fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Hash(_) => "hash",
+            Self::Set(_) => "set",
+            Self::List(_) => "list",
+            Self::SortedSet(_) => "zset",
+            Self::Stream(_) => "stream",
+        }
+    }
+}
+
+impl State {
+    fn handle(&mut self, connection: &mut Connection, args: Vec<String>) -> Reply {
+        let Some(name) = args.first().map(|name| name.to_ascii_uppercase()) else {
+            return Reply::Error("ERR empty command".to_owned());
+        };
+        if let Some(queued) = &mut connection.queued {
+            match name.as_str() {
+                "EXEC" => {}
+                "DISCARD" => {
+                    connection.queued = None;
+                    connection.aborted = false;
+                    connection.watched.clear();
+                    return Reply::OK;
+                }
+                "MULTI" | "WATCH" => return Reply::Error(format!("ERR {name} inside MULTI is not allowed")),
+                _ => {
+                    if let Some(index) = self.failing_commands.iter().position(|command| *command == name) {
+                        self.failing_commands.remove(index);
+                        connection.aborted = true;
+                        return Reply::Error(format!("ERR injected failure of {name}"));
+                    }
+                    queued.push(args);
+                    return Reply::Status("QUEUED");
+                }
+            }
+        }
+        self.expire();
+        match name.as_str() {
+            "PING" => Reply::Status("PONG"),
+            "CLIENT" | "SELECT" => Reply::OK,
+            "WATCH" => {
+                for key in &args[1..] {
+                    connection.watched.push((key.clone(), self.version(key)));
+                }
+                Reply::OK
+            }
+            "UNWATCH" => {
+                connection.watched.clear();
+                Reply::OK
+            }
+            "MULTI" => {
+                connection.queued = Some(Vec::new());
+                Reply::OK
+            }
+            "EXEC" => self.exec(connection),
+            _ => self.execute(&args).unwrap_or_else(Reply::Error),
+        }
+    }
+
+    fn exec(&mut self, connection: &mut Connection) -> Reply {
+        let Some(queued) = connection.queued.take() else {
+            return Reply::Error("ERR EXEC without MULTI".to_owned());
+        };
+        let watched = mem::take(&mut connection.watched);
+        if mem::take(&mut connection.aborted) {
+            return Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_owned());
+        }
+        for key in mem::take(&mut self.conflicting_keys) {
+            self.touch(&key);
+        }
+        if watched.iter().any(|(key, version)| self.version(key) != *version) {
+            return Reply::Array(None);
+        }
+        Reply::array(queued.iter().map(|args| self.execute(args).unwrap_or_else(Reply::Error)))
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or_default()
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.next_version += 1;
+        self.versions.insert(key.to_owned(), self.next_version);
+    }
+
+    fn expire(&mut self) {
+        let now = Utc::now().timestamp_millis();
+        let expired: Vec<_> =
+            self.expirations.iter().filter(|(_, deadline)| **deadline <= now).map(|(key, _)| key.clone()).collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.expirations.remove(key);
+        let removed = self.values.remove(key).is_some();
+        if removed {
+            self.touch(key);
+        }
+        removed
+    }
+
+    // Writes a key, keeping its expiration as Redis does for everything but SET
+    fn write(&mut self, key: &str, value: Value) {
+        self.touch(key);
+        self.values.insert(key.to_owned(), value);
+    }
+
+    // The value to modify, created empty if missing. Only touched when `modify` reports a change.
+    fn modify<T>(
+        &mut self,
+        key: &str,
+        empty: fn() -> Value,
+        modify: impl FnOnce(&mut Value) -> Result<(T, bool), String>,
+    ) -> Result<T, String> {
+        let value = self.values.entry(key.to_owned()).or_insert_with(empty);
+        let (result, changed) = modify(value)?;
+        let is_empty = match &*value {
+            Value::Hash(fields) => fields.is_empty(),
+            Value::Set(members) => members.is_empty(),
+            Value::List(items) => items.is_empty(),
+            Value::SortedSet(members) => members.is_empty(),
+            Value::String(_) | Value::Stream(_) => false,
+        };
+        if is_empty {
+            self.values.remove(key);
+            self.expirations.remove(key);
+        }
+        if changed {
+            self.touch(key);
+        }
+        Ok(result)
+    }
+
+    fn execute(&mut self, args: &[String]) -> CommandResult {
+        let name = args[0].to_ascii_uppercase();
+        let args = &args[1..];
+        match name.as_str() {
+            "GET" | "SET" | "SETEX" | "DEL" | "EXISTS" | "TYPE" | "RENAME" | "EXPIRE" | "EXPIREAT" | "TTL" | "SCAN"
+            | "INCR" | "INCRBY" | "PUBLISH" => self.execute_key_command(&name, args),
+            "HSET" | "HMSET" | "HSETNX" | "HGET" | "HMGET" | "HGETALL" | "HVALS" | "HDEL" => {
+                self.execute_hash_command(&name, args)
+            }
+            "SADD" | "SREM" | "SMEMBERS" | "SISMEMBER" | "SCARD" => self.execute_set_command(&name, args),
+            "LPUSH" | "LTRIM" | "LRANGE" | "ZADD" | "ZCARD" | "ZREMRANGEBYSCORE" => {
+                self.execute_list_command(&name, args)
+            }
+            "XADD" | "XREVRANGE" | "XLEN" => self.execute_stream_command(&name, args),
+            _ => Err(format!("ERR unknown command '{name}'")),
+        }
+    }
+
+    fn execute_key_command(&mut self, name: &str, args: &[String]) -> CommandResult {
+        let arity = |count: usize| {
+            if args.len() < count { Err(format!("ERR wrong number of arguments for '{name}'")) } else { Ok(()) }
+        };
+        arity(1)?;
+        let key = &args[0];
+        Ok(match name {
+            "GET" => match self.values.get(key) {
+                Some(Value::String(value)) => Reply::bulk(value.clone()),
+                Some(_) => return Err(WRONG_TYPE.to_owned()),
+                None => Reply::Bulk(None),
+            },
+            "SET" => {
+                arity(2)?;
+                let options: Vec<_> = args[2..].iter().map(|option| option.to_ascii_uppercase()).collect();
+                if options.iter().any(|option| option == "NX") && self.values.contains_key(key) {
+                    return Ok(Reply::Bulk(None));
+                }
+                self.write(key, Value::String(args[1].clone()));
+                self.expirations.remove(key);
+                if let Some(index) = options.iter().position(|option| option == "EX") {
+                    let seconds: i64 =
+                        args.get(index + 3).and_then(|seconds| seconds.parse().ok()).ok_or("ERR syntax error")?;
+                    self.expirations.insert(key.clone(), Utc::now().timestamp_millis() + seconds * 1000);
+                }
+                Reply::OK
+            }
+            "SETEX" => {
+                arity(3)?;
+                let seconds: i64 = args[1].parse().map_err(|_| "ERR value is not an integer")?;
+                self.write(key, Value::String(args[2].clone()));
+                self.expirations.insert(key.clone(), Utc::now().timestamp_millis() + seconds * 1000);
+                Reply::OK
+            }
+            "DEL" => Reply::integer(args.iter().filter(|key| self.remove(key)).count()),
+            "EXISTS" => Reply::integer(args.iter().filter(|key| self.values.contains_key(*key)).count()),
+            "TYPE" => Reply::Status(self.values.get(key).map_or("none", Value::type_name)),
+            "RENAME" => {
+                arity(2)?;
+                let value = self.values.remove(key).ok_or("ERR no such key")?;
+                let expiration = self.expirations.remove(key);
+                self.touch(key);
+                self.remove(&args[1]);
+                self.write(&args[1], value);
+                if let Some(expiration) = expiration {
+                    self.expirations.insert(args[1].clone(), expiration);
+                }
+                Reply::OK
+            }
+            "EXPIRE" | "EXPIREAT" => {
+                arity(2)?;
+                let seconds: i64 = args[1].parse().map_err(|_| "ERR value is not an integer")?;
+                if !self.values.contains_key(key) {
+                    return Ok(Reply::Integer(0));
+                }
+                let deadline = if name == "EXPIRE" { Utc::now().timestamp() + seconds } else { seconds };
+                self.expirations.insert(key.clone(), deadline * 1000);
+                self.touch(key);
+                Reply::Integer(1)
+            }
+            "TTL" => Reply::Integer(match (self.values.contains_key(key), self.expirations.get(key)) {
+                (false, _) => -2,
+                (true, None) => -1,
+                (true, Some(deadline)) => (deadline - Utc::now().timestamp_millis()) / 1000,
+            }),
+            "SCAN" => {
+                let pattern =
+                    args.iter().position(|arg| arg.eq_ignore_ascii_case("MATCH")).and_then(|index| args.get(index + 1));
+                let keys = self.values.keys().filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)));
+                Reply::array([Reply::bulk("0"), Reply::bulks(keys.cloned())])
+            }
+            "INCR" | "INCRBY" => {
+                let delta: i64 =
+                    args.get(1).map_or(Ok(1), |delta| delta.parse()).map_err(|_| "ERR value is not an integer")?;
+                let value = match self.values.get(key) {
+                    Some(Value::String(value)) => value.parse::<i64>().map_err(|_| "ERR value is not an integer")?,
+                    Some(_) => return Err(WRONG_TYPE.to_owned()),
+                    None => 0,
+                } + delta;
+                self.touch(key);
+                self.values.insert(key.clone(), Value::String(value.to_string()));
+                Reply::Integer(value)
+            }
+            "PUBLISH" => {
+                self.published += 1;
+                Reply::Integer(0)
+            }
+            _ => unreachable!("Dispatched by execute"),
+        })
+    }
+
+    fn execute_hash_command(&mut self, name: &str, args: &[String]) -> CommandResult {
+        let key = args.first().ok_or_else(|| format!("ERR wrong number of arguments for '{name}'"))?;
+        let fields = || match self.values.get(key) {
+            Some(Value::Hash(fields)) => Ok(Some(fields)),
+            Some(_) => Err(WRONG_TYPE.to_owned()),
+            None => Ok(None),
+        };
+        Ok(match name {
+            "HGET" => Reply::Bulk(fields()?.and_then(|fields| fields.get(args.get(1)?).cloned())),
+            "HMGET" => Reply::array(
+                args[1..]
+                    .iter()
+                    .map(|field| Reply::Bulk(fields().ok().flatten().and_then(|fields| fields.get(field).cloned()))),
+            ),
+            "HGETALL" => {
+                Reply::bulks(fields()?.into_iter().flatten().flat_map(|(field, value)| [field.clone(), value.clone()]))
+            }
+            "HVALS" => Reply::bulks(fields()?.into_iter().flat_map(BTreeMap::values).cloned()),
+            _ => self.modify(
+                key,
+                || Value::Hash(BTreeMap::new()),
+                |value| {
+                    let Value::Hash(fields) = value else {
+                        return Err(WRONG_TYPE.to_owned());
+                    };
+                    Ok(match name {
+                        "HSETNX" => {
+                            let added = !fields.contains_key(&args[1]);
+                            if added {
+                                fields.insert(args[1].clone(), args[2].clone());
+                            }
+                            (Reply::Integer(added.into()), added)
+                        }
+                        "HDEL" => {
+                            let removed = args[1..].iter().filter(|field| fields.remove(*field).is_some()).count();
+                            (Reply::integer(removed), removed > 0)
+                        }
+                        _ => {
+                            let pairs = args[1..].as_chunks::<2>().0;
+                            let added = pairs
+                                .iter()
+                                .filter(|[field, value]| fields.insert(field.clone(), value.clone()).is_none())
+                                .count();
+                            (if name == "HMSET" { Reply::OK } else { Reply::integer(added) }, !pairs.is_empty())
+                        }
+                    })
+                },
+            )?,
+        })
+    }
+
+    fn execute_set_command(&mut self, name: &str, args: &[String]) -> CommandResult {
+        let key = args.first().ok_or_else(|| format!("ERR wrong number of arguments for '{name}'"))?;
+        let members = match self.values.get(key) {
+            Some(Value::Set(members)) => Some(members),
+            Some(_) => return Err(WRONG_TYPE.to_owned()),
+            None => None,
+        };
+        Ok(match name {
+            "SMEMBERS" => Reply::bulks(members.into_iter().flatten().cloned()),
+            "SISMEMBER" => Reply::Integer(
+                members.is_some_and(|members| args.get(1).is_some_and(|member| members.contains(member))).into(),
+            ),
+            "SCARD" => Reply::integer(members.map_or(0, BTreeSet::len)),
+            _ => self.modify(
+                key,
+                || Value::Set(BTreeSet::new()),
+                |value| {
+                    let Value::Set(members) = value else {
+                        return Err(WRONG_TYPE.to_owned());
+                    };
+                    let changed = if name == "SADD" {
+                        args[1..].iter().filter(|member| members.insert((*member).clone())).count()
+                    } else {
+                        args[1..].iter().filter(|member| members.remove(*member)).count()
+                    };
+                    Ok((Reply::integer(changed), changed > 0))
+                },
+            )?,
+        })
+    }
+
+    fn execute_list_command(&mut self, name: &str, args: &[String]) -> CommandResult {
+        let key = args.first().ok_or_else(|| format!("ERR wrong number of arguments for '{name}'"))?;
+        let integer =
+            |index: usize| args.get(index).and_then(|arg| arg.parse::<i64>().ok()).ok_or("ERR value is not an integer");
+        match name {
+            "LPUSH" => self.modify(
+                key,
+                || Value::List(VecDeque::new()),
+                |value| {
+                    let Value::List(items) = value else {
+                        return Err(WRONG_TYPE.to_owned());
+                    };
+                    args[1..].iter().for_each(|item| items.push_front(item.clone()));
+                    Ok((Reply::integer(items.len()), true))
+                },
+            ),
+            "LTRIM" | "LRANGE" => {
+                let (start, stop) = (integer(1)?, integer(2)?);
+                self.modify(
+                    key,
+                    || Value::List(VecDeque::new()),
+                    |value| {
+                        let Value::List(items) = value else {
+                            return Err(WRONG_TYPE.to_owned());
+                        };
+                        let len = i64::try_from(items.len()).unwrap_or(i64::MAX);
+                        let resolve = |index: i64| {
+                            usize::try_from(if index < 0 { len + index } else { index }.clamp(0, len))
+                                .unwrap_or_default()
+                        };
+                        let (start, end) = (resolve(start), resolve(stop).saturating_add(1).min(items.len()));
+                        let range: VecDeque<_> = items.iter().take(end).skip(start).cloned().collect();
+                        if name == "LRANGE" {
+                            return Ok((Reply::bulks(range), false));
+                        }
+                        let changed = range.len() != items.len();
+                        *items = range;
+                        Ok((Reply::OK, changed))
+                    },
+                )
+            }
+            _ => {
+                let score = |arg: &str| match arg {
+                    "-inf" => Ok(f64::NEG_INFINITY),
+                    "+inf" | "inf" => Ok(f64::INFINITY),
+                    arg => arg
+                        .trim_start_matches('(')
+                        .parse::<f64>()
+                        .map_err(|_| "ERR value is not a valid float".to_owned()),
+                };
+                let (min, max) =
+                    if name == "ZREMRANGEBYSCORE" { (score(&args[1])?, score(&args[2])?) } else { (0.0, 0.0) };
+                let added: Vec<(f64, String)> = if name == "ZADD" {
+                    args[1..]
+                        .as_chunks::<2>()
+                        .0
+                        .iter()
+                        .map(|[score_arg, member]| Ok((score(score_arg)?, member.clone())))
+                        .collect::<Result<_, String>>()?
+                } else {
+                    Vec::new()
+                };
+                self.modify(
+                    key,
+                    || Value::SortedSet(Vec::new()),
+                    |value| {
+                        let Value::SortedSet(members) = value else {
+                            return Err(WRONG_TYPE.to_owned());
+                        };
+                        Ok(match name {
+                            "ZCARD" => (Reply::integer(members.len()), false),
+                            "ZADD" => {
+                                let mut count = 0;
+                                for (score, member) in added {
+                                    members.retain(|(_, existing)| *existing != member);
+                                    members.push((score, member));
+                                    count += 1;
+                                }
+                                (Reply::integer(count), count > 0)
+                            }
+                            _ => {
+                                let before = members.len();
+                                members.retain(|(score, _)| *score < min || *score > max);
+                                (Reply::integer(before - members.len()), before != members.len())
+                            }
+                        })
+                    },
+                )
+            }
+        }
+    }
+
+    fn execute_stream_command(&mut self, name: &str, args: &[String]) -> CommandResult {
+        let key = args.first().ok_or_else(|| format!("ERR wrong number of arguments for '{name}'"))?;
+        let entries = match self.values.get(key) {
+            Some(Value::Stream(entries)) => Some(entries),
+            Some(_) => return Err(WRONG_TYPE.to_owned()),
+            None => None,
+        };
+        match name {
+            "XLEN" => Ok(Reply::integer(entries.map_or(0, Vec::len))),
+            "XREVRANGE" => {
+                let count = args
+                    .iter()
+                    .position(|arg| arg.eq_ignore_ascii_case("COUNT"))
+                    .and_then(|index| args.get(index + 1)?.parse().ok());
+                let entries = entries.into_iter().flatten().rev().take(count.unwrap_or(usize::MAX));
+                Ok(Reply::array(
+                    entries.map(|(id, fields)| Reply::array([Reply::bulk(id.clone()), Reply::bulks(fields.clone())])),
+                ))
+            }
+            _ => {
+                // XADD key [MAXLEN [~|=] count] * field value...
+                let mut rest = &args[1..];
+                let mut max_len = None;
+                if rest.first().is_some_and(|arg| arg.eq_ignore_ascii_case("MAXLEN")) {
+                    let skip = if matches!(rest.get(1).map(String::as_str), Some("~" | "=")) { 2 } else { 1 };
+                    max_len =
+                        Some(rest.get(skip).and_then(|count| count.parse::<usize>().ok()).ok_or("ERR syntax error")?);
+                    rest = &rest[skip + 1..];
+                }
+                let fields = rest
+                    .get(1..)
+                    .filter(|fields| !fields.is_empty() && fields.len() % 2 == 0)
+                    .ok_or("ERR wrong number of arguments for 'XADD'")?
+                    .to_vec();
+                let now = Utc::now().timestamp_millis();
+                let last_id = entries.and_then(|entries| entries.last()).map(|(id, _)| {
+                    let (millis, sequence) = id.split_once('-').unwrap_or((id, "0"));
+                    (millis.parse::<i64>().unwrap_or_default(), sequence.parse::<u64>().unwrap_or_default())
+                });
+                let id = match last_id {
+                    Some((millis, sequence)) if millis >= now => format!("{millis}-{}", sequence + 1),
+                    _ => format!("{now}-0"),
+                };
+                self.modify(
+                    key,
+                    || Value::Stream(Vec::new()),
+                    |value| {
+                        let Value::Stream(entries) = value else {
+                            return Err(WRONG_TYPE.to_owned());
+                        };
+                        entries.push((id.clone(), fields));
+                        if let Some(max_len) = max_len {
+                            let excess = entries.len().saturating_sub(max_len);
+                            entries.drain(..excess);
+                        }
+                        Ok((Reply::bulk(id), true))
+                    },
+                )
+            }
+        }
+    }
+}
+
+// Redis glob patterns, as far as `*` and `?` go
+fn glob_match(pattern: &str, key: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern.len() == key.len() && pattern.chars().zip(key.chars()).all(|(p, k)| p == '?' || p == k),
+        Some((prefix, rest)) => {
+            key.is_char_boundary(prefix.len())
+                && glob_match(prefix, &key[..prefix.len()])
+                && (prefix.len()..=key.len())
+                    .any(|split| key.is_char_boundary(split) && glob_match(rest, &key[split..]))
+        }
+    }
+}
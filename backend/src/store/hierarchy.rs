@@ -0,0 +1,314 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    iter,
+};
+
+use uuid::Uuid;
+
+use crate::store::{Author, Notification, StoreError, StoreResult, Task, TaskStore, TaskUpdate};
+
+const COMMIT_ATTEMPTS: usize = 5; // Before giving up on tasks that keep being modified concurrently
+
+// What a change of the task hierarchy writes, committed by the backends all at once or not at all
+pub struct TaskChanges {
+    pub saved: Vec<Task>,   // Created with version 0, updated with their version bumped by one
+    pub deleted: Vec<Task>, // Subtasks after the task they're in
+}
+
+// The tasks a change is worked out from: the given tasks and their ancestors, each with its direct subtasks, and with
+// subtrees, everything below the given tasks too. The rest of the user's tasks isn't loaded.
+pub struct TaskScope {
+    task_ids: Vec<Uuid>,
+    with_subtrees: bool,
+}
+
+// A snapshot of a user's tasks, changed in memory under the hierarchy rules:
+// - A parent is completed exactly when all of its subtasks are, as far as changes of its subtasks go
+// - Completing a task completes its whole subtree
+// - A task with subtasks is only deleted together with them
+// Every task a change touches gets its version bumped and is stamped once, so concurrent changes of it are caught. A
+// parent counts as touched whenever its subtasks change, completion included, so that concurrent changes of siblings
+// conflict on it instead of each judging the parent by the other's stale state.
+pub struct TaskTree {
+    tasks: HashMap<Uuid, Task>,
+    saved: Vec<Uuid>,
+    deleted: Vec<Task>,
+    updated_at: i64,
+    updated_by: Author,
+}
+
+impl TaskChanges {
+    const fn is_empty(&self) -> bool {
+        self.saved.is_empty() && self.deleted.is_empty()
+    }
+
+    // The version each task must still be stored at for the changes to be committed, None for tasks that mustn't exist
+    pub fn expected_versions(&self) -> impl Iterator<Item = (Uuid, Option<u64>)> {
+        let saved = self.saved.iter().map(|task| (task.id, task.version.checked_sub(1)));
+        saved.chain(self.deleted.iter().map(|task| (task.id, Some(task.version))))
+    }
+
+    pub fn notifications(&self) -> impl Iterator<Item = Notification> {
+        let saved = self.saved.iter().map(|task| {
+            if task.version == 0 {
+                Notification::TaskCreated {
+                    task: task.clone(),
+                }
+            } else {
+                Notification::TaskUpdated {
+                    task: task.clone(),
+                }
+            }
+        });
+        saved.chain(self.deleted.iter().map(|task| Notification::TaskDeleted {
+            task_id: task.id,
+            version: task.version,
+        }))
+    }
+}
+
+impl TaskScope {
+    pub fn around(task_ids: impl IntoIterator<Item = Uuid>) -> Self {
+        Self {
+            task_ids: task_ids.into_iter().collect(),
+            with_subtrees: false,
+        }
+    }
+
+    pub const fn with_subtrees(mut self) -> Self {
+        self.with_subtrees = true;
+        self
+    }
+
+    async fn load(&self, store: &dyn TaskStore, username: &str) -> StoreResult<Vec<Task>> {
+        let mut tasks = HashMap::new();
+        // Tasks whose subtasks are loaded, so chains loaded before and cycles in stored tasks are only walked once
+        let mut listed = HashSet::new();
+        for &task_id in &self.task_ids {
+            let mut next = Some(task_id);
+            while let Some(task_id) = next.filter(|task_id| listed.insert(*task_id)) {
+                let task = match tasks.entry(task_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match store.get_task(username, task_id).await? {
+                        Some(task) => entry.insert(task),
+                        None => break,
+                    },
+                };
+                next = task.parent_id;
+                for subtask in store.list_subtasks(username, task_id).await? {
+                    tasks.entry(subtask.id).or_insert(subtask);
+                }
+            }
+            if self.with_subtrees {
+                let mut pending: Vec<Uuid> =
+                    tasks.values().filter(|task| task.parent_id == Some(task_id)).map(|task| task.id).collect();
+                while let Some(subtask_id) = pending.pop() {
+                    if listed.insert(subtask_id) {
+                        for subtask in store.list_subtasks(username, subtask_id).await? {
+                            pending.push(subtask.id);
+                            tasks.entry(subtask.id).or_insert(subtask);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(tasks.into_values().collect())
+    }
+}
+
+impl TaskTree {
+    pub fn new(tasks: Vec<Task>, updated_at: i64, updated_by: Author) -> Self {
+        Self {
+            tasks: tasks.into_iter().map(|task| (task.id, task)).collect(),
+            saved: Vec::new(),
+            deleted: Vec::new(),
+            updated_at,
+            updated_by,
+        }
+    }
+
+    pub fn into_changes(self) -> TaskChanges {
+        let mut tasks = self.tasks;
+        TaskChanges {
+            saved: self.saved.iter().filter_map(|task_id| tasks.remove(task_id)).collect(),
+            deleted: self.deleted,
+        }
+    }
+
+    // Fails with UnknownParent if the parent doesn't exist
+    pub fn create(&mut self, task: Task) -> StoreResult<()> {
+        if let Some(parent_id) = task.parent_id {
+            self.check_parent(parent_id)?;
+        }
+        let (task_id, parent_id) = (task.id, task.parent_id);
+        self.tasks.insert(task_id, task);
+        self.saved.push(task_id);
+        self.subtasks_changed(parent_id);
+        Ok(())
+    }
+
+    // Returns the updated task, or None if it doesn't exist
+    pub fn update(
+        &mut self,
+        task_id: Uuid,
+        update: &TaskUpdate,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>> {
+        let Some(task) = self.tasks.get_mut(&task_id) else {
+            return Ok(None);
+        };
+        task.check_version(expected_version)?;
+        let was_completed = task.completed;
+        update.apply(task); // Bumps the version itself
+        let (completed, parent_id) = (task.completed, task.parent_id);
+        self.saved.push(task_id);
+        if completed != was_completed {
+            if completed {
+                for subtask_id in self.subtree(task_id) {
+                    self.set_completed(subtask_id, true);
+                }
+            }
+            self.subtasks_changed(parent_id);
+        }
+        Ok(self.tasks.get(&task_id).cloned())
+    }
+
+    // Moves the task, and with it its subtree, under another parent or to the top level. Returns the moved task, or
+    // None if it doesn't exist. Fails with UnknownParent or ParentCycle if the parent doesn't exist or is in the subtree.
+    pub fn move_task(
+        &mut self,
+        task_id: Uuid,
+        parent_id: Option<Uuid>,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>> {
+        let Some(task) = self.tasks.get(&task_id) else {
+            return Ok(None);
+        };
+        task.check_version(expected_version)?;
+        let old_parent_id = task.parent_id;
+        if let Some(parent_id) = parent_id {
+            self.check_parent(parent_id)?;
+            if parent_id == task_id || self.ancestors(parent_id).contains(&task_id) {
+                return Err(StoreError::ParentCycle);
+            }
+        }
+        self.edit(task_id).parent_id = parent_id;
+        self.subtasks_changed(old_parent_id);
+        if parent_id != old_parent_id {
+            self.subtasks_changed(parent_id);
+        }
+        Ok(self.tasks.get(&task_id).cloned())
+    }
+
+    // Returns false if the task doesn't exist. Fails with HasSubtasks if it has any, unless they're deleted too.
+    pub fn delete(&mut self, task_id: Uuid, expected_version: Option<u64>, with_subtasks: bool) -> StoreResult<bool> {
+        let Some(task) = self.tasks.get(&task_id) else {
+            return Ok(false);
+        };
+        task.check_version(expected_version)?;
+        let parent_id = task.parent_id;
+        let subtree = self.subtree(task_id);
+        if !subtree.is_empty() && !with_subtasks {
+            return Err(StoreError::HasSubtasks);
+        }
+        for deleted_id in iter::once(task_id).chain(subtree) {
+            self.saved.retain(|saved_id| *saved_id != deleted_id);
+            self.deleted.extend(self.tasks.remove(&deleted_id));
+        }
+        self.subtasks_changed(parent_id);
+        Ok(true)
+    }
+
+    fn check_parent(&self, parent_id: Uuid) -> StoreResult<()> {
+        if self.tasks.contains_key(&parent_id) { Ok(()) } else { Err(StoreError::UnknownParent) }
+    }
+
+    // The task, marked as saved. Bumps its version and stamps it the first time.
+    fn edit(&mut self, task_id: Uuid) -> &mut Task {
+        let task = self.tasks.get_mut(&task_id).expect("Edited tasks exist");
+        if !self.saved.contains(&task_id) {
+            self.saved.push(task_id);
+            task.version += 1;
+            task.updated_at = self.updated_at;
+            task.updated_by = Some(self.updated_by);
+        }
+        task
+    }
+
+    // Only touches the task if its completion changes
+    fn set_completed(&mut self, task_id: Uuid, completed: bool) {
+        if self.tasks.get(&task_id).is_some_and(|task| task.completed != completed) {
+            let updated_at = self.updated_at;
+            let task = self.edit(task_id);
+            task.completed = completed;
+            task.completed_at = completed.then_some(updated_at);
+        }
+    }
+
+    fn subtasks(&self, task_id: Uuid) -> impl Iterator<Item = &Task> {
+        self.tasks.values().filter(move |task| task.parent_id == Some(task_id))
+    }
+
+    // Breadth first, so every subtask comes after the task it's in
+    fn subtree(&self, task_id: Uuid) -> Vec<Uuid> {
+        let mut subtree: Vec<Uuid> = self.subtasks(task_id).map(|task| task.id).collect();
+        let mut next = 0;
+        while let Some(&subtask_id) = subtree.get(next) {
+            subtree.extend(self.subtasks(subtask_id).map(|task| task.id));
+            next += 1;
+        }
+        subtree
+    }
+
+    // Parent first, bounded in case stored tasks ever form a cycle
+    fn ancestors(&self, task_id: Uuid) -> Vec<Uuid> {
+        let mut ancestors = Vec::new();
+        let mut next = self.tasks.get(&task_id).and_then(|task| task.parent_id);
+        while let Some(parent_id) = next.filter(|_| ancestors.len() < self.tasks.len()) {
+            ancestors.push(parent_id);
+            next = self.tasks.get(&parent_id).and_then(|task| task.parent_id);
+        }
+        ancestors
+    }
+
+    // A task got subtasks added, removed, completed or reopened, which touches it even if its completion stays the same.
+    // Completes or reopens it and its ancestors for as long as their subtasks call for it, touching each in turn.
+    fn subtasks_changed(&mut self, task_id: Option<Uuid>) {
+        let Some(task_id) = task_id else {
+            return;
+        };
+        for task_id in iter::once(task_id).chain(self.ancestors(task_id)) {
+            self.edit(task_id);
+            let subtasks: Vec<&Task> = self.subtasks(task_id).collect();
+            if subtasks.is_empty() {
+                return; // A task whose last subtask is gone keeps its completion
+            }
+            let completed = subtasks.iter().all(|subtask| subtask.completed);
+            if self.tasks.get(&task_id).is_none_or(|task| task.completed == completed) {
+                return;
+            }
+            self.set_completed(task_id, completed);
+        }
+    }
+}
+
+// Runs `change` on a snapshot of the tasks in `scope` and commits what it changed, starting over with a fresh snapshot
+// if any of the tasks it changed was modified meanwhile
+pub async fn change_tasks<T: Send>(
+    store: &dyn TaskStore,
+    username: &str,
+    scope: &TaskScope,
+    updated_at: i64,
+    updated_by: Author,
+    change: impl Fn(&mut TaskTree) -> StoreResult<T> + Send + Sync,
+) -> StoreResult<T> {
+    for _ in 0..COMMIT_ATTEMPTS {
+        let mut tree = TaskTree::new(scope.load(store, username).await?, updated_at, updated_by);
+        let result = change(&mut tree)?;
+        let changes = tree.into_changes();
+        if changes.is_empty() || store.commit_tasks(username, &changes).await? {
+            return Ok(result);
+        }
+    }
+    Err(StoreError::Conflict)
+}
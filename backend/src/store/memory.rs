@@ -0,0 +1,724 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::time::{self, Duration, Instant};
+use uuid::Uuid;
+
+use crate::store::{
+    ApiToken,
+    ApiTokenRecord,
+    AuditEntry,
+    EventId,
+    EventSink,
+    EventStream,
+    JwtStatus,
+    MfaLogin,
+    Notification,
+    OidcState,
+    RefreshToken,
+    Session,
+    SessionEvent,
+    StoreError,
+    StoreResult,
+    Task,
+    TaskStore,
+    TaskUpdate,
+    User,
+    broadcast::Broadcaster,
+    hierarchy::TaskChanges,
+    log_publish_error,
+};
+
+const SWEEP_INTERVAL_SECONDS: u64 = 60;
+
+// Keeps everything in process memory, for tests and demos that shouldn't need a Redis server. Expired records are
+// ignored on read and swept periodically.
+pub struct MemoryStore {
+    data: Mutex<Data>,
+    events: Box<dyn EventSink>,
+}
+
+#[derive(Default)]
+struct Data {
+    users: HashMap<String, User>,
+    oidc_subjects: HashMap<String, String>,
+    tasks: HashMap<String, HashMap<Uuid, Task>>, // By username
+    sessions: HashMap<Uuid, Expiring<(String, Session)>>,
+    revoked_jwts: HashMap<Uuid, Instant>,
+    refresh_tokens: HashMap<String, Expiring<RefreshToken>>,
+    mfa_logins: HashMap<String, Expiring<MfaLogin>>,
+    oidc_states: HashMap<String, Expiring<OidcState>>,
+    totp_steps: HashMap<(String, u64), Instant>,
+    totp_enrollments: HashMap<String, Expiring<String>>,
+    api_tokens: HashMap<String, Expiring<ApiTokenRecord>>,
+    login_failures: HashMap<String, Vec<Instant>>, // When each failure leaves the window it was counted in
+    lockout_levels: HashMap<String, Expiring<u32>>,
+    lockouts: HashMap<String, Instant>,
+    audit_log: VecDeque<String>,
+}
+
+struct Expiring<T> {
+    value: T,
+    expires_at: Option<Instant>,
+}
+
+impl<T> Expiring<T> {
+    fn new(value: T, ttl_seconds: u64) -> Self {
+        Self {
+            value,
+            expires_at: Some(Instant::now() + Duration::from_secs(ttl_seconds)),
+        }
+    }
+
+    fn live(&self) -> Option<&T> {
+        self.expires_at.is_none_or(|expires_at| expires_at > Instant::now()).then_some(&self.value)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Arc<Self> {
+        Self::with_events(Box::new(Broadcaster::default()))
+    }
+
+    pub fn with_events(events: Box<dyn EventSink>) -> Arc<Self> {
+        let store = Arc::new(Self {
+            data: Mutex::new(Data::default()),
+            events,
+        });
+        tokio::spawn(sweep(Arc::downgrade(&store)));
+        store
+    }
+
+    fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Data) -> T) -> T {
+        f(&mut self.data())
+    }
+}
+
+impl Data {
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, session| session.live().is_some());
+        self.revoked_jwts.retain(|_, expires_at| *expires_at > now);
+        self.refresh_tokens.retain(|_, token| token.live().is_some());
+        self.mfa_logins.retain(|_, mfa_login| mfa_login.live().is_some());
+        self.oidc_states.retain(|_, oidc_state| oidc_state.live().is_some());
+        self.totp_steps.retain(|_, expires_at| *expires_at > now);
+        self.totp_enrollments.retain(|_, secret| secret.live().is_some());
+        self.api_tokens.retain(|_, record| record.live().is_some());
+        self.login_failures.retain(|_, failures| {
+            failures.retain(|expires_at| *expires_at > now);
+            !failures.is_empty()
+        });
+        self.lockout_levels.retain(|_, level| level.live().is_some());
+        self.lockouts.retain(|_, expires_at| *expires_at > now);
+    }
+
+    // Everything but the user record and its tasks
+    fn drop_user_credentials(&mut self, username: &str) {
+        self.sessions.retain(|_, session| session.value.0 != username);
+        self.api_tokens.retain(|_, record| record.value.username != username);
+        self.totp_enrollments.remove(username);
+    }
+}
+
+// Stops once the store is dropped
+async fn sweep(store: Weak<MemoryStore>) {
+    let mut interval = time::interval(Duration::from_secs(SWEEP_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        store.data().sweep();
+        store.events.prune();
+    }
+}
+
+fn remaining_seconds(expires_at: Instant) -> u64 {
+    expires_at.saturating_duration_since(Instant::now()).as_secs()
+}
+
+#[async_trait]
+impl TaskStore for MemoryStore {
+    async fn get_user(&self, username: &str) -> StoreResult<Option<User>> {
+        Ok(self.data().users.get(username).cloned())
+    }
+
+    async fn create_user(&self, user: &User) -> StoreResult<bool> {
+        Ok(self.update(|data| {
+            let subject_taken =
+                user.oidc_subject.as_ref().is_some_and(|oidc_subject| data.oidc_subjects.contains_key(oidc_subject));
+            if subject_taken || data.users.contains_key(&user.username) {
+                return false;
+            }
+            if let Some(oidc_subject) = &user.oidc_subject {
+                data.oidc_subjects.insert(oidc_subject.clone(), user.username.clone());
+            }
+            data.users.insert(user.username.clone(), user.clone());
+            true
+        }))
+    }
+
+    async fn save_user(&self, user: &User) -> StoreResult<()> {
+        self.data().users.insert(user.username.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn replace_password_hash(
+        &self,
+        username: &str,
+        old_password_hash: Option<&str>,
+        new_password_hash: &str,
+    ) -> StoreResult<bool> {
+        Ok(self.update(|data| {
+            let Some(user) =
+                data.users.get_mut(username).filter(|user| user.password_hash.as_deref() == old_password_hash)
+            else {
+                return false;
+            };
+            user.password_hash = Some(new_password_hash.to_owned());
+            true
+        }))
+    }
+
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> StoreResult<bool> {
+        Ok(self.update(|data| {
+            let Some(user) = data.users.get_mut(username) else {
+                return false;
+            };
+            let Some(index) = user.recovery_code_hashes.iter().position(|hash| hash == code_hash) else {
+                return false;
+            };
+            user.recovery_code_hashes.remove(index);
+            true
+        }))
+    }
+
+    async fn rename_user(&self, old_username: &str, user: &User) -> StoreResult<()> {
+        self.update(|data| {
+            if data.users.contains_key(&user.username) {
+                return Err(StoreError::UsernameTaken);
+            }
+            data.users.remove(old_username);
+            data.users.insert(user.username.clone(), user.clone());
+            if let Some(tasks) = data.tasks.remove(old_username) {
+                data.tasks.insert(user.username.clone(), tasks);
+            }
+            if let Some(oidc_subject) = &user.oidc_subject {
+                data.oidc_subjects.insert(oidc_subject.clone(), user.username.clone());
+            }
+            data.drop_user_credentials(old_username);
+            Ok(())
+        })?;
+        log_publish_error(self.events.publish_session_event(old_username, &SessionEvent::AllSessionsRevoked).await);
+        log_publish_error(self.events.forget(old_username).await);
+        Ok(())
+    }
+
+    async fn delete_user(&self, user: &User) -> StoreResult<()> {
+        self.update(|data| {
+            data.users.remove(&user.username);
+            data.tasks.remove(&user.username);
+            if let Some(oidc_subject) = &user.oidc_subject {
+                data.oidc_subjects.remove(oidc_subject);
+            }
+            data.drop_user_credentials(&user.username);
+        });
+        log_publish_error(self.events.publish_session_event(&user.username, &SessionEvent::AccountDeleted).await);
+        log_publish_error(self.events.forget(&user.username).await);
+        Ok(())
+    }
+
+    async fn oidc_subject_username(&self, subject: &str) -> StoreResult<Option<String>> {
+        Ok(self.data().oidc_subjects.get(subject).cloned())
+    }
+
+    async fn create_session(&self, username: &str, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        self.data().sessions.insert(session.id, Expiring::new((username.to_owned(), session.clone()), ttl_seconds));
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> StoreResult<Option<Session>> {
+        Ok(self.data().sessions.get(&session_id).and_then(Expiring::live).map(|(_, session)| session.clone()))
+    }
+
+    async fn update_session(&self, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        if let Some(entry) = self.data().sessions.get_mut(&session.id) {
+            *entry = Expiring::new((entry.value.0.clone(), session.clone()), ttl_seconds);
+        }
+        Ok(())
+    }
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>> {
+        Ok(self
+            .data()
+            .sessions
+            .values()
+            .filter_map(Expiring::live)
+            .filter(|(owner, _)| owner == username)
+            .map(|(_, session)| session.clone())
+            .collect())
+    }
+
+    async fn revoke_session(&self, username: &str, session_id: Uuid) -> StoreResult<bool> {
+        let revoked = self.update(|data| {
+            let owned = data.sessions.get(&session_id).is_some_and(|session| session.value.0 == username);
+            owned && data.sessions.remove(&session_id).is_some()
+        });
+        if revoked {
+            log_publish_error(
+                self.events
+                    .publish_session_event(username, &SessionEvent::SessionRevoked {
+                        session_id,
+                    })
+                    .await,
+            );
+        }
+        Ok(revoked)
+    }
+
+    async fn revoke_all_sessions(&self, username: &str) -> StoreResult<()> {
+        let revoked = self.update(|data| {
+            let session_count = data.sessions.len();
+            data.sessions.retain(|_, session| session.value.0 != username);
+            data.sessions.len() < session_count
+        });
+        if revoked {
+            log_publish_error(self.events.publish_session_event(username, &SessionEvent::AllSessionsRevoked).await);
+        }
+        Ok(())
+    }
+
+    async fn jwt_status(&self, jti: Uuid, session_id: Uuid) -> StoreResult<JwtStatus> {
+        let data = self.data();
+        Ok(if data.revoked_jwts.get(&jti).is_some_and(|expires_at| *expires_at > Instant::now()) {
+            JwtStatus::Revoked
+        } else if data.sessions.get(&session_id).and_then(Expiring::live).is_some() {
+            JwtStatus::Valid
+        } else {
+            JwtStatus::SessionRevoked
+        })
+    }
+
+    async fn revoke_jwt(&self, jti: Uuid, ttl_seconds: u64) -> StoreResult<()> {
+        self.data().revoked_jwts.insert(jti, Instant::now() + Duration::from_secs(ttl_seconds));
+        Ok(())
+    }
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        username: &str,
+        session_id: Uuid,
+        ttl_seconds: u64,
+    ) -> StoreResult<()> {
+        let refresh_token = RefreshToken {
+            username: username.to_owned(),
+            session_id,
+            rotated: false,
+        };
+        self.data().refresh_tokens.insert(token_hash.to_owned(), Expiring::new(refresh_token, ttl_seconds));
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, token_hash: &str) -> StoreResult<Option<RefreshToken>> {
+        Ok(self.update(|data| {
+            let entry = data.refresh_tokens.get_mut(token_hash).filter(|entry| entry.live().is_some())?;
+            let refresh_token = entry.value.clone();
+            entry.value.rotated = true;
+            Some(refresh_token)
+        }))
+    }
+
+    async fn create_mfa_login(&self, token_hash: &str, mfa_login: &MfaLogin, ttl_seconds: u64) -> StoreResult<()> {
+        self.data().mfa_logins.insert(token_hash.to_owned(), Expiring::new(mfa_login.clone(), ttl_seconds));
+        Ok(())
+    }
+
+    async fn get_mfa_login(&self, token_hash: &str) -> StoreResult<Option<MfaLogin>> {
+        Ok(self.data().mfa_logins.get(token_hash).and_then(Expiring::live).cloned())
+    }
+
+    async fn consume_mfa_login(&self, token_hash: &str) -> StoreResult<bool> {
+        Ok(self.data().mfa_logins.remove(token_hash).is_some_and(|mfa_login| mfa_login.live().is_some()))
+    }
+
+    async fn create_oidc_state(&self, state: &str, oidc_state: &OidcState, ttl_seconds: u64) -> StoreResult<()> {
+        self.data().oidc_states.insert(state.to_owned(), Expiring::new(oidc_state.clone(), ttl_seconds));
+        Ok(())
+    }
+
+    async fn take_oidc_state(&self, state: &str) -> StoreResult<Option<OidcState>> {
+        let oidc_state = self.data().oidc_states.remove(state);
+        Ok(oidc_state.filter(|oidc_state| oidc_state.live().is_some()).map(|oidc_state| oidc_state.value))
+    }
+
+    async fn claim_totp_step(&self, username: &str, step: u64, ttl_seconds: u64) -> StoreResult<bool> {
+        Ok(self.update(|data| {
+            let key = (username.to_owned(), step);
+            if data.totp_steps.get(&key).is_some_and(|expires_at| *expires_at > Instant::now()) {
+                return false;
+            }
+            data.totp_steps.insert(key, Instant::now() + Duration::from_secs(ttl_seconds));
+            true
+        }))
+    }
+
+    async fn set_totp_enrollment(&self, username: &str, secret: &str, ttl_seconds: u64) -> StoreResult<()> {
+        self.data().totp_enrollments.insert(username.to_owned(), Expiring::new(secret.to_owned(), ttl_seconds));
+        Ok(())
+    }
+
+    async fn get_totp_enrollment(&self, username: &str) -> StoreResult<Option<String>> {
+        Ok(self.data().totp_enrollments.get(username).and_then(Expiring::live).cloned())
+    }
+
+    async fn delete_totp_enrollment(&self, username: &str) -> StoreResult<()> {
+        self.data().totp_enrollments.remove(username);
+        Ok(())
+    }
+
+    async fn create_api_token(&self, token_hash: &str, record: &ApiTokenRecord) -> StoreResult<()> {
+        let expires_at = record.token.expires_at.map(|expires_at| {
+            Instant::now() + Duration::from_secs((expires_at - Utc::now().timestamp()).max(0).cast_unsigned())
+        });
+        self.data().api_tokens.insert(token_hash.to_owned(), Expiring {
+            value: record.clone(),
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> StoreResult<Option<ApiTokenRecord>> {
+        Ok(self.data().api_tokens.get(token_hash).and_then(Expiring::live).cloned())
+    }
+
+    async fn list_api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>> {
+        Ok(self
+            .data()
+            .api_tokens
+            .values()
+            .filter_map(Expiring::live)
+            .filter(|record| record.username == username)
+            .map(|record| record.token.clone())
+            .collect())
+    }
+
+    async fn revoke_api_token(&self, username: &str, token_id: Uuid) -> StoreResult<bool> {
+        let revoked = self.update(|data| {
+            let token_count = data.api_tokens.len();
+            data.api_tokens.retain(|_, record| record.value.username != username || record.value.token.id != token_id);
+            data.api_tokens.len() < token_count
+        });
+        if revoked {
+            log_publish_error(
+                self.events
+                    .publish_session_event(username, &SessionEvent::ApiTokenRevoked {
+                        token_id,
+                    })
+                    .await,
+            );
+        }
+        Ok(revoked)
+    }
+
+    async fn add_login_failure(&self, subject: &str, window_seconds: u64) -> StoreResult<usize> {
+        let now = Instant::now();
+        Ok(self.update(|data| {
+            let failures = data.login_failures.entry(subject.to_owned()).or_default();
+            failures.retain(|expires_at| *expires_at > now);
+            failures.push(now + Duration::from_secs(window_seconds));
+            failures.len()
+        }))
+    }
+
+    async fn clear_login_failures(&self, subject: &str) -> StoreResult<()> {
+        self.data().login_failures.remove(subject);
+        Ok(())
+    }
+
+    async fn escalate_lockout(&self, subject: &str, level_ttl_seconds: u64) -> StoreResult<u32> {
+        Ok(self.update(|data| {
+            let level = data.lockout_levels.get(subject).and_then(Expiring::live).copied().unwrap_or_default() + 1;
+            data.lockout_levels.insert(subject.to_owned(), Expiring::new(level, level_ttl_seconds));
+            level
+        }))
+    }
+
+    async fn lock_out(&self, subject: &str, seconds: u64) -> StoreResult<()> {
+        self.update(|data| {
+            data.lockouts.insert(subject.to_owned(), Instant::now() + Duration::from_secs(seconds));
+            data.login_failures.remove(subject);
+        });
+        Ok(())
+    }
+
+    async fn lockout_remaining(&self, subjects: &[String]) -> StoreResult<Option<u64>> {
+        let data = self.data();
+        Ok(subjects
+            .iter()
+            .filter_map(|subject| data.lockouts.get(subject))
+            .map(|expires_at| remaining_seconds(*expires_at))
+            .filter(|seconds| *seconds > 0)
+            .max())
+    }
+
+    async fn append_audit_entry(&self, entry: &AuditEntry, max_entries: usize) -> StoreResult<()> {
+        let entry_json = serde_json::to_string(entry)?;
+        self.update(|data| {
+            data.audit_log.push_front(entry_json);
+            data.audit_log.truncate(max_entries);
+        });
+        Ok(())
+    }
+
+    async fn list_tasks(&self, username: &str) -> StoreResult<Vec<Task>> {
+        Ok(self.data().tasks.get(username).map_or_else(Vec::new, |tasks| tasks.values().cloned().collect()))
+    }
+
+    async fn get_task(&self, username: &str, task_id: Uuid) -> StoreResult<Option<Task>> {
+        Ok(self.data().tasks.get(username).and_then(|tasks| tasks.get(&task_id)).cloned())
+    }
+
+    async fn list_subtasks(&self, username: &str, task_id: Uuid) -> StoreResult<Vec<Task>> {
+        Ok(self.data().tasks.get(username).map_or_else(Vec::new, |tasks| {
+            tasks.values().filter(|task| task.parent_id == Some(task_id)).cloned().collect()
+        }))
+    }
+
+    async fn create_task(&self, username: &str, task: &Task) -> StoreResult<()> {
+        self.data().tasks.entry(username.to_owned()).or_default().insert(task.id, task.clone());
+        log_publish_error(
+            self.events
+                .publish_notification(username, &Notification::TaskCreated {
+                    task: task.clone(),
+                })
+                .await,
+        );
+        Ok(())
+    }
+
+    async fn update_task(
+        &self,
+        username: &str,
+        task_id: Uuid,
+        update: &TaskUpdate,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>> {
+        let task = self.update(|data| -> StoreResult<_> {
+            let Some(task) = data.tasks.get_mut(username).and_then(|tasks| tasks.get_mut(&task_id)) else {
+                return Ok(None);
+            };
+            task.check_version(expected_version)?;
+            update.apply(task);
+            Ok(Some(task.clone()))
+        })?;
+        if let Some(task) = &task {
+            log_publish_error(
+                self.events
+                    .publish_notification(username, &Notification::TaskUpdated {
+                        task: task.clone(),
+                    })
+                    .await,
+            );
+        }
+        Ok(task)
+    }
+
+    async fn delete_task(&self, username: &str, task_id: Uuid, expected_version: Option<u64>) -> StoreResult<bool> {
+        let deleted = self.update(|data| -> StoreResult<_> {
+            let Some(tasks) = data.tasks.get_mut(username) else {
+                return Ok(None);
+            };
+            if let Some(task) = tasks.get(&task_id) {
+                task.check_version(expected_version)?;
+            }
+            Ok(tasks.remove(&task_id))
+        })?;
+        if let Some(task) = &deleted {
+            let notification = Notification::TaskDeleted {
+                task_id,
+                version: task.version,
+            };
+            log_publish_error(self.events.publish_notification(username, &notification).await);
+        }
+        Ok(deleted.is_some())
+    }
+
+    async fn commit_tasks(&self, username: &str, changes: &TaskChanges) -> StoreResult<bool> {
+        let committed = self.update(|data| {
+            let tasks = data.tasks.entry(username.to_owned()).or_default();
+            if changes
+                .expected_versions()
+                .any(|(task_id, version)| tasks.get(&task_id).map(|task| task.version) != version)
+            {
+                return false;
+            }
+            for task in &changes.saved {
+                tasks.insert(task.id, task.clone());
+            }
+            for task in &changes.deleted {
+                tasks.remove(&task.id);
+            }
+            true
+        });
+        if committed {
+            for notification in changes.notifications() {
+                log_publish_error(self.events.publish_notification(username, &notification).await);
+            }
+        }
+        Ok(committed)
+    }
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream> {
+        self.events.subscribe(username, last_event_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::{StreamExt, stream};
+
+    use super::*;
+    use crate::store::tests::{retitle, task};
+
+    // Fails every publish, like a Redis server that's down
+    struct FailingSink;
+
+    // Records the task ID and version of every published notification
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        published: Arc<Mutex<Vec<(Uuid, u64)>>>,
+    }
+
+    #[async_trait]
+    impl EventSink for FailingSink {
+        async fn publish_notification(&self, _: &str, _: &Notification) -> StoreResult<()> {
+            Err(StoreError::Backend(Box::new(io::Error::other("Publish failed"))))
+        }
+
+        async fn publish_session_event(&self, _: &str, _: &SessionEvent) -> StoreResult<()> {
+            Err(StoreError::Backend(Box::new(io::Error::other("Publish failed"))))
+        }
+
+        async fn forget(&self, _: &str) -> StoreResult<()> {
+            Err(StoreError::Backend(Box::new(io::Error::other("Forget failed"))))
+        }
+
+        async fn subscribe(&self, _: &str, _: Option<EventId>) -> StoreResult<EventStream> {
+            Ok(stream::empty().boxed())
+        }
+    }
+
+    impl RecordingSink {
+        fn published(&self) -> Vec<(Uuid, u64)> {
+            self.published.lock().unwrap_or_else(PoisonError::into_inner).clone()
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for RecordingSink {
+        async fn publish_notification(&self, _: &str, notification: &Notification) -> StoreResult<()> {
+            let published = match notification {
+                Notification::TaskCreated {
+                    task,
+                }
+                | Notification::TaskUpdated {
+                    task,
+                } => (task.id, task.version),
+                Notification::TaskDeleted {
+                    task_id,
+                    version,
+                } => (*task_id, *version),
+            };
+            self.published.lock().unwrap_or_else(PoisonError::into_inner).push(published);
+            Ok(())
+        }
+
+        async fn publish_session_event(&self, _: &str, _: &SessionEvent) -> StoreResult<()> {
+            Ok(())
+        }
+
+        async fn forget(&self, _: &str) -> StoreResult<()> {
+            Ok(())
+        }
+
+        async fn subscribe(&self, _: &str, _: Option<EventId>) -> StoreResult<EventStream> {
+            Ok(stream::empty().boxed())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweep_drops_login_failures_once_out_of_the_window() {
+        let store = MemoryStore::new();
+        store.add_login_failure("alice", 60).await.expect("Memory store");
+        time::advance(Duration::from_secs(30)).await;
+        store.add_login_failure("alice", 60).await.expect("Memory store");
+        store.add_login_failure("bob", 60).await.expect("Memory store");
+        time::advance(Duration::from_secs(45)).await;
+        store.data().sweep();
+        assert_eq!(store.data().login_failures.get("alice").map(Vec::len), Some(1));
+        time::advance(Duration::from_secs(30)).await;
+        store.data().sweep();
+        assert!(store.data().login_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_publishes_keep_committed_writes() {
+        let store = MemoryStore::with_events(Box::new(FailingSink));
+        let created = task("Write tests", None);
+        store.create_task("alice", &created).await.expect("Create despite the failed publish");
+        let updated = store.update_task("alice", created.id, &retitle("Run tests"), Some(0)).await;
+        assert_eq!(updated.expect("Update despite the failed publish").map(|task| task.version), Some(1));
+        let stored = store.get_task("alice", created.id).await.expect("Memory store").expect("Stored task");
+        assert_eq!((stored.title.as_str(), stored.version), ("Run tests", 1));
+        let changes = TaskChanges {
+            saved: Vec::new(),
+            deleted: vec![stored],
+        };
+        assert!(store.commit_tasks("alice", &changes).await.expect("Commit despite the failed publish"));
+        assert!(store.get_task("alice", created.id).await.expect("Memory store").is_none());
+        store.revoke_all_sessions("alice").await.expect("Revoke despite the failed publish");
+    }
+
+    #[tokio::test]
+    async fn only_committed_writes_publish_events() {
+        let events = RecordingSink::default();
+        let store = MemoryStore::with_events(Box::new(events.clone()));
+        let created = task("Write tests", None);
+        store.create_task("alice", &created).await.expect("Memory store");
+        assert_eq!(events.published(), [(created.id, 0)]);
+
+        let stale = store.update_task("alice", created.id, &retitle("Run tests"), Some(3)).await;
+        assert!(matches!(stale, Err(StoreError::VersionMismatch(0))));
+        let mut stale = created.clone();
+        stale.version = 4; // Expects version 3
+        let changes = TaskChanges {
+            saved: vec![stale],
+            deleted: Vec::new(),
+        };
+        assert!(!store.commit_tasks("alice", &changes).await.expect("Memory store"));
+        assert_eq!(events.published(), [(created.id, 0)]);
+
+        store.update_task("alice", created.id, &retitle("Run tests"), Some(0)).await.expect("Memory store");
+        let mut completed = store.get_task("alice", created.id).await.expect("Memory store").expect("Stored task");
+        completed.completed = true;
+        completed.version += 1;
+        let changes = TaskChanges {
+            saved: vec![completed],
+            deleted: Vec::new(),
+        };
+        assert!(store.commit_tasks("alice", &changes).await.expect("Memory store"));
+        assert_eq!(events.published(), [(created.id, 0), (created.id, 1), (created.id, 2)]);
+    }
+}
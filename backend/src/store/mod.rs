@@ -0,0 +1,596 @@
+mod broadcast;
+#[cfg(test)]
+mod fake_redis;
+pub mod hierarchy;
+mod memory;
+mod redis;
+mod sql;
+#[cfg(test)]
+pub mod tests;
+
+use std::{env, error::Error, fmt, net::IpAddr, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::store::{hierarchy::TaskChanges, memory::MemoryStore, redis::RedisStore, sql::SqlStore};
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+pub type EventStream = BoxStream<'static, StoreResult<Event>>;
+
+// How many notifications, and for how long, a reconnecting WebSocket can still catch up on
+pub const NOTIFICATION_HISTORY_LENGTH: usize = 1000;
+pub const NOTIFICATION_HISTORY_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Backend(Box<dyn Error + Send + Sync>),
+    Json(serde_json::Error),
+    Conflict,             // A concurrent write got in between, the operation can be retried
+    UsernameTaken,        // Only returned by rename_user
+    VersionMismatch(u64), // Returned by task changes that expect a version, with the task's current version
+    // Only returned by task hierarchy changes
+    UnknownParent,
+    ParentCycle,
+    HasSubtasks,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub username: String,
+    pub password_hash: Option<String>, // None for users provisioned through OIDC
+    #[serde(default)]
+    pub oidc_subject: Option<String>,
+    #[serde(default)]
+    pub totp_secret: Option<String>, // Base32, only set once the enrollment is confirmed
+    #[serde(default)]
+    pub recovery_code_hashes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Task {
+    pub id: Uuid,
+    pub category: String,
+    pub title: String,
+    pub text: String,
+    pub completed: bool,
+    pub due: Option<i64>, // UNIX timestamp, midnight UTC of the day for all-day deadlines
+    // Fields added later default when missing, so tasks stored before them are read as they are
+    #[serde(default)]
+    pub due_tz: Option<String>, // IANA time zone the deadline was set in, e.g. "Europe/Prague"
+    #[serde(default)]
+    pub all_day: bool,
+    #[serde(default)]
+    pub parent_id: Option<Uuid>, // The task this is a subtask of, None for top-level tasks
+    #[serde(default)]
+    pub version: u64, // 0 when created, incremented by every update
+    // Stamped by the server, tasks from before they existed have them backfilled when the backend migrates its data
+    #[serde(default)]
+    pub created_at: i64, // UNIX timestamp
+    #[serde(default)]
+    pub updated_at: i64, // UNIX timestamp, the same as created_at until the first update
+    #[serde(default)]
+    pub completed_at: Option<i64>, // UNIX timestamp, set while the task is completed
+    #[serde(default)]
+    pub created_by: Option<Author>, // None for tasks created before authorship was recorded
+    #[serde(default)]
+    pub updated_by: Option<Author>,
+}
+
+// The credential a task was changed with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Author {
+    Session(Uuid),
+    ApiToken(Uuid),
+}
+
+// Only the fields that are set get written, besides the version and the update's stamps
+pub struct TaskUpdate {
+    pub category: Option<String>,
+    pub title: Option<String>,
+    pub text: Option<String>,
+    pub completed: Option<bool>,
+    pub due: Nullable<i64>, // UNIX timestamp
+    pub due_tz: Nullable<String>,
+    pub all_day: Option<bool>,
+    pub updated_at: i64, // UNIX timestamp, also the completion time when the update completes the task
+    pub updated_by: Author,
+}
+
+// An update of a field that can be cleared. Deserializes from null as Clear, a missing field needs #[serde(default)].
+#[derive(Default, Clone, PartialEq, Eq)]
+pub enum Nullable<T> {
+    #[default]
+    Unchanged,
+    Clear,
+    Set(T),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub id: Uuid,
+    pub device: Option<String>,
+    pub created_at: i64,   // UNIX timestamp
+    pub refreshed_at: i64, // UNIX timestamp
+}
+
+pub enum JwtStatus {
+    Valid,
+    Revoked,
+    SessionRevoked,
+}
+
+#[derive(Clone)]
+pub struct RefreshToken {
+    pub username: String,
+    pub session_id: Uuid,
+    pub rotated: bool, // Whether it had already been rotated before
+}
+
+// A login whose password was correct, waiting for the second factor
+#[derive(Clone)]
+pub struct MfaLogin {
+    pub username: String,
+    pub device: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct OidcState {
+    pub nonce: String,
+    pub code_verifier: String,
+    pub device: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ApiTokenScope {
+    #[serde(rename = "read_only")]
+    ReadOnly,
+    #[serde(rename = "read_write")]
+    ReadWrite,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    pub scope: ApiTokenScope,
+    pub created_at: i64,         // UNIX timestamp
+    pub expires_at: Option<i64>, // UNIX timestamp
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiTokenRecord {
+    pub username: String,
+    #[serde(flatten)]
+    pub token: ApiToken,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum AuditEvent {
+    #[serde(rename = "account_locked")]
+    AccountLocked { username: String, ip: IpAddr, lockout_seconds: u64 },
+    #[serde(rename = "ip_locked")]
+    IpLocked { ip: IpAddr, lockout_seconds: u64 },
+}
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub timestamp: i64, // UNIX timestamp
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+#[allow(clippy::enum_variant_names)]
+pub enum Notification {
+    #[serde(rename = "task_created")]
+    TaskCreated { task: Task },
+    #[serde(rename = "task_updated")]
+    TaskUpdated { task: Task },
+    #[serde(rename = "task_deleted")]
+    TaskDeleted { task_id: Uuid, version: u64 }, // The version the task had when it was deleted
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum SessionEvent {
+    #[serde(rename = "session_revoked")]
+    SessionRevoked { session_id: Uuid },
+    #[serde(rename = "all_sessions_revoked")]
+    AllSessionsRevoked,
+    #[serde(rename = "account_deleted")]
+    AccountDeleted,
+    #[serde(rename = "api_token_revoked")]
+    ApiTokenRevoked { token_id: Uuid },
+}
+
+// Position in a user's notification stream, formatted like Redis stream IDs: `<UNIX timestamp in ms>-<sequence>`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventId {
+    pub millis: u64,
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct NotificationEvent {
+    pub event_id: EventId,
+    #[serde(flatten)]
+    pub notification: Notification,
+}
+
+// What a user's WebSockets receive
+#[derive(Clone)]
+pub enum Event {
+    Notification(Box<NotificationEvent>), // Boxed as tasks make it much larger than session events
+    Session(SessionEvent),
+}
+
+// Everything the handlers persist or publish. Expiring records take their TTL from the caller, so the policy stays
+// with the handlers and every backend expires them the same way.
+#[async_trait]
+pub trait TaskStore: Send + Sync {
+    async fn get_user(&self, username: &str) -> StoreResult<Option<User>>;
+
+    // Returns false if the username, or the user's OIDC subject, is already taken
+    async fn create_user(&self, user: &User) -> StoreResult<bool>;
+
+    async fn save_user(&self, user: &User) -> StoreResult<()>;
+
+    // Returns false if the password hash isn't `old_password_hash` anymore, None for accounts without a password
+    async fn replace_password_hash(
+        &self,
+        username: &str,
+        old_password_hash: Option<&str>,
+        new_password_hash: &str,
+    ) -> StoreResult<bool>;
+
+    // Removes the recovery code from the user's, returns false if it isn't among them (anymore). Of concurrent calls
+    // with the same code only one returns true.
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> StoreResult<bool>;
+
+    // Moves the user and its tasks to `user.username`. Sessions, API tokens and a pending TOTP enrollment are dropped
+    // and AllSessionsRevoked is published to the old username.
+    async fn rename_user(&self, old_username: &str, user: &User) -> StoreResult<()>;
+
+    // Deletes the user with everything that belongs to it and publishes AccountDeleted
+    async fn delete_user(&self, user: &User) -> StoreResult<()>;
+
+    async fn oidc_subject_username(&self, subject: &str) -> StoreResult<Option<String>>;
+
+    async fn create_session(&self, username: &str, session: &Session, ttl_seconds: u64) -> StoreResult<()>;
+
+    async fn get_session(&self, session_id: Uuid) -> StoreResult<Option<Session>>;
+
+    async fn update_session(&self, session: &Session, ttl_seconds: u64) -> StoreResult<()>;
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>>;
+
+    // Returns false if the session doesn't belong to the user, publishes SessionRevoked otherwise
+    async fn revoke_session(&self, username: &str, session_id: Uuid) -> StoreResult<bool>;
+
+    // Publishes AllSessionsRevoked if the user had any session
+    async fn revoke_all_sessions(&self, username: &str) -> StoreResult<()>;
+
+    async fn jwt_status(&self, jti: Uuid, session_id: Uuid) -> StoreResult<JwtStatus>;
+
+    async fn revoke_jwt(&self, jti: Uuid, ttl_seconds: u64) -> StoreResult<()>;
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        username: &str,
+        session_id: Uuid,
+        ttl_seconds: u64,
+    ) -> StoreResult<()>;
+
+    // Marks the refresh token as rotated, atomically, so only one of several concurrent rotations sees `rotated: false`
+    async fn rotate_refresh_token(&self, token_hash: &str) -> StoreResult<Option<RefreshToken>>;
+
+    async fn create_mfa_login(&self, token_hash: &str, mfa_login: &MfaLogin, ttl_seconds: u64) -> StoreResult<()>;
+
+    async fn get_mfa_login(&self, token_hash: &str) -> StoreResult<Option<MfaLogin>>;
+
+    // Returns false if the MFA login was already consumed (or expired)
+    async fn consume_mfa_login(&self, token_hash: &str) -> StoreResult<bool>;
+
+    async fn create_oidc_state(&self, state: &str, oidc_state: &OidcState, ttl_seconds: u64) -> StoreResult<()>;
+
+    // Returns and deletes the OIDC state
+    async fn take_oidc_state(&self, state: &str) -> StoreResult<Option<OidcState>>;
+
+    // Returns false if the TOTP time step was already used
+    async fn claim_totp_step(&self, username: &str, step: u64, ttl_seconds: u64) -> StoreResult<bool>;
+
+    async fn set_totp_enrollment(&self, username: &str, secret: &str, ttl_seconds: u64) -> StoreResult<()>;
+
+    async fn get_totp_enrollment(&self, username: &str) -> StoreResult<Option<String>>;
+
+    async fn delete_totp_enrollment(&self, username: &str) -> StoreResult<()>;
+
+    // Expires at `record.token.expires_at`, if set
+    async fn create_api_token(&self, token_hash: &str, record: &ApiTokenRecord) -> StoreResult<()>;
+
+    async fn get_api_token(&self, token_hash: &str) -> StoreResult<Option<ApiTokenRecord>>;
+
+    async fn list_api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>>;
+
+    // Returns false if the API token doesn't belong to the user, publishes ApiTokenRevoked otherwise
+    async fn revoke_api_token(&self, username: &str, token_id: Uuid) -> StoreResult<bool>;
+
+    // Records a failed login of `subject` and returns how many happened within the sliding window
+    async fn add_login_failure(&self, subject: &str, window_seconds: u64) -> StoreResult<usize>;
+
+    async fn clear_login_failures(&self, subject: &str) -> StoreResult<()>;
+
+    // Returns how many times the subject got locked out, counting this lockout
+    async fn escalate_lockout(&self, subject: &str, level_ttl_seconds: u64) -> StoreResult<u32>;
+
+    // Also clears the subject's failed logins
+    async fn lock_out(&self, subject: &str, seconds: u64) -> StoreResult<()>;
+
+    // Returns the longest remaining lockout of the subjects in seconds
+    async fn lockout_remaining(&self, subjects: &[String]) -> StoreResult<Option<u64>>;
+
+    // Keeps the `max_entries` most recent entries
+    async fn append_audit_entry(&self, entry: &AuditEntry, max_entries: usize) -> StoreResult<()>;
+
+    async fn list_tasks(&self, username: &str) -> StoreResult<Vec<Task>>;
+
+    async fn get_task(&self, username: &str, task_id: Uuid) -> StoreResult<Option<Task>>;
+
+    // The task's direct subtasks, without loading the user's other tasks
+    async fn list_subtasks(&self, username: &str, task_id: Uuid) -> StoreResult<Vec<Task>>;
+
+    // Publishes TaskCreated, but only once the task is committed
+    async fn create_task(&self, username: &str, task: &Task) -> StoreResult<()>;
+
+    // Writes only the updated fields, atomically, so concurrent updates of different fields don't overwrite each other.
+    // With `expected_version`, fails with VersionMismatch unless the task is still at that version. Returns the updated
+    // task, or None if it doesn't exist. Publishes TaskUpdated once the update is committed.
+    async fn update_task(
+        &self,
+        username: &str,
+        task_id: Uuid,
+        update: &TaskUpdate,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>>;
+
+    // For tasks without subtasks, whose deletion doesn't change any other task. Adding a subtask bumps the version of
+    // the task it's added to, so passing the version the task was seen without subtasks at makes sure it still has none.
+    // Returns false if the task doesn't exist, publishes TaskDeleted once the deletion is committed otherwise.
+    // `expected_version` is checked like for update_task.
+    async fn delete_task(&self, username: &str, task_id: Uuid, expected_version: Option<u64>) -> StoreResult<bool>;
+
+    // Writes the changes of change_tasks atomically, unless any of the tasks isn't at the expected version anymore, in
+    // which case it returns false and writes nothing. Publishes a notification for every task once they're committed.
+    async fn commit_tasks(&self, username: &str, changes: &TaskChanges) -> StoreResult<bool>;
+
+    // Streams the user's notifications and session events. With `last_event_id`, the notifications published after it
+    // that are still in the history come first.
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream>;
+}
+
+// Where the backends that don't keep events next to their data publish them, once the write is committed
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn publish_notification(&self, username: &str, notification: &Notification) -> StoreResult<()>;
+
+    async fn publish_session_event(&self, username: &str, session_event: &SessionEvent) -> StoreResult<()>;
+
+    // Drops the notification history of a renamed or deleted user
+    async fn forget(&self, username: &str) -> StoreResult<()>;
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream>;
+
+    // Called periodically, for sinks that don't expire their history on their own
+    fn prune(&self) {}
+}
+
+// The write an event is about is committed by the time it's published, so failing the request would only make the
+// client retry a write that already happened. Subscribers miss the event either way.
+pub fn log_publish_error(result: StoreResult<()>) {
+    if let Err(err) = result {
+        error!("Event publish error: {err}");
+    }
+}
+
+impl Task {
+    pub const fn check_version(&self, expected_version: Option<u64>) -> StoreResult<()> {
+        match expected_version {
+            Some(expected_version) if expected_version != self.version => {
+                Err(StoreError::VersionMismatch(self.version))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TaskUpdate {
+    // Bumps the version and stamps the task too, even if nothing changes
+    pub fn apply(&self, task: &mut Task) {
+        task.version += 1;
+        task.updated_at = self.updated_at;
+        task.updated_by = Some(self.updated_by);
+        if let Some(category) = &self.category {
+            task.category.clone_from(category);
+        }
+        if let Some(title) = &self.title {
+            task.title.clone_from(title);
+        }
+        if let Some(text) = &self.text {
+            task.text.clone_from(text);
+        }
+        if let Some(completed) = self.completed {
+            if completed != task.completed {
+                task.completed_at = completed.then_some(self.updated_at);
+            }
+            task.completed = completed;
+        }
+        self.due.apply(&mut task.due);
+        self.due_tz.apply(&mut task.due_tz);
+        if let Some(all_day) = self.all_day {
+            task.all_day = all_day;
+        }
+    }
+}
+
+impl EventId {
+    // The ID of an event published now, after this one
+    pub const fn next(self, now_millis: u64) -> Self {
+        if now_millis > self.millis {
+            Self {
+                millis: now_millis,
+                sequence: 0,
+            }
+        } else {
+            Self {
+                millis: self.millis,
+                sequence: self.sequence + 1,
+            }
+        }
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.millis, self.sequence)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = String;
+
+    fn from_str(event_id: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid event ID {event_id:?}");
+        let (millis, sequence) = event_id.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            millis: millis.parse().map_err(|_| invalid())?,
+            sequence: sequence.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+// Stored as `session:<uuid>` or `api_token:<uuid>` by the backends that don't store tasks as JSON
+impl fmt::Display for Author {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Session(session_id) => write!(f, "session:{session_id}"),
+            Self::ApiToken(token_id) => write!(f, "api_token:{token_id}"),
+        }
+    }
+}
+
+impl FromStr for Author {
+    type Err = String;
+
+    fn from_str(author: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid author {author:?}");
+        let (kind, id) = author.split_once(':').ok_or_else(invalid)?;
+        let id = Uuid::parse_str(id).map_err(|_| invalid())?;
+        match kind {
+            "session" => Ok(Self::Session(id)),
+            "api_token" => Ok(Self::ApiToken(id)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for EventId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<T: Clone + PartialEq> Nullable<T> {
+    // The update that turns `old` into `new`
+    pub fn change(old: Option<&T>, new: Option<T>) -> Self {
+        if old == new.as_ref() { Self::Unchanged } else { new.into() }
+    }
+
+    pub const fn is_unchanged(&self) -> bool {
+        matches!(self, Self::Unchanged)
+    }
+
+    pub const fn is_clear(&self) -> bool {
+        matches!(self, Self::Clear)
+    }
+
+    pub const fn value(&self) -> Option<&T> {
+        match self {
+            Self::Set(value) => Some(value),
+            Self::Unchanged | Self::Clear => None,
+        }
+    }
+
+    pub fn apply(&self, field: &mut Option<T>) {
+        match self {
+            Self::Unchanged => {}
+            Self::Clear => *field = None,
+            Self::Set(value) => *field = Some(value.clone()),
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Nullable<T> {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Clear, Self::Set)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Nullable<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<T>::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl<'de> Deserialize<'de> for EventId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backend(err) => err.fmt(f),
+            Self::Json(err) => write!(f, "Stored JSON is invalid: {err}"),
+            Self::Conflict => f.write_str("Modified concurrently, try again"),
+            Self::UsernameTaken => f.write_str("Username already exists"),
+            Self::VersionMismatch(version) => write!(f, "Task was modified meanwhile, it's at version {version} now"),
+            Self::UnknownParent => f.write_str("Parent task not found"),
+            Self::ParentCycle => f.write_str("A task can't be moved into its own subtree"),
+            Self::HasSubtasks => f.write_str("Task has subtasks, delete them with it or move them first"),
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<uuid::Error> for StoreError {
+    fn from(err: uuid::Error) -> Self {
+        Self::Backend(Box::new(err))
+    }
+}
+
+// STORE: Storage backend, "redis", "sql" (SQLite or PostgreSQL) or "memory" (nothing is persisted), defaults to "redis"
+pub async fn from_env() -> Result<Arc<dyn TaskStore>, Box<dyn Error>> {
+    match env::var("STORE").as_deref().unwrap_or("redis") {
+        "redis" => Ok(Arc::new(RedisStore::from_env().await?)),
+        "sql" => Ok(SqlStore::from_env().await?),
+        "memory" => Ok(MemoryStore::new()),
+        store => Err(format!("Unsupported STORE {store:?}").into()),
+    }
+}
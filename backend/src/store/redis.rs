@@ -0,0 +1,1340 @@
+use std::{collections::HashMap, env, error::Error, fmt::Display};
+
+use async_trait::async_trait;
+use bb8::RunError;
+use chrono::Utc;
+use futures::{FutureExt, StreamExt, TryStreamExt, stream};
+use redis::{
+    AsyncCommands,
+    AsyncConnectionConfig,
+    Client as RedisClient,
+    ExistenceCheck,
+    Pipeline,
+    RedisError,
+    SetExpiry,
+    SetOptions,
+    ToRedisArgs,
+    aio::MultiplexedConnection,
+    streams::{StreamId, StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply},
+};
+use serde::de::DeserializeOwned;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::store::{
+    ApiToken,
+    ApiTokenRecord,
+    AuditEntry,
+    Author,
+    Event,
+    EventId,
+    EventSink,
+    EventStream,
+    JwtStatus,
+    MfaLogin,
+    NOTIFICATION_HISTORY_LENGTH,
+    NOTIFICATION_HISTORY_SECONDS,
+    Notification,
+    NotificationEvent,
+    OidcState,
+    RefreshToken,
+    Session,
+    SessionEvent,
+    StoreError,
+    StoreResult,
+    Task,
+    TaskStore,
+    TaskUpdate,
+    User,
+    hierarchy::TaskChanges,
+};
+
+type Pool = bb8::Pool<RedisClient>;
+
+const SCHEMA_VERSION: u32 = 3;
+
+const TASK_BATCH_SIZE: usize = 500; // Tasks loaded per pipeline, so huge lists don't build one huge reply
+
+const TASK_TRANSACTION_ATTEMPTS: usize = 5; // Before giving up on a task that keeps being modified concurrently
+const USER_TRANSACTION_ATTEMPTS: usize = 5; // Before giving up on a user record that keeps being modified concurrently
+const TOKEN_TRANSACTION_ATTEMPTS: usize = 5; // Before giving up on a refresh token that keeps being modified concurrently
+
+const NOTIFICATION_BATCH_SIZE: usize = 100; // Notifications read per XREAD
+
+pub struct RedisStore {
+    pool: Pool,
+    events: RedisEvents,
+}
+
+// Just the events part, which other backends use to fan events out to every instance. Notifications go through a
+// capped stream per user so reconnecting WebSockets can catch up, session events through pub/sub.
+pub struct RedisEvents {
+    client: RedisClient, // Pub/sub and blocking reads need dedicated connections, the pool's are multiplexed
+    pool: Pool,
+}
+
+// UNWATCHes the connection when dropped unless disarmed, so a transaction whose future is dropped between its WATCH and
+// its EXEC doesn't go back to the pool with the keys still WATCHed
+struct WatchGuard(Option<MultiplexedConnection>);
+
+struct UserKeys {
+    task_ids: Vec<String>,
+    session_ids: Vec<String>,
+    api_token_hashes: Vec<String>,
+}
+
+impl From<RedisError> for StoreError {
+    fn from(err: RedisError) -> Self {
+        Self::Backend(Box::new(err))
+    }
+}
+
+impl From<RunError<RedisError>> for StoreError {
+    fn from(err: RunError<RedisError>) -> Self {
+        Self::Backend(Box::new(err))
+    }
+}
+
+impl RedisStore {
+    // REDIS_URL: Defaults to "redis://127.0.0.1:6379"
+    pub async fn from_env() -> Result<Self, Box<dyn Error>> {
+        Self::connect(&env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned())).await
+    }
+
+    pub async fn connect(url: &str) -> Result<Self, Box<dyn Error>> {
+        let events = RedisEvents::connect(url).await?;
+        let store = Self {
+            pool: events.pool.clone(),
+            events,
+        };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    // Brings data written by older versions up to date, once. Safe to run from several instances at the same time.
+    async fn migrate(&self) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let version: Option<u32> = conn.get("schema_version").await?;
+        if version.unwrap_or_default() >= SCHEMA_VERSION {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp();
+        let task_keys: Vec<String> = conn.scan_match("task:*").await?.try_collect().await?;
+        // Version 1: Tasks went from JSON strings to hashes
+        for task_key in &task_keys {
+            loop {
+                let migrated = watching(&mut conn, task_key, async |conn| {
+                    let key_type: String = redis::cmd("TYPE").arg(task_key).query_async(&mut *conn).await?;
+                    if key_type != "string" {
+                        unwatch(conn).await?;
+                        return Ok(true);
+                    }
+                    let task: Option<Task> = get_json(conn, task_key).await?;
+                    let mut pipe = redis::pipe();
+                    pipe.atomic().del(task_key).ignore();
+                    if let Some(task) = task {
+                        pipe.hset_multiple(task_key, &task_fields(&task))
+                            .ignore()
+                            .hset_multiple(task_key, &backfilled_stamp_fields(task.completed, now))
+                            .ignore();
+                    }
+                    let committed: Option<()> = pipe.query_async(conn).await?;
+                    Ok(committed.is_some())
+                })
+                .await?;
+                if migrated {
+                    break;
+                }
+            }
+        }
+        // Version 2: Tasks got timestamps
+        for task_key in &task_keys {
+            loop {
+                let migrated = watching(&mut conn, task_key, async |conn| {
+                    let fields: HashMap<String, String> = conn.hgetall(task_key).await?;
+                    if fields.is_empty() || fields.contains_key("created_at") {
+                        unwatch(conn).await?;
+                        return Ok(true);
+                    }
+                    let completed = fields.get("completed").is_some_and(|completed| completed == "true");
+                    let committed: Option<()> = redis::pipe()
+                        .atomic()
+                        .hset_multiple(task_key, &backfilled_stamp_fields(completed, now))
+                        .ignore()
+                        .query_async(conn)
+                        .await?;
+                    Ok(committed.is_some())
+                })
+                .await?;
+                if migrated {
+                    break;
+                }
+            }
+        }
+        // Version 3: Subtasks got indexed by the task they're in. SADD is idempotent, so no transaction is needed.
+        for task_keys in task_keys.chunks(TASK_BATCH_SIZE) {
+            let mut pipe = redis::pipe();
+            for task_key in task_keys {
+                pipe.hget(task_key, "parent_id");
+            }
+            let parent_ids: Vec<Option<String>> = pipe.query_async(&mut *conn).await?;
+            let mut pipe = redis::pipe();
+            for (task_key, parent_id) in task_keys.iter().zip(parent_ids) {
+                // Task keys are `task:<username>:<uuid>`, and usernames can't contain ':'
+                let (Some(parent_id), Some((username, task_id))) =
+                    (parent_id, task_key.strip_prefix("task:").and_then(|key| key.split_once(':')))
+                else {
+                    continue;
+                };
+                pipe.sadd(format!("subtask_ids:{username}:{parent_id}"), task_id).ignore();
+            }
+            pipe.exec_async(&mut *conn).await?;
+        }
+        Ok(conn.set("schema_version", SCHEMA_VERSION).await?)
+    }
+}
+
+impl RedisEvents {
+    pub async fn connect(url: &str) -> Result<Self, Box<dyn Error>> {
+        let client = RedisClient::open(url)?;
+        let pool = Pool::builder().build(client.clone()).await?;
+        Ok(Self {
+            client,
+            pool,
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for RedisEvents {
+    async fn publish_notification(&self, username: &str, notification: &Notification) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let mut pipe = redis::pipe();
+        add_notification(pipe.atomic(), username, &serde_json::to_string(notification)?);
+        Ok(pipe.exec_async(&mut *conn).await?)
+    }
+
+    async fn publish_session_event(&self, username: &str, session_event: &SessionEvent) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.publish(format!("session_events:{username}"), serde_json::to_string(session_event)?).await?)
+    }
+
+    async fn forget(&self, username: &str) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.del(notification_stream_key(username)).await?)
+    }
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(format!("session_events:{username}")).await?;
+        let session_events = pubsub.into_on_message().map(|msg| {
+            let payload: String = msg.get_payload()?;
+            Ok(Event::Session(serde_json::from_str(&payload)?))
+        });
+        // XREAD BLOCK waits for as long as it takes, longer than the default response timeout
+        let mut conn = self
+            .client
+            .get_multiplexed_async_connection_with_config(&AsyncConnectionConfig::new().set_response_timeout(None))
+            .await?;
+        let stream_key = notification_stream_key(username);
+        // Without a last event ID, only what's published from now on is sent. The current last ID is looked up now
+        // rather than reading from "$", which would skip whatever is published between two reads.
+        let start_id = if let Some(last_event_id) = last_event_id {
+            last_event_id.to_string()
+        } else {
+            let reply: StreamRangeReply = conn.xrevrange_count(&stream_key, "+", "-", 1).await?;
+            reply.ids.first().map_or_else(|| "0-0".to_owned(), |entry| entry.id.clone())
+        };
+        let notifications = stream::unfold(Some((conn, start_id)), move |state| {
+            let stream_key = stream_key.clone();
+            async move {
+                let (mut conn, last_id) = state?;
+                match read_notifications(&mut conn, &stream_key, &last_id).await {
+                    Ok(events) => {
+                        let last_id = events.last().map_or(last_id, |event| event.event_id.to_string());
+                        let events = events.into_iter().map(|event| Ok(Event::Notification(Box::new(event)))).collect();
+                        Some((events, Some((conn, last_id))))
+                    }
+                    Err(err) => Some((vec![Err(err)], None)),
+                }
+            }
+        })
+        .flat_map(stream::iter);
+        Ok(stream::select(session_events, notifications).boxed())
+    }
+}
+
+fn notification_stream_key(username: &str) -> String {
+    format!("notification_stream:{username}")
+}
+
+// Appends to the user's notification stream, which keeps roughly the last NOTIFICATION_HISTORY_LENGTH notifications
+// and expires once the user has been idle for NOTIFICATION_HISTORY_SECONDS. Redis assigns the event ID.
+fn add_notification(pipe: &mut Pipeline, username: &str, notification_json: &str) {
+    let stream_key = notification_stream_key(username);
+    pipe.xadd_maxlen(&stream_key, StreamMaxlen::Approx(NOTIFICATION_HISTORY_LENGTH), "*", &[(
+        "notification",
+        notification_json,
+    )])
+    .ignore()
+    .expire(&stream_key, NOTIFICATION_HISTORY_SECONDS.cast_signed())
+    .ignore();
+}
+
+// Blocks until there are notifications after `last_id`
+async fn read_notifications(
+    conn: &mut MultiplexedConnection,
+    stream_key: &str,
+    last_id: &str,
+) -> StoreResult<Vec<NotificationEvent>> {
+    let options = StreamReadOptions::default().block(0).count(NOTIFICATION_BATCH_SIZE);
+    let reply: Option<StreamReadReply> = conn.xread_options(&[stream_key], &[last_id], &options).await?;
+    reply
+        .into_iter()
+        .flat_map(|reply| reply.keys)
+        .flat_map(|key| key.ids)
+        .map(|entry| notification_event(&entry))
+        .collect()
+}
+
+fn notification_event(entry: &StreamId) -> StoreResult<NotificationEvent> {
+    let invalid = || StoreError::Backend(format!("Notification stream entry {} is invalid", entry.id).into());
+    let notification_json: String = entry.get("notification").ok_or_else(invalid)?;
+    Ok(NotificationEvent {
+        event_id: entry.id.parse().map_err(|_| invalid())?,
+        notification: serde_json::from_str(&notification_json)?,
+    })
+}
+
+// The user record and every key that lists the user's tasks, sessions and API tokens. A transaction built from what
+// they list WATCHes them, so it fails instead of missing one created meanwhile.
+fn user_watch_keys(username: &str) -> Vec<String> {
+    vec![
+        format!("user:{username}"),
+        format!("task_ids:{username}"),
+        format!("sessions:{username}"),
+        format!("api_tokens:{username}"),
+    ]
+}
+
+async fn user_keys(conn: &mut MultiplexedConnection, username: &str) -> StoreResult<UserKeys> {
+    let (task_ids, session_ids, api_token_hashes) = redis::pipe()
+        .smembers(format!("task_ids:{username}"))
+        .smembers(format!("sessions:{username}"))
+        .hvals(format!("api_tokens:{username}"))
+        .query_async(conn)
+        .await?;
+    Ok(UserKeys {
+        task_ids,
+        session_ids,
+        api_token_hashes,
+    })
+}
+
+// Runs `transaction` with the keys WATCHed. Pooled connections outlive the transaction, so if it fails or is dropped
+// before its EXEC the keys are UNWATCHed again, or the next transaction on the connection could be aborted by them. A
+// transaction that returns without an EXEC has to UNWATCH itself.
+async fn watching<T>(
+    conn: &mut MultiplexedConnection,
+    keys: impl ToRedisArgs,
+    transaction: impl AsyncFnOnce(&mut MultiplexedConnection) -> StoreResult<T>,
+) -> StoreResult<T> {
+    redis::cmd("WATCH").arg(keys).exec_async(&mut *conn).await?;
+    let guard = WatchGuard(Some(conn.clone()));
+    let result = transaction(conn).await;
+    if result.is_ok() {
+        guard.disarm();
+    }
+    result
+}
+
+async fn unwatch(conn: &mut MultiplexedConnection) -> StoreResult<()> {
+    Ok(redis::cmd("UNWATCH").exec_async(conn).await?)
+}
+
+impl WatchGuard {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for WatchGuard {
+    // Drop can't wait for the reply, so the UNWATCH is only queued, on the connection the guard shares with the pooled
+    // one. Whoever gets the connection from the pool next is queued after it. Queuing only fails if the connection is
+    // broken or backed up, which it can't be for long without being broken.
+    fn drop(&mut self) {
+        if let Some(mut conn) = self.0.take() {
+            let mut unwatch = redis::cmd("UNWATCH");
+            unwatch.set_no_response(true);
+            if !matches!(conn.send_packed_command(&unwatch).now_or_never(), Some(Ok(_))) {
+                warn!("Failed to queue an UNWATCH, the connection's next transaction may be aborted");
+            }
+        }
+    }
+}
+
+// Tasks are hashes so single fields can be written without reading the rest. `due`, `due_tz`, `parent_id`,
+// `completed_at` and the authors are left out when unset, and `all_day` and `version` may be missing from tasks written before they existed.
+fn task_fields(task: &Task) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("id", task.id.to_string()),
+        ("category", task.category.clone()),
+        ("title", task.title.clone()),
+        ("text", task.text.clone()),
+        ("completed", task.completed.to_string()),
+        ("all_day", task.all_day.to_string()),
+        ("version", task.version.to_string()),
+        ("created_at", task.created_at.to_string()),
+        ("updated_at", task.updated_at.to_string()),
+    ];
+    let optional_fields = [
+        ("due", task.due.map(|due| due.to_string())),
+        ("due_tz", task.due_tz.clone()),
+        ("parent_id", task.parent_id.map(|parent_id| parent_id.to_string())),
+        ("completed_at", task.completed_at.map(|completed_at| completed_at.to_string())),
+        ("created_by", task.created_by.map(|author| author.to_string())),
+        ("updated_by", task.updated_by.map(|author| author.to_string())),
+    ];
+    fields.extend(optional_fields.into_iter().filter_map(|(field, value)| Some((field, value?))));
+    fields
+}
+
+// Tasks from before the timestamps existed are taken to have been created, and completed if they are, when migrated
+fn backfilled_stamp_fields(completed: bool, now: i64) -> Vec<(&'static str, String)> {
+    let mut fields = vec![("created_at", now.to_string()), ("updated_at", now.to_string())];
+    if completed {
+        fields.push(("completed_at", now.to_string()));
+    }
+    fields
+}
+
+fn task_update_fields(update: &TaskUpdate) -> Vec<(&'static str, String)> {
+    [
+        ("category", update.category.clone()),
+        ("title", update.title.clone()),
+        ("text", update.text.clone()),
+        ("completed", update.completed.map(|completed| completed.to_string())),
+        ("due", update.due.value().map(ToString::to_string)),
+        ("due_tz", update.due_tz.value().cloned()),
+        ("all_day", update.all_day.map(|all_day| all_day.to_string())),
+        ("updated_at", Some(update.updated_at.to_string())),
+        ("updated_by", Some(update.updated_by.to_string())),
+    ]
+    .into_iter()
+    .filter_map(|(field, value)| Some((field, value?)))
+    .collect()
+}
+
+// Unset fields are left out of the hash rather than stored empty
+fn task_cleared_fields(update: &TaskUpdate) -> Vec<&'static str> {
+    [("due", update.due.is_clear()), ("due_tz", update.due_tz.is_clear())]
+        .into_iter()
+        .filter_map(|(field, cleared)| cleared.then_some(field))
+        .collect()
+}
+
+// An empty hash is a task that doesn't exist
+fn task_from_fields(mut fields: HashMap<String, String>) -> StoreResult<Option<Task>> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let mut take = |field: &str| {
+        fields.remove(field).ok_or_else(|| StoreError::Backend(format!("Task hash is missing {field:?}").into()))
+    };
+    let parse_error = |err: &dyn Display| StoreError::Backend(format!("Task hash is invalid: {err}").into());
+    Ok(Some(Task {
+        id: Uuid::parse_str(&take("id")?)?,
+        category: take("category")?,
+        title: take("title")?,
+        text: take("text")?,
+        completed: take("completed")?.parse().map_err(|err| parse_error(&err))?,
+        created_at: take("created_at")?.parse().map_err(|err| parse_error(&err))?,
+        updated_at: take("updated_at")?.parse().map_err(|err| parse_error(&err))?,
+        due: fields.remove("due").map(|due| due.parse()).transpose().map_err(|err| parse_error(&err))?,
+        due_tz: fields.remove("due_tz"),
+        all_day: fields
+            .remove("all_day")
+            .map(|all_day| all_day.parse())
+            .transpose()
+            .map_err(|err| parse_error(&err))?
+            .unwrap_or_default(),
+        parent_id: fields.remove("parent_id").map(|parent_id| Uuid::parse_str(&parent_id)).transpose()?,
+        version: fields
+            .remove("version")
+            .map(|version| version.parse())
+            .transpose()
+            .map_err(|err| parse_error(&err))?
+            .unwrap_or_default(),
+        completed_at: fields
+            .remove("completed_at")
+            .map(|completed_at| completed_at.parse())
+            .transpose()
+            .map_err(|err| parse_error(&err))?,
+        created_by: fields
+            .remove("created_by")
+            .map(|author| author.parse::<Author>())
+            .transpose()
+            .map_err(|err| parse_error(&err))?,
+        updated_by: fields
+            .remove("updated_by")
+            .map(|author| author.parse::<Author>())
+            .transpose()
+            .map_err(|err| parse_error(&err))?,
+    }))
+}
+
+async fn get_task(conn: &mut MultiplexedConnection, task_key: &str) -> StoreResult<Option<Task>> {
+    task_from_fields(conn.hgetall(task_key).await?)
+}
+
+// Loads the tasks in pipelines of TASK_BATCH_SIZE, along with the IDs whose task is gone, e.g. after a failed write
+async fn get_tasks<'a>(
+    conn: &mut MultiplexedConnection,
+    username: &str,
+    task_ids: &'a [String],
+) -> StoreResult<(Vec<Task>, Vec<&'a String>)> {
+    let mut tasks = Vec::with_capacity(task_ids.len());
+    let mut dangling_task_ids = Vec::new();
+    for task_ids in task_ids.chunks(TASK_BATCH_SIZE) {
+        let mut pipe = redis::pipe();
+        for task_id in task_ids {
+            pipe.hgetall(format!("task:{username}:{task_id}"));
+        }
+        let task_fields: Vec<HashMap<String, String>> = pipe.query_async(&mut *conn).await?;
+        for (task_id, fields) in task_ids.iter().zip(task_fields) {
+            match task_from_fields(fields)? {
+                Some(task) => tasks.push(task),
+                None => dangling_task_ids.push(task_id),
+            }
+        }
+    }
+    Ok((tasks, dangling_task_ids))
+}
+
+async fn get_json<T: DeserializeOwned>(conn: &mut MultiplexedConnection, key: &str) -> StoreResult<Option<T>> {
+    let json: Option<String> = conn.get(key).await?;
+    Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+}
+
+#[async_trait]
+impl TaskStore for RedisStore {
+    async fn get_user(&self, username: &str) -> StoreResult<Option<User>> {
+        let mut conn = self.pool.get().await?;
+        get_json(&mut conn, &format!("user:{username}")).await
+    }
+
+    async fn create_user(&self, user: &User) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let user_key = format!("user:{}", user.username);
+        let user_json = serde_json::to_string(user)?;
+        let Some(oidc_subject) = &user.oidc_subject else {
+            let created: Option<String> = conn
+                .set_options(&user_key, user_json, SetOptions::default().conditional_set(ExistenceCheck::NX))
+                .await?;
+            return Ok(created.is_some());
+        };
+        let subject_key = format!("oidc_subject:{oidc_subject}");
+        watching(&mut conn, &[&user_key, &subject_key], async |conn| {
+            let taken: usize = conn.exists(&[&user_key, &subject_key]).await?;
+            if taken > 0 {
+                unwatch(conn).await?;
+                return Ok(false);
+            }
+            let committed: Option<()> = redis::pipe()
+                .atomic()
+                .set(&user_key, user_json)
+                .ignore()
+                .set(&subject_key, &user.username)
+                .ignore()
+                .query_async(conn)
+                .await?;
+            Ok(committed.is_some())
+        })
+        .await
+    }
+
+    async fn save_user(&self, user: &User) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let user_json = serde_json::to_string(user)?;
+        Ok(conn.set(format!("user:{}", user.username), user_json).await?)
+    }
+
+    async fn replace_password_hash(
+        &self,
+        username: &str,
+        old_password_hash: Option<&str>,
+        new_password_hash: &str,
+    ) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let user_key = format!("user:{username}");
+        watching(&mut conn, &user_key, async |conn| {
+            let user: Option<User> = get_json(conn, &user_key).await?;
+            let Some(mut user) = user.filter(|user| user.password_hash.as_deref() == old_password_hash) else {
+                unwatch(conn).await?;
+                return Ok(false);
+            };
+            user.password_hash = Some(new_password_hash.to_owned());
+            let user_json = serde_json::to_string(&user)?;
+            let committed: Option<()> =
+                redis::pipe().atomic().set(&user_key, user_json).ignore().query_async(conn).await?;
+            Ok(committed.is_some())
+        })
+        .await
+    }
+
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let user_key = format!("user:{username}");
+        for _ in 0..USER_TRANSACTION_ATTEMPTS {
+            // None if the user was modified meanwhile
+            let consumed = watching(&mut conn, &user_key, async |conn| {
+                let user: Option<User> = get_json(conn, &user_key).await?;
+                let Some(mut user) = user else {
+                    unwatch(conn).await?;
+                    return Ok(Some(false));
+                };
+                let Some(index) = user.recovery_code_hashes.iter().position(|hash| hash == code_hash) else {
+                    unwatch(conn).await?;
+                    return Ok(Some(false));
+                };
+                user.recovery_code_hashes.remove(index);
+                let user_json = serde_json::to_string(&user)?;
+                let committed: Option<()> =
+                    redis::pipe().atomic().set(&user_key, user_json).ignore().query_async(conn).await?;
+                Ok(committed.map(|()| true))
+            })
+            .await?;
+            if let Some(consumed) = consumed {
+                return Ok(consumed);
+            }
+        }
+        Err(StoreError::Conflict)
+    }
+
+    async fn rename_user(&self, old_username: &str, user: &User) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let new_username = &user.username;
+        let new_user_key = format!("user:{new_username}");
+        let mut watched_keys = user_watch_keys(old_username);
+        watched_keys.push(new_user_key.clone());
+        watching(&mut conn, watched_keys, async |conn| {
+            let UserKeys {
+                task_ids,
+                session_ids,
+                api_token_hashes,
+            } = user_keys(conn, old_username).await?;
+            if conn.exists(&new_user_key).await? {
+                return Err(StoreError::UsernameTaken);
+            }
+            // Skip IDs whose task no longer exists and tasks without subtasks, RENAME would fail on their keys
+            let keys_exist: Vec<bool> = if task_ids.is_empty() {
+                Vec::new()
+            } else {
+                let mut pipe = redis::pipe();
+                for task_id in &task_ids {
+                    pipe.exists(format!("task:{old_username}:{task_id}"))
+                        .exists(format!("subtask_ids:{old_username}:{task_id}"));
+                }
+                pipe.query_async(&mut *conn).await?
+            };
+            let (task_ids, parent_ids): (Vec<_>, Vec<_>) = task_ids
+                .into_iter()
+                .zip(keys_exist.chunks(2))
+                .filter(|(_, exist)| exist[0])
+                .map(|(task_id, exist)| {
+                    let parent_id = exist[1].then(|| task_id.clone());
+                    (task_id, parent_id)
+                })
+                .unzip();
+            let user_json = serde_json::to_string(user)?;
+            let event_json = serde_json::to_string(&SessionEvent::AllSessionsRevoked)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .set(&new_user_key, user_json)
+                .ignore()
+                .del(format!("user:{old_username}"))
+                .ignore()
+                .del(format!("task_ids:{old_username}"))
+                .ignore()
+                .del(format!("sessions:{old_username}"))
+                .ignore()
+                .del(format!("api_tokens:{old_username}"))
+                .ignore()
+                .del(format!("totp_enrollment:{old_username}"))
+                .ignore()
+                .publish(format!("session_events:{old_username}"), event_json)
+                .ignore()
+                .del(notification_stream_key(old_username))
+                .ignore();
+            if let Some(oidc_subject) = &user.oidc_subject {
+                pipe.set(format!("oidc_subject:{oidc_subject}"), new_username).ignore();
+            }
+            for task_id in &task_ids {
+                pipe.rename(format!("task:{old_username}:{task_id}"), format!("task:{new_username}:{task_id}"))
+                    .ignore();
+            }
+            for parent_id in parent_ids.iter().flatten() {
+                pipe.rename(
+                    format!("subtask_ids:{old_username}:{parent_id}"),
+                    format!("subtask_ids:{new_username}:{parent_id}"),
+                )
+                .ignore();
+            }
+            if !task_ids.is_empty() {
+                pipe.sadd(format!("task_ids:{new_username}"), task_ids).ignore();
+            }
+            for session_id in session_ids {
+                pipe.del(format!("session:{session_id}")).ignore();
+            }
+            for api_token_hash in api_token_hashes {
+                pipe.del(format!("api_token:{api_token_hash}")).ignore();
+            }
+            let committed: Option<()> = pipe.query_async(conn).await?;
+            committed.ok_or(StoreError::Conflict)
+        })
+        .await
+    }
+
+    async fn delete_user(&self, user: &User) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let username = &user.username;
+        watching(&mut conn, user_watch_keys(username), async |conn| {
+            let UserKeys {
+                task_ids,
+                session_ids,
+                api_token_hashes,
+            } = user_keys(conn, username).await?;
+            let event_json = serde_json::to_string(&SessionEvent::AccountDeleted)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .del(format!("user:{username}"))
+                .ignore()
+                .del(format!("task_ids:{username}"))
+                .ignore()
+                .del(format!("sessions:{username}"))
+                .ignore()
+                .del(format!("api_tokens:{username}"))
+                .ignore()
+                .del(format!("totp_enrollment:{username}"))
+                .ignore()
+                .publish(format!("session_events:{username}"), event_json)
+                .ignore()
+                .del(notification_stream_key(username))
+                .ignore();
+            if let Some(oidc_subject) = &user.oidc_subject {
+                pipe.del(format!("oidc_subject:{oidc_subject}")).ignore();
+            }
+            for task_id in task_ids {
+                pipe.del(format!("task:{username}:{task_id}"))
+                    .ignore()
+                    .del(format!("subtask_ids:{username}:{task_id}"))
+                    .ignore();
+            }
+            for session_id in session_ids {
+                pipe.del(format!("session:{session_id}")).ignore();
+            }
+            for api_token_hash in api_token_hashes {
+                pipe.del(format!("api_token:{api_token_hash}")).ignore();
+            }
+            let committed: Option<()> = pipe.query_async(conn).await?;
+            committed.ok_or(StoreError::Conflict)
+        })
+        .await
+    }
+
+    async fn oidc_subject_username(&self, subject: &str) -> StoreResult<Option<String>> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.get(format!("oidc_subject:{subject}")).await?)
+    }
+
+    async fn create_session(&self, username: &str, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let session_json = serde_json::to_string(session)?;
+        Ok(redis::pipe()
+            .atomic()
+            .set_ex(format!("session:{}", session.id), session_json, ttl_seconds)
+            .sadd(format!("sessions:{username}"), session.id.to_string())
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> StoreResult<Option<Session>> {
+        let mut conn = self.pool.get().await?;
+        get_json(&mut conn, &format!("session:{session_id}")).await
+    }
+
+    async fn update_session(&self, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let session_json = serde_json::to_string(session)?;
+        Ok(conn.set_ex(format!("session:{}", session.id), session_json, ttl_seconds).await?)
+    }
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>> {
+        let mut conn = self.pool.get().await?;
+        let sessions_key = format!("sessions:{username}");
+        let session_ids: Vec<String> = conn.smembers(&sessions_key).await?;
+        let mut sessions = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            if let Some(session) = get_json(&mut conn, &format!("session:{session_id}")).await? {
+                sessions.push(session);
+            } else {
+                // The session expired, drop it from the index
+                conn.srem::<_, _, ()>(&sessions_key, session_id).await?;
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn revoke_session(&self, username: &str, session_id: Uuid) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let sessions_key = format!("sessions:{username}");
+        if !conn.sismember(&sessions_key, session_id.to_string()).await? {
+            return Ok(false);
+        }
+        let event_json = serde_json::to_string(&SessionEvent::SessionRevoked {
+            session_id,
+        })?;
+        redis::pipe()
+            .atomic()
+            .del(format!("session:{session_id}"))
+            .srem(&sessions_key, session_id.to_string())
+            .publish(format!("session_events:{username}"), event_json)
+            .exec_async(&mut *conn)
+            .await?;
+        Ok(true)
+    }
+
+    async fn revoke_all_sessions(&self, username: &str) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let sessions_key = format!("sessions:{username}");
+        let session_ids: Vec<String> = conn.smembers(&sessions_key).await?;
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+        let session_keys: Vec<_> = session_ids.iter().map(|session_id| format!("session:{session_id}")).collect();
+        let event_json = serde_json::to_string(&SessionEvent::AllSessionsRevoked)?;
+        Ok(redis::pipe()
+            .atomic()
+            .del(session_keys)
+            .srem(&sessions_key, session_ids)
+            .publish(format!("session_events:{username}"), event_json)
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn jwt_status(&self, jti: Uuid, session_id: Uuid) -> StoreResult<JwtStatus> {
+        let mut conn = self.pool.get().await?;
+        let (revoked, session_exists): (bool, bool) = redis::pipe()
+            .exists(format!("revoked:{jti}"))
+            .exists(format!("session:{session_id}"))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(if revoked {
+            JwtStatus::Revoked
+        } else if session_exists {
+            JwtStatus::Valid
+        } else {
+            JwtStatus::SessionRevoked
+        })
+    }
+
+    async fn revoke_jwt(&self, jti: Uuid, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.set_ex(format!("revoked:{jti}"), "1", ttl_seconds).await?)
+    }
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        username: &str,
+        session_id: Uuid,
+        ttl_seconds: u64,
+    ) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let token_key = format!("refresh_token:{token_hash}");
+        Ok(redis::pipe()
+            .atomic()
+            .hset_multiple(&token_key, &[("username", username.to_owned()), ("session", session_id.to_string())])
+            .expire(&token_key, ttl_seconds.cast_signed())
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn rotate_refresh_token(&self, token_hash: &str) -> StoreResult<Option<RefreshToken>> {
+        let mut conn = self.pool.get().await?;
+        let token_key = format!("refresh_token:{token_hash}");
+        for _ in 0..TOKEN_TRANSACTION_ATTEMPTS {
+            // An HSETNX on its own would recreate a token that expired or was revoked after the read, without a TTL
+            let rotation = watching(&mut conn, &token_key, async |conn| {
+                let (username, session_id): (Option<String>, Option<String>) =
+                    conn.hmget(&token_key, &["username", "session"]).await?;
+                let (Some(username), Some(session_id)) = (username, session_id) else {
+                    unwatch(conn).await?;
+                    return Ok(Some(None));
+                };
+                let session_id = session_id.parse()?;
+                // None if the token was modified meanwhile
+                let first_rotation: Option<(bool,)> =
+                    redis::pipe().atomic().hset_nx(&token_key, "rotated", "1").query_async(conn).await?;
+                Ok(first_rotation.map(|(first_rotation,)| {
+                    Some(RefreshToken {
+                        username,
+                        session_id,
+                        rotated: !first_rotation,
+                    })
+                }))
+            })
+            .await?;
+            if let Some(rotation) = rotation {
+                return Ok(rotation);
+            }
+        }
+        Err(StoreError::Conflict)
+    }
+
+    async fn create_mfa_login(&self, token_hash: &str, mfa_login: &MfaLogin, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let token_key = format!("mfa_token:{token_hash}");
+        let mut fields = vec![("username", mfa_login.username.clone())];
+        fields.extend(mfa_login.device.clone().map(|device| ("device", device)));
+        Ok(redis::pipe()
+            .atomic()
+            .hset_multiple(&token_key, &fields)
+            .expire(&token_key, ttl_seconds.cast_signed())
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn get_mfa_login(&self, token_hash: &str) -> StoreResult<Option<MfaLogin>> {
+        let mut conn = self.pool.get().await?;
+        let (username, device): (Option<String>, Option<String>) =
+            conn.hmget(format!("mfa_token:{token_hash}"), &["username", "device"]).await?;
+        Ok(username.map(|username| MfaLogin {
+            username,
+            device,
+        }))
+    }
+
+    async fn consume_mfa_login(&self, token_hash: &str) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        // DEL only succeeds for one of several concurrent requests
+        Ok(conn.del(format!("mfa_token:{token_hash}")).await?)
+    }
+
+    async fn create_oidc_state(&self, state: &str, oidc_state: &OidcState, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let state_key = format!("oidc_state:{state}");
+        let mut fields = vec![("nonce", oidc_state.nonce.clone()), ("code_verifier", oidc_state.code_verifier.clone())];
+        fields.extend(oidc_state.device.clone().map(|device| ("device", device)));
+        Ok(redis::pipe()
+            .atomic()
+            .hset_multiple(&state_key, &fields)
+            .expire(&state_key, ttl_seconds.cast_signed())
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn take_oidc_state(&self, state: &str) -> StoreResult<Option<OidcState>> {
+        let mut conn = self.pool.get().await?;
+        let state_key = format!("oidc_state:{state}");
+        let ((nonce, code_verifier, device),): ((Option<String>, Option<String>, Option<String>),) = redis::pipe()
+            .atomic()
+            .hmget(&state_key, &["nonce", "code_verifier", "device"])
+            .del(&state_key)
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        let (Some(nonce), Some(code_verifier)) = (nonce, code_verifier) else {
+            return Ok(None);
+        };
+        Ok(Some(OidcState {
+            nonce,
+            code_verifier,
+            device,
+        }))
+    }
+
+    async fn claim_totp_step(&self, username: &str, step: u64, ttl_seconds: u64) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let first_use: Option<String> = conn
+            .set_options(
+                format!("totp_step:{username}:{step}"),
+                "1",
+                SetOptions::default().conditional_set(ExistenceCheck::NX).with_expiration(SetExpiry::EX(ttl_seconds)),
+            )
+            .await?;
+        Ok(first_use.is_some())
+    }
+
+    async fn set_totp_enrollment(&self, username: &str, secret: &str, ttl_seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.set_ex(format!("totp_enrollment:{username}"), secret, ttl_seconds).await?)
+    }
+
+    async fn get_totp_enrollment(&self, username: &str) -> StoreResult<Option<String>> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.get(format!("totp_enrollment:{username}")).await?)
+    }
+
+    async fn delete_totp_enrollment(&self, username: &str) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.del(format!("totp_enrollment:{username}")).await?)
+    }
+
+    async fn create_api_token(&self, token_hash: &str, record: &ApiTokenRecord) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let token_key = format!("api_token:{token_hash}");
+        let record_json = serde_json::to_string(record)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().set(&token_key, record_json).hset(
+            format!("api_tokens:{}", record.username),
+            record.token.id.to_string(),
+            token_hash,
+        );
+        if let Some(expires_at) = record.token.expires_at {
+            pipe.expire_at(&token_key, expires_at);
+        }
+        Ok(pipe.exec_async(&mut *conn).await?)
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> StoreResult<Option<ApiTokenRecord>> {
+        let mut conn = self.pool.get().await?;
+        // Expired tokens are removed by Redis
+        get_json(&mut conn, &format!("api_token:{token_hash}")).await
+    }
+
+    async fn list_api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>> {
+        let mut conn = self.pool.get().await?;
+        let api_tokens_key = format!("api_tokens:{username}");
+        let token_hashes: Vec<(String, String)> = conn.hgetall(&api_tokens_key).await?;
+        let mut api_tokens = Vec::with_capacity(token_hashes.len());
+        for (token_id, token_hash) in token_hashes {
+            let record: Option<ApiTokenRecord> = get_json(&mut conn, &format!("api_token:{token_hash}")).await?;
+            if let Some(record) = record {
+                api_tokens.push(record.token);
+            } else {
+                // The token expired, drop it from the index
+                conn.hdel::<_, _, ()>(&api_tokens_key, token_id).await?;
+            }
+        }
+        Ok(api_tokens)
+    }
+
+    async fn revoke_api_token(&self, username: &str, token_id: Uuid) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let api_tokens_key = format!("api_tokens:{username}");
+        let token_hash: Option<String> = conn.hget(&api_tokens_key, token_id.to_string()).await?;
+        let Some(token_hash) = token_hash else {
+            return Ok(false);
+        };
+        let event_json = serde_json::to_string(&SessionEvent::ApiTokenRevoked {
+            token_id,
+        })?;
+        redis::pipe()
+            .atomic()
+            .del(format!("api_token:{token_hash}"))
+            .hdel(&api_tokens_key, token_id.to_string())
+            .publish(format!("session_events:{username}"), event_json)
+            .exec_async(&mut *conn)
+            .await?;
+        Ok(true)
+    }
+
+    async fn add_login_failure(&self, subject: &str, window_seconds: u64) -> StoreResult<usize> {
+        let mut conn = self.pool.get().await?;
+        let now = Utc::now().timestamp_millis();
+        let window_start = now - i64::try_from(window_seconds * 1000).unwrap_or(i64::MAX);
+        let failures_key = format!("login_failures:{subject}");
+        let (failures,): (usize,) = redis::pipe()
+            .atomic()
+            .zrembyscore(&failures_key, "-inf", window_start)
+            .ignore()
+            .zadd(&failures_key, Uuid::new_v4().to_string(), now)
+            .ignore()
+            .zcard(&failures_key)
+            .expire(&failures_key, window_seconds.cast_signed())
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(failures)
+    }
+
+    async fn clear_login_failures(&self, subject: &str) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.del(format!("login_failures:{subject}")).await?)
+    }
+
+    async fn escalate_lockout(&self, subject: &str, level_ttl_seconds: u64) -> StoreResult<u32> {
+        let mut conn = self.pool.get().await?;
+        let level_key = format!("lockout_level:{subject}");
+        let (level,): (u32,) = redis::pipe()
+            .atomic()
+            .incr(&level_key, 1)
+            .expire(&level_key, level_ttl_seconds.cast_signed())
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(level)
+    }
+
+    async fn lock_out(&self, subject: &str, seconds: u64) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        Ok(redis::pipe()
+            .atomic()
+            .set_ex(format!("lockout:{subject}"), "1", seconds)
+            .del(format!("login_failures:{subject}"))
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn lockout_remaining(&self, subjects: &[String]) -> StoreResult<Option<u64>> {
+        let mut conn = self.pool.get().await?;
+        let mut pipe = redis::pipe();
+        for subject in subjects {
+            pipe.ttl(format!("lockout:{subject}"));
+        }
+        let ttls: Vec<i64> = pipe.query_async(&mut *conn).await?;
+        Ok(ttls.into_iter().filter(|ttl| *ttl > 0).max().map(i64::cast_unsigned))
+    }
+
+    async fn append_audit_entry(&self, entry: &AuditEntry, max_entries: usize) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let entry_json = serde_json::to_string(entry)?;
+        Ok(redis::pipe()
+            .atomic()
+            .lpush("audit_log", entry_json)
+            .ltrim("audit_log", 0, max_entries.cast_signed() - 1)
+            .exec_async(&mut *conn)
+            .await?)
+    }
+
+    async fn list_tasks(&self, username: &str) -> StoreResult<Vec<Task>> {
+        let mut conn = self.pool.get().await?;
+        let task_ids_key = format!("task_ids:{username}");
+        let task_ids: Vec<String> = conn.smembers(&task_ids_key).await?;
+        let (tasks, dangling_task_ids) = get_tasks(&mut conn, username, &task_ids).await?;
+        if !dangling_task_ids.is_empty() {
+            conn.srem::<_, _, ()>(&task_ids_key, dangling_task_ids).await?;
+        }
+        Ok(tasks)
+    }
+
+    async fn get_task(&self, username: &str, task_id: Uuid) -> StoreResult<Option<Task>> {
+        let mut conn = self.pool.get().await?;
+        get_task(&mut conn, &format!("task:{username}:{task_id}")).await
+    }
+
+    async fn list_subtasks(&self, username: &str, task_id: Uuid) -> StoreResult<Vec<Task>> {
+        let mut conn = self.pool.get().await?;
+        let subtask_ids_key = format!("subtask_ids:{username}:{task_id}");
+        let subtask_ids: Vec<String> = conn.smembers(&subtask_ids_key).await?;
+        let (subtasks, dangling_subtask_ids) = get_tasks(&mut conn, username, &subtask_ids).await?;
+        if !dangling_subtask_ids.is_empty() {
+            conn.srem::<_, _, ()>(&subtask_ids_key, dangling_subtask_ids).await?;
+        }
+        Ok(subtasks)
+    }
+
+    async fn create_task(&self, username: &str, task: &Task) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        let notification_json = serde_json::to_string(&Notification::TaskCreated {
+            task: task.clone(),
+        })?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(format!("task:{username}:{}", task.id), &task_fields(task))
+            .sadd(format!("task_ids:{username}"), task.id.to_string());
+        if let Some(parent_id) = task.parent_id {
+            pipe.sadd(format!("subtask_ids:{username}:{parent_id}"), task.id.to_string());
+        }
+        add_notification(&mut pipe, username, &notification_json);
+        Ok(pipe.exec_async(&mut *conn).await?)
+    }
+
+    async fn update_task(
+        &self,
+        username: &str,
+        task_id: Uuid,
+        update: &TaskUpdate,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>> {
+        let mut conn = self.pool.get().await?;
+        let task_key = format!("task:{username}:{task_id}");
+        let fields = task_update_fields(update);
+        let cleared_fields = task_cleared_fields(update);
+        // The notification carries the whole task, so the fields that aren't written are WATCHed too
+        for _ in 0..TASK_TRANSACTION_ATTEMPTS {
+            // None if the task was modified meanwhile
+            let updated = watching(&mut conn, &task_key, async |conn| {
+                let Some(mut task) = get_task(conn, &task_key).await? else {
+                    unwatch(conn).await?;
+                    return Ok(Some(None));
+                };
+                task.check_version(expected_version)?;
+                update.apply(&mut task);
+                let notification_json = serde_json::to_string(&Notification::TaskUpdated {
+                    task: task.clone(),
+                })?;
+                let mut pipe = redis::pipe();
+                pipe.atomic().hset(&task_key, "version", task.version).ignore();
+                if update.completed.is_some() {
+                    match task.completed_at {
+                        Some(completed_at) => pipe.hset(&task_key, "completed_at", completed_at).ignore(),
+                        None => pipe.hdel(&task_key, "completed_at").ignore(),
+                    };
+                }
+                if !fields.is_empty() {
+                    pipe.hset_multiple(&task_key, &fields).ignore();
+                }
+                if !cleared_fields.is_empty() {
+                    pipe.hdel(&task_key, &cleared_fields).ignore();
+                }
+                add_notification(&mut pipe, username, &notification_json);
+                let committed: Option<()> = pipe.query_async(conn).await?;
+                Ok(committed.map(|()| Some(task)))
+            })
+            .await?;
+            if let Some(task) = updated {
+                return Ok(task);
+            }
+        }
+        Err(StoreError::Conflict)
+    }
+
+    async fn delete_task(&self, username: &str, task_id: Uuid, expected_version: Option<u64>) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let task_key = format!("task:{username}:{task_id}");
+        for _ in 0..TASK_TRANSACTION_ATTEMPTS {
+            // None if the task was modified meanwhile
+            let deleted = watching(&mut conn, &task_key, async |conn| {
+                let Some(task) = get_task(conn, &task_key).await? else {
+                    unwatch(conn).await?;
+                    return Ok(Some(false));
+                };
+                task.check_version(expected_version)?;
+                let notification_json = serde_json::to_string(&Notification::TaskDeleted {
+                    task_id,
+                    version: task.version,
+                })?;
+                let mut pipe = redis::pipe();
+                pipe.atomic()
+                    .del(&task_key)
+                    .ignore()
+                    .srem(format!("task_ids:{username}"), task_id.to_string())
+                    .ignore();
+                if let Some(parent_id) = task.parent_id {
+                    pipe.srem(format!("subtask_ids:{username}:{parent_id}"), task_id.to_string()).ignore();
+                }
+                add_notification(&mut pipe, username, &notification_json);
+                let committed: Option<()> = pipe.query_async(conn).await?;
+                Ok(committed.map(|()| true))
+            })
+            .await?;
+            if let Some(deleted) = deleted {
+                return Ok(deleted);
+            }
+        }
+        Err(StoreError::Conflict)
+    }
+
+    async fn commit_tasks(&self, username: &str, changes: &TaskChanges) -> StoreResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let (task_keys, expected_versions): (Vec<String>, Vec<Option<u64>>) = changes
+            .expected_versions()
+            .map(|(task_id, version)| (format!("task:{username}:{task_id}"), version))
+            .unzip();
+        watching(&mut conn, &task_keys, async |conn| {
+            let mut pipe = redis::pipe();
+            for task_key in &task_keys {
+                pipe.cmd("HMGET").arg(task_key).arg("id").arg("version").arg("parent_id");
+            }
+            // Tasks written before versions existed have no version field until they're updated
+            let stored: Vec<(Option<String>, Option<u64>, Option<String>)> = pipe.query_async(&mut *conn).await?;
+            let (stored_versions, stored_parent_ids): (Vec<_>, Vec<_>) = stored
+                .into_iter()
+                .map(|(id, version, parent_id)| (id.map(|_| version.unwrap_or_default()), parent_id))
+                .unzip();
+            if stored_versions != expected_versions {
+                unwatch(conn).await?;
+                return Ok(false);
+            }
+            // In the order of expected_versions, saved tasks first
+            let mut stored_parent_ids = stored_parent_ids.into_iter();
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (task, stored_parent_id) in changes.saved.iter().zip(&mut stored_parent_ids) {
+                // Whole tasks are written, so fields that were cleared have to go
+                let task_id = task.id.to_string();
+                let task_key = format!("task:{username}:{task_id}");
+                pipe.del(&task_key)
+                    .ignore()
+                    .hset_multiple(&task_key, &task_fields(task))
+                    .ignore()
+                    .sadd(format!("task_ids:{username}"), &task_id)
+                    .ignore();
+                let parent_id = task.parent_id.map(|parent_id| parent_id.to_string());
+                if let Some(stored_parent_id) = stored_parent_id.filter(|stored| Some(stored) != parent_id.as_ref()) {
+                    pipe.srem(format!("subtask_ids:{username}:{stored_parent_id}"), &task_id).ignore();
+                }
+                if let Some(parent_id) = parent_id {
+                    pipe.sadd(format!("subtask_ids:{username}:{parent_id}"), &task_id).ignore();
+                }
+            }
+            for (task, stored_parent_id) in changes.deleted.iter().zip(stored_parent_ids) {
+                let task_id = task.id.to_string();
+                pipe.del(format!("task:{username}:{task_id}"))
+                    .ignore()
+                    .del(format!("subtask_ids:{username}:{task_id}"))
+                    .ignore()
+                    .srem(format!("task_ids:{username}"), &task_id)
+                    .ignore();
+                if let Some(stored_parent_id) = stored_parent_id {
+                    pipe.srem(format!("subtask_ids:{username}:{stored_parent_id}"), &task_id).ignore();
+                }
+            }
+            for notification in changes.notifications() {
+                add_notification(&mut pipe, username, &serde_json::to_string(&notification)?);
+            }
+            let committed: Option<()> = pipe.query_async(conn).await?;
+            Ok(committed.is_some())
+        })
+        .await
+    }
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream> {
+        self.events.subscribe(username, last_event_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::store::fake_redis::FakeRedis;
+
+    // The transaction's future is dropped right after the WATCH, then the key is written by another client, which must
+    // not abort the next transaction on the connection
+    #[tokio::test]
+    async fn dropped_transactions_leave_no_watched_keys_behind() {
+        let fake_redis = FakeRedis::start().await;
+        let store = RedisStore::connect(fake_redis.url()).await.expect("Fake Redis store");
+        let mut conn = store.pool.get().await.expect("Pooled connection");
+        let (watched, watching_started) = oneshot::channel();
+        let transaction = watching(&mut conn, "key", async |_: &mut MultiplexedConnection| {
+            watched.send(()).ok();
+            future::pending::<StoreResult<()>>().await
+        });
+        tokio::select! {
+            _ = transaction => unreachable!("The transaction never finishes"),
+            _ = watching_started => {}
+        }
+        let mut other_conn = store.events.client.get_multiplexed_async_connection().await.expect("Other connection");
+        let () = other_conn.set("key", "written meanwhile").await.expect("SET");
+        let committed: Option<()> =
+            redis::pipe().atomic().set("other key", "value").ignore().query_async(&mut *conn).await.expect("EXEC");
+        assert!(committed.is_some());
+    }
+}
@@ -0,0 +1,1004 @@
+use std::{
+    env,
+    error::Error,
+    sync::{Arc, Weak},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{
+    Any,
+    AnyPool,
+    Error as SqlError,
+    Executor,
+    Row,
+    any::{self, AnyRow},
+    error::DatabaseError,
+    migrate::Migrator,
+};
+use tokio::time::{self, Duration};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::store::{
+    ApiToken,
+    ApiTokenRecord,
+    ApiTokenScope,
+    AuditEntry,
+    Author,
+    EventId,
+    EventSink,
+    EventStream,
+    JwtStatus,
+    MfaLogin,
+    Notification,
+    OidcState,
+    RefreshToken,
+    Session,
+    SessionEvent,
+    StoreError,
+    StoreResult,
+    Task,
+    TaskStore,
+    TaskUpdate,
+    User,
+    broadcast::Broadcaster,
+    hierarchy::TaskChanges,
+    log_publish_error,
+    redis::RedisEvents,
+};
+
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+const SWEEP_INTERVAL_SECONDS: u64 = 60;
+
+const USER_UPDATE_ATTEMPTS: usize = 5; // Before giving up on a user record that keeps being modified concurrently
+
+const USER_COLUMNS: &str = "username, password_hash, oidc_subject, totp_secret, recovery_code_hashes";
+const TASK_COLUMNS: &str = "id, category, title, text, completed, due, due_tz, all_day, parent_id, version, created_at, \
+                            updated_at, completed_at, created_by, updated_by";
+const SESSION_COLUMNS: &str = "id, device, created_at, refreshed_at";
+const API_TOKEN_COLUMNS: &str = "username, id, name, scope, created_at, expires_at";
+
+// The queries are shared between SQLite and PostgreSQL through sqlx's Any driver, so they stick to the common subset
+// (`$n` parameters, ON CONFLICT, RETURNING) and booleans are stored as 0 or 1.
+pub struct SqlStore {
+    pool: AnyPool,
+    events: Box<dyn EventSink>,
+}
+
+impl From<SqlError> for StoreError {
+    fn from(err: SqlError) -> Self {
+        Self::Backend(Box::new(err))
+    }
+}
+
+impl SqlStore {
+    // DATABASE_URL: "sqlite://<file>" or "postgres://<user>:<password>@<host>/<database>", defaults to
+    //               "sqlite://task-tracker.db?mode=rwc"
+    // REDIS_URL: Optional, events are published through Redis if set so every instance receives them, otherwise they
+    //            only reach this instance's WebSockets
+    pub async fn from_env() -> Result<Arc<Self>, Box<dyn Error>> {
+        let url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://task-tracker.db?mode=rwc".to_owned());
+        let events: Box<dyn EventSink> = match env::var("REDIS_URL") {
+            Ok(redis_url) => Box::new(RedisEvents::connect(&redis_url).await?),
+            Err(_) => Box::new(Broadcaster::default()),
+        };
+        Self::connect(&url, events).await
+    }
+
+    pub async fn connect(url: &str, events: Box<dyn EventSink>) -> Result<Arc<Self>, Box<dyn Error>> {
+        any::install_default_drivers();
+        let migrator = if url.starts_with("sqlite:") {
+            &SQLITE_MIGRATOR
+        } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            &POSTGRES_MIGRATOR
+        } else {
+            return Err("DATABASE_URL must be a sqlite:// or postgres:// URL".into());
+        };
+        let pool = AnyPool::connect(url).await?;
+        migrator.run(&pool).await?;
+        let store = Arc::new(Self {
+            pool,
+            events,
+        });
+        tokio::spawn(sweep(Arc::downgrade(&store)));
+        Ok(store)
+    }
+
+    // Unlike with Redis, events are published after the transaction commits, so a failed publish doesn't undo it
+    async fn publish_notification(&self, username: &str, notification: Notification) {
+        log_publish_error(self.events.publish_notification(username, &notification).await);
+    }
+
+    async fn publish_session_event(&self, username: &str, session_event: SessionEvent) {
+        log_publish_error(self.events.publish_session_event(username, &session_event).await);
+    }
+
+    async fn forget(&self, username: &str) {
+        log_publish_error(self.events.forget(username).await);
+    }
+
+    // After a conditional write matched nothing: fails if that's because the task is at another version
+    async fn version_mismatch<T>(&self, username: &str, task_id: Uuid) -> StoreResult<Option<T>> {
+        let version: Option<i64> = sqlx::query_scalar("SELECT version FROM tasks WHERE username = $1 AND id = $2")
+            .bind(username)
+            .bind(task_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+        version.map_or(Ok(None), |version| Err(StoreError::VersionMismatch(version.cast_unsigned())))
+    }
+
+    async fn delete_expired(&self) -> StoreResult<()> {
+        let now = now();
+        for table in [
+            "sessions",
+            "revoked_jwts",
+            "refresh_tokens",
+            "mfa_logins",
+            "oidc_states",
+            "totp_steps",
+            "totp_enrollments",
+            "lockout_levels",
+            "lockouts",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE expires_at <= $1")).bind(now).execute(&self.pool).await?;
+        }
+        sqlx::query("DELETE FROM api_tokens WHERE expires_at <= $1").bind(now).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+// Stops once the store is dropped
+async fn sweep(store: Weak<SqlStore>) {
+    let mut interval = time::interval(Duration::from_secs(SWEEP_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        if let Err(err) = store.delete_expired().await {
+            error!("Expired records deletion error: {err}");
+        }
+        store.events.prune();
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+fn expires_at(ttl_seconds: u64) -> i64 {
+    now().saturating_add(ttl_seconds.cast_signed())
+}
+
+const fn scope_name(scope: ApiTokenScope) -> &'static str {
+    match scope {
+        ApiTokenScope::ReadOnly => "read_only",
+        ApiTokenScope::ReadWrite => "read_write",
+    }
+}
+
+fn user_from_row(row: &AnyRow) -> StoreResult<User> {
+    Ok(User {
+        username: row.try_get("username")?,
+        password_hash: row.try_get("password_hash")?,
+        oidc_subject: row.try_get("oidc_subject")?,
+        totp_secret: row.try_get("totp_secret")?,
+        recovery_code_hashes: serde_json::from_str(row.try_get("recovery_code_hashes")?)?,
+    })
+}
+
+fn task_from_row(row: &AnyRow) -> StoreResult<Task> {
+    Ok(Task {
+        id: Uuid::parse_str(row.try_get("id")?)?,
+        category: row.try_get("category")?,
+        title: row.try_get("title")?,
+        text: row.try_get("text")?,
+        completed: row.try_get::<i64, _>("completed")? != 0,
+        due: row.try_get("due")?,
+        due_tz: row.try_get("due_tz")?,
+        all_day: row.try_get::<i64, _>("all_day")? != 0,
+        parent_id: row.try_get::<Option<&str>, _>("parent_id")?.map(Uuid::parse_str).transpose()?,
+        version: row.try_get::<i64, _>("version")?.cast_unsigned(),
+        created_at: row.try_get("created_at")?,
+        updated_at: row.try_get("updated_at")?,
+        completed_at: row.try_get("completed_at")?,
+        created_by: author_from_row(row, "created_by")?,
+        updated_by: author_from_row(row, "updated_by")?,
+    })
+}
+
+fn author_from_row(row: &AnyRow, column: &str) -> StoreResult<Option<Author>> {
+    row.try_get::<Option<&str>, _>(column)?
+        .map(str::parse)
+        .transpose()
+        .map_err(|err: String| StoreError::Backend(err.into()))
+}
+
+async fn insert_task<'c>(executor: impl Executor<'c, Database = Any>, username: &str, task: &Task) -> StoreResult<()> {
+    sqlx::query(&format!(
+        "INSERT INTO tasks (username, {TASK_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, \
+         $15, $16)"
+    ))
+    .bind(username)
+    .bind(task.id.to_string())
+    .bind(&task.category)
+    .bind(&task.title)
+    .bind(&task.text)
+    .bind(i64::from(task.completed))
+    .bind(task.due)
+    .bind(&task.due_tz)
+    .bind(i64::from(task.all_day))
+    .bind(task.parent_id.map(|parent_id| parent_id.to_string()))
+    .bind(task.version.cast_signed())
+    .bind(task.created_at)
+    .bind(task.updated_at)
+    .bind(task.completed_at)
+    .bind(task.created_by.map(|author| author.to_string()))
+    .bind(task.updated_by.map(|author| author.to_string()))
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+fn session_from_row(row: &AnyRow) -> StoreResult<Session> {
+    Ok(Session {
+        id: Uuid::parse_str(row.try_get("id")?)?,
+        device: row.try_get("device")?,
+        created_at: row.try_get("created_at")?,
+        refreshed_at: row.try_get("refreshed_at")?,
+    })
+}
+
+fn api_token_record_from_row(row: &AnyRow) -> StoreResult<ApiTokenRecord> {
+    let scope = match row.try_get("scope")? {
+        "read_only" => ApiTokenScope::ReadOnly,
+        "read_write" => ApiTokenScope::ReadWrite,
+        scope => return Err(StoreError::Backend(format!("Unknown API token scope {scope:?}").into())),
+    };
+    Ok(ApiTokenRecord {
+        username: row.try_get("username")?,
+        token: ApiToken {
+            id: Uuid::parse_str(row.try_get("id")?)?,
+            name: row.try_get("name")?,
+            scope,
+            created_at: row.try_get("created_at")?,
+            expires_at: row.try_get("expires_at")?,
+        },
+    })
+}
+
+fn is_unique_violation(err: &SqlError) -> bool {
+    err.as_database_error().is_some_and(DatabaseError::is_unique_violation)
+}
+
+#[async_trait]
+impl TaskStore for SqlStore {
+    async fn get_user(&self, username: &str) -> StoreResult<Option<User>> {
+        sqlx::query(&format!("SELECT {USER_COLUMNS} FROM users WHERE username = $1"))
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(user_from_row)
+            .transpose()
+    }
+
+    async fn create_user(&self, user: &User) -> StoreResult<bool> {
+        // Without a conflict target, DO NOTHING covers both the username and the OIDC subject
+        let result = sqlx::query(&format!(
+            "INSERT INTO users ({USER_COLUMNS}) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING"
+        ))
+        .bind(&user.username)
+        .bind(&user.password_hash)
+        .bind(&user.oidc_subject)
+        .bind(&user.totp_secret)
+        .bind(serde_json::to_string(&user.recovery_code_hashes)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_user(&self, user: &User) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO users ({USER_COLUMNS}) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (username) DO UPDATE SET \
+             password_hash = excluded.password_hash, oidc_subject = excluded.oidc_subject, totp_secret = \
+             excluded.totp_secret, recovery_code_hashes = excluded.recovery_code_hashes"
+        ))
+        .bind(&user.username)
+        .bind(&user.password_hash)
+        .bind(&user.oidc_subject)
+        .bind(&user.totp_secret)
+        .bind(serde_json::to_string(&user.recovery_code_hashes)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn replace_password_hash(
+        &self,
+        username: &str,
+        old_password_hash: Option<&str>,
+        new_password_hash: &str,
+    ) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "UPDATE users SET password_hash = $1 WHERE username = $2 AND password_hash IS NOT DISTINCT FROM $3",
+        )
+        .bind(new_password_hash)
+        .bind(username)
+        .bind(old_password_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // The hashes are a JSON array, so the removal only goes through if nobody changed them since they were read
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> StoreResult<bool> {
+        for _ in 0..USER_UPDATE_ATTEMPTS {
+            let Some(row) = sqlx::query("SELECT recovery_code_hashes FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await?
+            else {
+                return Ok(false);
+            };
+            let old_hashes_json: String = row.try_get("recovery_code_hashes")?;
+            let mut hashes: Vec<String> = serde_json::from_str(&old_hashes_json)?;
+            let Some(index) = hashes.iter().position(|hash| hash == code_hash) else {
+                return Ok(false);
+            };
+            hashes.remove(index);
+            let result = sqlx::query(
+                "UPDATE users SET recovery_code_hashes = $1 WHERE username = $2 AND recovery_code_hashes = $3",
+            )
+            .bind(serde_json::to_string(&hashes)?)
+            .bind(username)
+            .bind(&old_hashes_json)
+            .execute(&self.pool)
+            .await?;
+            if result.rows_affected() > 0 {
+                return Ok(true);
+            }
+        }
+        Err(StoreError::Conflict)
+    }
+
+    async fn rename_user(&self, old_username: &str, user: &User) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for table in ["sessions", "api_tokens", "totp_enrollments"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE username = $1"))
+                .bind(old_username)
+                .execute(&mut *tx)
+                .await?;
+        }
+        // The tasks follow through ON UPDATE CASCADE
+        let result = sqlx::query(
+            "UPDATE users SET username = $1, password_hash = $2, oidc_subject = $3, totp_secret = $4, \
+             recovery_code_hashes = $5 WHERE username = $6",
+        )
+        .bind(&user.username)
+        .bind(&user.password_hash)
+        .bind(&user.oidc_subject)
+        .bind(&user.totp_secret)
+        .bind(serde_json::to_string(&user.recovery_code_hashes)?)
+        .bind(old_username)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| if is_unique_violation(&err) { StoreError::UsernameTaken } else { err.into() })?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError::Conflict);
+        }
+        tx.commit().await?;
+        self.publish_session_event(old_username, SessionEvent::AllSessionsRevoked).await;
+        self.forget(old_username).await;
+        Ok(())
+    }
+
+    async fn delete_user(&self, user: &User) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM totp_enrollments WHERE username = $1").bind(&user.username).execute(&mut *tx).await?;
+        // Subtasks can't outlive the task they're in, not even within a cascade, so the hierarchy goes first
+        sqlx::query("UPDATE tasks SET parent_id = NULL WHERE username = $1 AND parent_id IS NOT NULL")
+            .bind(&user.username)
+            .execute(&mut *tx)
+            .await?;
+        // Tasks, sessions and API tokens go with it through ON DELETE CASCADE
+        sqlx::query("DELETE FROM users WHERE username = $1").bind(&user.username).execute(&mut *tx).await?;
+        tx.commit().await?;
+        self.publish_session_event(&user.username, SessionEvent::AccountDeleted).await;
+        self.forget(&user.username).await;
+        Ok(())
+    }
+
+    async fn oidc_subject_username(&self, subject: &str) -> StoreResult<Option<String>> {
+        Ok(sqlx::query_scalar("SELECT username FROM users WHERE oidc_subject = $1")
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn create_session(&self, username: &str, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO sessions (username, {SESSION_COLUMNS}, expires_at) VALUES ($1, $2, $3, $4, $5, $6)"
+        ))
+        .bind(username)
+        .bind(session.id.to_string())
+        .bind(&session.device)
+        .bind(session.created_at)
+        .bind(session.refreshed_at)
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> StoreResult<Option<Session>> {
+        sqlx::query(&format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE id = $1 AND expires_at > $2"))
+            .bind(session_id.to_string())
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(session_from_row)
+            .transpose()
+    }
+
+    async fn update_session(&self, session: &Session, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query(
+            "UPDATE sessions SET device = $1, created_at = $2, refreshed_at = $3, expires_at = $4 WHERE id = $5",
+        )
+        .bind(&session.device)
+        .bind(session.created_at)
+        .bind(session.refreshed_at)
+        .bind(expires_at(ttl_seconds))
+        .bind(session.id.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, username: &str) -> StoreResult<Vec<Session>> {
+        sqlx::query(&format!(
+            "SELECT {SESSION_COLUMNS} FROM sessions WHERE username = $1 AND expires_at > $2 ORDER BY created_at"
+        ))
+        .bind(username)
+        .bind(now())
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(session_from_row)
+        .collect()
+    }
+
+    async fn revoke_session(&self, username: &str, session_id: Uuid) -> StoreResult<bool> {
+        let result = sqlx::query("DELETE FROM sessions WHERE id = $1 AND username = $2")
+            .bind(session_id.to_string())
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.publish_session_event(username, SessionEvent::SessionRevoked {
+            session_id,
+        })
+        .await;
+        Ok(true)
+    }
+
+    async fn revoke_all_sessions(&self, username: &str) -> StoreResult<()> {
+        let result = sqlx::query("DELETE FROM sessions WHERE username = $1").bind(username).execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Ok(());
+        }
+        self.publish_session_event(username, SessionEvent::AllSessionsRevoked).await;
+        Ok(())
+    }
+
+    async fn jwt_status(&self, jti: Uuid, session_id: Uuid) -> StoreResult<JwtStatus> {
+        let now = now();
+        let revoked = sqlx::query("SELECT 1 FROM revoked_jwts WHERE jti = $1 AND expires_at > $2")
+            .bind(jti.to_string())
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+        if revoked.is_some() {
+            return Ok(JwtStatus::Revoked);
+        }
+        let session = sqlx::query("SELECT 1 FROM sessions WHERE id = $1 AND expires_at > $2")
+            .bind(session_id.to_string())
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(if session.is_some() { JwtStatus::Valid } else { JwtStatus::SessionRevoked })
+    }
+
+    async fn revoke_jwt(&self, jti: Uuid, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO revoked_jwts (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO UPDATE SET expires_at = \
+             excluded.expires_at",
+        )
+        .bind(jti.to_string())
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        username: &str,
+        session_id: Uuid,
+        ttl_seconds: u64,
+    ) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO refresh_tokens (token_hash, username, session_id, rotated, expires_at) VALUES ($1, $2, $3, 0, \
+             $4)",
+        )
+        .bind(token_hash)
+        .bind(username)
+        .bind(session_id.to_string())
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, token_hash: &str) -> StoreResult<Option<RefreshToken>> {
+        let now = now();
+        // Only one concurrent UPDATE can match `rotated = 0`, the others fall through to the SELECT
+        let mut rotated = false;
+        let mut row = sqlx::query(
+            "UPDATE refresh_tokens SET rotated = 1 WHERE token_hash = $1 AND rotated = 0 AND expires_at > $2 \
+             RETURNING username, session_id",
+        )
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        if row.is_none() {
+            rotated = true;
+            row = sqlx::query(
+                "SELECT username, session_id FROM refresh_tokens WHERE token_hash = $1 AND expires_at > $2",
+            )
+            .bind(token_hash)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+        }
+        row.map(|row| {
+            Ok(RefreshToken {
+                username: row.try_get("username")?,
+                session_id: Uuid::parse_str(row.try_get("session_id")?)?,
+                rotated,
+            })
+        })
+        .transpose()
+    }
+
+    async fn create_mfa_login(&self, token_hash: &str, mfa_login: &MfaLogin, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query("INSERT INTO mfa_logins (token_hash, username, device, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(token_hash)
+            .bind(&mfa_login.username)
+            .bind(&mfa_login.device)
+            .bind(expires_at(ttl_seconds))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_mfa_login(&self, token_hash: &str) -> StoreResult<Option<MfaLogin>> {
+        sqlx::query("SELECT username, device FROM mfa_logins WHERE token_hash = $1 AND expires_at > $2")
+            .bind(token_hash)
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| {
+                Ok(MfaLogin {
+                    username: row.try_get("username")?,
+                    device: row.try_get("device")?,
+                })
+            })
+            .transpose()
+    }
+
+    async fn consume_mfa_login(&self, token_hash: &str) -> StoreResult<bool> {
+        let result = sqlx::query("DELETE FROM mfa_logins WHERE token_hash = $1 AND expires_at > $2")
+            .bind(token_hash)
+            .bind(now())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn create_oidc_state(&self, state: &str, oidc_state: &OidcState, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO oidc_states (state, nonce, code_verifier, device, expires_at) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(state)
+        .bind(&oidc_state.nonce)
+        .bind(&oidc_state.code_verifier)
+        .bind(&oidc_state.device)
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn take_oidc_state(&self, state: &str) -> StoreResult<Option<OidcState>> {
+        let row =
+            sqlx::query("DELETE FROM oidc_states WHERE state = $1 RETURNING nonce, code_verifier, device, expires_at")
+                .bind(state)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some(row) =
+            row.filter(|row| row.try_get::<i64, _>("expires_at").is_ok_and(|expires_at| expires_at > now()))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(OidcState {
+            nonce: row.try_get("nonce")?,
+            code_verifier: row.try_get("code_verifier")?,
+            device: row.try_get("device")?,
+        }))
+    }
+
+    async fn claim_totp_step(&self, username: &str, step: u64, ttl_seconds: u64) -> StoreResult<bool> {
+        let step = step.cast_signed();
+        sqlx::query("DELETE FROM totp_steps WHERE username = $1 AND step = $2 AND expires_at <= $3")
+            .bind(username)
+            .bind(step)
+            .bind(now())
+            .execute(&self.pool)
+            .await?;
+        let result = sqlx::query(
+            "INSERT INTO totp_steps (username, step, expires_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(username)
+        .bind(step)
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_totp_enrollment(&self, username: &str, secret: &str, ttl_seconds: u64) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO totp_enrollments (username, secret, expires_at) VALUES ($1, $2, $3) ON CONFLICT (username) DO \
+             UPDATE SET secret = excluded.secret, expires_at = excluded.expires_at",
+        )
+        .bind(username)
+        .bind(secret)
+        .bind(expires_at(ttl_seconds))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_totp_enrollment(&self, username: &str) -> StoreResult<Option<String>> {
+        Ok(sqlx::query_scalar("SELECT secret FROM totp_enrollments WHERE username = $1 AND expires_at > $2")
+            .bind(username)
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn delete_totp_enrollment(&self, username: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM totp_enrollments WHERE username = $1").bind(username).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn create_api_token(&self, token_hash: &str, record: &ApiTokenRecord) -> StoreResult<()> {
+        sqlx::query(&format!(
+            "INSERT INTO api_tokens (token_hash, {API_TOKEN_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        ))
+        .bind(token_hash)
+        .bind(&record.username)
+        .bind(record.token.id.to_string())
+        .bind(&record.token.name)
+        .bind(scope_name(record.token.scope))
+        .bind(record.token.created_at)
+        .bind(record.token.expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> StoreResult<Option<ApiTokenRecord>> {
+        sqlx::query(&format!(
+            "SELECT {API_TOKEN_COLUMNS} FROM api_tokens WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > \
+             $2)"
+        ))
+        .bind(token_hash)
+        .bind(now())
+        .fetch_optional(&self.pool)
+        .await?
+        .as_ref()
+        .map(api_token_record_from_row)
+        .transpose()
+    }
+
+    async fn list_api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>> {
+        sqlx::query(&format!(
+            "SELECT {API_TOKEN_COLUMNS} FROM api_tokens WHERE username = $1 AND (expires_at IS NULL OR expires_at > \
+             $2) ORDER BY created_at"
+        ))
+        .bind(username)
+        .bind(now())
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| Ok(api_token_record_from_row(row)?.token))
+        .collect()
+    }
+
+    async fn revoke_api_token(&self, username: &str, token_id: Uuid) -> StoreResult<bool> {
+        let result = sqlx::query("DELETE FROM api_tokens WHERE username = $1 AND id = $2")
+            .bind(username)
+            .bind(token_id.to_string())
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.publish_session_event(username, SessionEvent::ApiTokenRevoked {
+            token_id,
+        })
+        .await;
+        Ok(true)
+    }
+
+    async fn add_login_failure(&self, subject: &str, window_seconds: u64) -> StoreResult<usize> {
+        let now = Utc::now().timestamp_millis();
+        let window_start = now - i64::try_from(window_seconds * 1000).unwrap_or(i64::MAX);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM login_failures WHERE subject = $1 AND failed_at <= $2")
+            .bind(subject)
+            .bind(window_start)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO login_failures (subject, failed_at) VALUES ($1, $2)")
+            .bind(subject)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        let failures: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_failures WHERE subject = $1")
+            .bind(subject)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(usize::try_from(failures).unwrap_or(usize::MAX))
+    }
+
+    async fn clear_login_failures(&self, subject: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM login_failures WHERE subject = $1").bind(subject).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn escalate_lockout(&self, subject: &str, level_ttl_seconds: u64) -> StoreResult<u32> {
+        // An expired level starts over, as if the sweep had already deleted it
+        let level: i64 = sqlx::query_scalar(
+            "INSERT INTO lockout_levels (subject, level, expires_at) VALUES ($1, 1, $2) ON CONFLICT (subject) DO \
+             UPDATE SET level = CASE WHEN lockout_levels.expires_at > $3 THEN lockout_levels.level + 1 ELSE 1 END, \
+             expires_at = excluded.expires_at RETURNING level",
+        )
+        .bind(subject)
+        .bind(expires_at(level_ttl_seconds))
+        .bind(now())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(u32::try_from(level).unwrap_or(u32::MAX))
+    }
+
+    async fn lock_out(&self, subject: &str, seconds: u64) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO lockouts (subject, expires_at) VALUES ($1, $2) ON CONFLICT (subject) DO UPDATE SET expires_at \
+             = excluded.expires_at",
+        )
+        .bind(subject)
+        .bind(expires_at(seconds))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM login_failures WHERE subject = $1").bind(subject).execute(&mut *tx).await?;
+        Ok(tx.commit().await?)
+    }
+
+    async fn lockout_remaining(&self, subjects: &[String]) -> StoreResult<Option<u64>> {
+        let now = now();
+        let mut remaining = None;
+        for subject in subjects {
+            let expires_at: Option<i64> =
+                sqlx::query_scalar("SELECT expires_at FROM lockouts WHERE subject = $1 AND expires_at > $2")
+                    .bind(subject)
+                    .bind(now)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            remaining = remaining.max(expires_at.map(|expires_at| (expires_at - now).cast_unsigned()));
+        }
+        Ok(remaining)
+    }
+
+    async fn append_audit_entry(&self, entry: &AuditEntry, max_entries: usize) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO audit_log (entry) VALUES ($1)")
+            .bind(serde_json::to_string(entry)?)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM audit_log WHERE id NOT IN (SELECT id FROM audit_log ORDER BY id DESC LIMIT $1)")
+            .bind(i64::try_from(max_entries).unwrap_or(i64::MAX))
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx.commit().await?)
+    }
+
+    async fn list_tasks(&self, username: &str) -> StoreResult<Vec<Task>> {
+        sqlx::query(&format!("SELECT {TASK_COLUMNS} FROM tasks WHERE username = $1"))
+            .bind(username)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(task_from_row)
+            .collect()
+    }
+
+    async fn get_task(&self, username: &str, task_id: Uuid) -> StoreResult<Option<Task>> {
+        sqlx::query(&format!("SELECT {TASK_COLUMNS} FROM tasks WHERE username = $1 AND id = $2"))
+            .bind(username)
+            .bind(task_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(task_from_row)
+            .transpose()
+    }
+
+    async fn list_subtasks(&self, username: &str, task_id: Uuid) -> StoreResult<Vec<Task>> {
+        sqlx::query(&format!("SELECT {TASK_COLUMNS} FROM tasks WHERE username = $1 AND parent_id = $2"))
+            .bind(username)
+            .bind(task_id.to_string())
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(task_from_row)
+            .collect()
+    }
+
+    async fn create_task(&self, username: &str, task: &Task) -> StoreResult<()> {
+        insert_task(&self.pool, username, task).await?;
+        self.publish_notification(username, Notification::TaskCreated {
+            task: task.clone(),
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn update_task(
+        &self,
+        username: &str,
+        task_id: Uuid,
+        update: &TaskUpdate,
+        expected_version: Option<u64>,
+    ) -> StoreResult<Option<Task>> {
+        let task = sqlx::query(&format!(
+            "UPDATE tasks SET category = COALESCE($1, category), title = COALESCE($2, title), text = COALESCE($3, \
+             text), completed = COALESCE($4, completed), due = CASE WHEN $5 = 1 THEN $6 ELSE due END, due_tz = CASE \
+             WHEN $7 = 1 THEN $8 ELSE due_tz END, all_day = COALESCE($9, all_day), version = version + 1, updated_at = \
+             $13, updated_by = $14, completed_at = CASE WHEN $4 IS NULL OR $4 = completed THEN completed_at WHEN $4 = 1 \
+             THEN $13 END WHERE username = $10 AND id = $11 AND ($12 IS NULL OR version = $12) RETURNING {TASK_COLUMNS}"
+        ))
+        .bind(&update.category)
+        .bind(&update.title)
+        .bind(&update.text)
+        .bind(update.completed.map(i64::from))
+        // Nullable columns take a flag whether to write them, so they can be cleared
+        .bind(i64::from(!update.due.is_unchanged()))
+        .bind(update.due.value().copied())
+        .bind(i64::from(!update.due_tz.is_unchanged()))
+        .bind(update.due_tz.value().cloned())
+        .bind(update.all_day.map(i64::from))
+        .bind(username)
+        .bind(task_id.to_string())
+        .bind(expected_version.map(u64::cast_signed))
+        .bind(update.updated_at)
+        .bind(update.updated_by.to_string())
+        .fetch_optional(&self.pool)
+        .await?
+        .as_ref()
+        .map(task_from_row)
+        .transpose()?;
+        if task.is_none() && expected_version.is_some() {
+            return self.version_mismatch(username, task_id).await;
+        }
+        if let Some(task) = &task {
+            self.publish_notification(username, Notification::TaskUpdated {
+                task: task.clone(),
+            })
+            .await;
+        }
+        Ok(task)
+    }
+
+    async fn delete_task(&self, username: &str, task_id: Uuid, expected_version: Option<u64>) -> StoreResult<bool> {
+        let version: Option<i64> = sqlx::query_scalar(
+            "DELETE FROM tasks WHERE username = $1 AND id = $2 AND ($3 IS NULL OR version = $3) RETURNING version",
+        )
+        .bind(username)
+        .bind(task_id.to_string())
+        .bind(expected_version.map(u64::cast_signed))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(version) = version else {
+            if expected_version.is_some() {
+                self.version_mismatch::<()>(username, task_id).await?;
+            }
+            return Ok(false);
+        };
+        self.publish_notification(username, Notification::TaskDeleted {
+            task_id,
+            version: version.cast_unsigned(),
+        })
+        .await;
+        Ok(true)
+    }
+
+    async fn commit_tasks(&self, username: &str, changes: &TaskChanges) -> StoreResult<bool> {
+        let mut tx = self.pool.begin().await?;
+        // Every statement checks the version it expects, the transaction is rolled back when dropped if one doesn't match
+        for task in &changes.saved {
+            if task.version == 0 {
+                insert_task(&mut *tx, username, task).await?;
+                continue;
+            }
+            let result = sqlx::query(
+                "UPDATE tasks SET category = $1, title = $2, text = $3, completed = $4, due = $5, due_tz = $6, all_day = \
+                 $7, parent_id = $8, version = $9, updated_at = $10, completed_at = $11, updated_by = $12 WHERE username \
+                 = $13 AND id = $14 AND version = $15",
+            )
+            .bind(&task.category)
+            .bind(&task.title)
+            .bind(&task.text)
+            .bind(i64::from(task.completed))
+            .bind(task.due)
+            .bind(&task.due_tz)
+            .bind(i64::from(task.all_day))
+            .bind(task.parent_id.map(|parent_id| parent_id.to_string()))
+            .bind(task.version.cast_signed())
+            .bind(task.updated_at)
+            .bind(task.completed_at)
+            .bind(task.updated_by.map(|author| author.to_string()))
+            .bind(username)
+            .bind(task.id.to_string())
+            .bind((task.version - 1).cast_signed())
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Ok(false);
+            }
+        }
+        // Subtasks first, they reference the task they're in
+        for task in changes.deleted.iter().rev() {
+            let result = sqlx::query("DELETE FROM tasks WHERE username = $1 AND id = $2 AND version = $3")
+                .bind(username)
+                .bind(task.id.to_string())
+                .bind(task.version.cast_signed())
+                .execute(&mut *tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Ok(false);
+            }
+        }
+        tx.commit().await?;
+        for notification in changes.notifications() {
+            self.publish_notification(username, notification).await;
+        }
+        Ok(true)
+    }
+
+    async fn subscribe(&self, username: &str, last_event_id: Option<EventId>) -> StoreResult<EventStream> {
+        self.events.subscribe(username, last_event_id).await
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    env,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use chrono::Utc;
+use futures::future;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use uuid::Uuid;
+
+use crate::store::{
+    Author,
+    Nullable,
+    StoreError,
+    Task,
+    TaskStore,
+    TaskUpdate,
+    User,
+    broadcast::Broadcaster,
+    fake_redis::FakeRedis,
+    hierarchy::{self, TaskScope, TaskTree},
+    memory::MemoryStore,
+    redis::RedisStore,
+    sql::SqlStore,
+};
+
+pub const AUTHOR: Author = Author::Session(Uuid::nil());
+
+const CONCURRENT_REQUESTS: usize = 8;
+
+// Every backend the tests can reach: the memory store, SQLite in a temporary file, Redis against a fake server, and
+// PostgreSQL and Redis if TEST_DATABASE_URL and TEST_REDIS_URL point at servers the tests may write to. Names are
+// random, so runs don't clash.
+pub async fn stores() -> Vec<(&'static str, Arc<dyn TaskStore>)> {
+    let sqlite_path = env::temp_dir().join(format!("task-tracker-test-{}.db", Uuid::new_v4()));
+    let sqlite_url = format!("sqlite://{}?mode=rwc", sqlite_path.display());
+    let fake_redis = FakeRedis::start().await;
+    let mut stores: Vec<(&'static str, Arc<dyn TaskStore>)> = vec![
+        ("memory", MemoryStore::new()),
+        ("sqlite", SqlStore::connect(&sqlite_url, Box::new(Broadcaster::default())).await.expect("SQLite store")),
+        ("fake redis", Arc::new(RedisStore::connect(fake_redis.url()).await.expect("Fake Redis store"))),
+    ];
+    if let Ok(database_url) = env::var("TEST_DATABASE_URL") {
+        let store = SqlStore::connect(&database_url, Box::new(Broadcaster::default())).await;
+        stores.push(("postgres", store.expect("TEST_DATABASE_URL store")));
+    }
+    if let Ok(redis_url) = env::var("TEST_REDIS_URL") {
+        stores.push(("redis", Arc::new(RedisStore::connect(&redis_url).await.expect("TEST_REDIS_URL store"))));
+    }
+    stores
+}
+
+// Forwards connections to the server at `url` and counts the round trips made through them, that is how many times a
+// client sent something after it last received something. Returns the URL to connect to instead.
+async fn count_round_trips(url: &str) -> (String, Arc<AtomicUsize>) {
+    let mut url = Url::parse(url).expect("Server URL");
+    let upstream =
+        format!("{}:{}", url.host_str().expect("Server host"), url.port_or_known_default().expect("Server port"));
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Proxy listener");
+    url.set_host(Some("127.0.0.1")).expect("Proxy host");
+    url.set_port(Some(listener.local_addr().expect("Proxy address").port())).expect("Proxy port");
+    let round_trips = Arc::new(AtomicUsize::new(0));
+    let counter = round_trips.clone();
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let server = TcpStream::connect(&upstream).await.expect("Server connection");
+            tokio::spawn(relay(client, server, counter.clone()));
+        }
+    });
+    (url.to_string(), round_trips)
+}
+
+async fn relay(client: TcpStream, server: TcpStream, round_trips: Arc<AtomicUsize>) -> io::Result<()> {
+    let awaiting_reply = AtomicBool::new(false);
+    let ((mut client_read, mut client_write), (mut server_read, mut server_write)) =
+        (client.into_split(), server.into_split());
+    let requests = async {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = client_read.read(&mut buf).await?;
+            if len == 0 {
+                return server_write.shutdown().await;
+            }
+            if !awaiting_reply.swap(true, Ordering::SeqCst) {
+                round_trips.fetch_add(1, Ordering::SeqCst);
+            }
+            server_write.write_all(&buf[..len]).await?;
+        }
+    };
+    let replies = async {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = server_read.read(&mut buf).await?;
+            if len == 0 {
+                return client_write.shutdown().await;
+            }
+            awaiting_reply.store(false, Ordering::SeqCst);
+            client_write.write_all(&buf[..len]).await?;
+        }
+    };
+    tokio::try_join!(requests, replies).map(|_| ())
+}
+
+pub fn user(username: &str) -> User {
+    User {
+        username: username.to_owned(),
+        password_hash: Some(String::new()),
+        oidc_subject: None,
+        totp_secret: None,
+        recovery_code_hashes: Vec::new(),
+    }
+}
+
+fn unique_name(prefix: &str) -> String {
+    format!("{prefix}-{}", Uuid::new_v4().simple())
+}
+
+pub fn task(title: &str, parent_id: Option<Uuid>) -> Task {
+    let now = Utc::now().timestamp();
+    Task {
+        id: Uuid::new_v4(),
+        category: String::new(),
+        title: title.to_owned(),
+        text: String::new(),
+        completed: false,
+        due: None,
+        due_tz: None,
+        all_day: false,
+        parent_id,
+        version: 0,
+        created_at: now,
+        updated_at: now,
+        completed_at: None,
+        created_by: Some(AUTHOR),
+        updated_by: None,
+    }
+}
+
+pub fn retitle(title: &str) -> TaskUpdate {
+    TaskUpdate {
+        category: None,
+        title: Some(title.to_owned()),
+        text: None,
+        completed: None,
+        due: Nullable::Unchanged,
+        due_tz: Nullable::Unchanged,
+        all_day: None,
+        updated_at: Utc::now().timestamp(),
+        updated_by: AUTHOR,
+    }
+}
+
+fn complete() -> TaskUpdate {
+    TaskUpdate {
+        title: None,
+        completed: Some(true),
+        ..retitle("")
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_rotations_rotate_a_refresh_token_once() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        let token_hash = unique_name("token");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        store.create_refresh_token(&token_hash, &username, Uuid::new_v4(), 60).await.expect(backend);
+        let rotations =
+            future::join_all((0..CONCURRENT_REQUESTS).map(|_| store.rotate_refresh_token(&token_hash))).await;
+        let first_rotations = rotations
+            .into_iter()
+            .map(|rotation| rotation.expect(backend).expect(backend))
+            .filter(|token| !token.rotated)
+            .count();
+        assert_eq!(first_rotations, 1, "{backend}");
+    }
+}
+
+// Listing loads the tasks in one query, or one pipeline per TASK_BATCH_SIZE tasks with Redis, so it takes a few round
+// trips (checking out a pooled connection, preparing the query) however many tasks there are. Only the backends behind
+// a network connection are checked: Redis against a fake server, and PostgreSQL and Redis with TEST_DATABASE_URL and
+// TEST_REDIS_URL.
+#[tokio::test(flavor = "multi_thread")]
+async fn listing_tasks_takes_a_few_round_trips_however_many_tasks_there_are() {
+    const TASK_COUNT: usize = 100;
+    let fake_redis = FakeRedis::start().await;
+    let (fake_redis_url, round_trips) = count_round_trips(fake_redis.url()).await;
+    let store = RedisStore::connect(&fake_redis_url).await.expect("Fake Redis store");
+    let mut stores: Vec<(&'static str, Arc<dyn TaskStore>, Arc<AtomicUsize>)> =
+        vec![("fake redis", Arc::new(store), round_trips)];
+    if let Ok(database_url) = env::var("TEST_DATABASE_URL") {
+        let (database_url, round_trips) = count_round_trips(&database_url).await;
+        let store = SqlStore::connect(&database_url, Box::new(Broadcaster::default())).await;
+        stores.push(("postgres", store.expect("TEST_DATABASE_URL store"), round_trips));
+    }
+    if let Ok(redis_url) = env::var("TEST_REDIS_URL") {
+        let (redis_url, round_trips) = count_round_trips(&redis_url).await;
+        let store = RedisStore::connect(&redis_url).await.expect("TEST_REDIS_URL store");
+        stores.push(("redis", Arc::new(store), round_trips));
+    }
+    for (backend, store, round_trips) in stores {
+        let username = unique_name("alice");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        for i in 0..TASK_COUNT {
+            store.create_task(&username, &task(&format!("Task {i}"), None)).await.expect(backend);
+        }
+        let before = round_trips.load(Ordering::SeqCst);
+        let tasks = store.list_tasks(&username).await.expect(backend);
+        let listing_round_trips = round_trips.load(Ordering::SeqCst) - before;
+        assert_eq!(tasks.len(), TASK_COUNT, "{backend}");
+        assert!(listing_round_trips < TASK_COUNT / 10, "{backend} took {listing_round_trips} round trips");
+    }
+}
+
+#[tokio::test]
+async fn deleting_a_user_deletes_their_subtasks() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        let user = user(&username);
+        assert!(store.create_user(&user).await.expect(backend));
+        let parent = task("Parent", None);
+        let subtask = task("Subtask", Some(parent.id));
+        hierarchy::change_tasks(&*store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+            tree.create(parent.clone())?;
+            tree.create(subtask.clone())
+        })
+        .await
+        .expect(backend);
+        store.delete_user(&user).await.expect(backend);
+        assert!(store.list_tasks(&username).await.expect(backend).is_empty(), "{backend}");
+    }
+}
+
+#[tokio::test]
+async fn subtasks_follow_the_task_they_are_moved_to_and_renamed_users() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        let (parent, other_parent) = (task("Parent", None), task("Other parent", None));
+        let subtask = task("Subtask", Some(parent.id));
+        hierarchy::change_tasks(&*store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+            tree.create(parent.clone())?;
+            tree.create(other_parent.clone())?;
+            tree.create(subtask.clone())
+        })
+        .await
+        .expect(backend);
+        let subtask_ids = async |username: &str, task_id| {
+            let subtasks = store.list_subtasks(username, task_id).await.expect(backend);
+            subtasks.into_iter().map(|task| task.id).collect::<Vec<_>>()
+        };
+        assert_eq!(subtask_ids(&username, parent.id).await, [subtask.id], "{backend}");
+        let scope = TaskScope::around([subtask.id, other_parent.id]);
+        hierarchy::change_tasks(&*store, &username, &scope, subtask.created_at, AUTHOR, |tree| {
+            tree.move_task(subtask.id, Some(other_parent.id), None)
+        })
+        .await
+        .expect(backend);
+        assert!(subtask_ids(&username, parent.id).await.is_empty(), "{backend}");
+        assert_eq!(subtask_ids(&username, other_parent.id).await, [subtask.id], "{backend}");
+        let new_username = unique_name("bob");
+        store.rename_user(&username, &user(&new_username)).await.expect(backend);
+        assert_eq!(subtask_ids(&new_username, other_parent.id).await, [subtask.id], "{backend}");
+    }
+}
+
+#[tokio::test]
+async fn deleting_a_subtree_loads_only_the_tasks_around_it() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        let parent = task("Parent", None);
+        let subtask = task("Subtask", Some(parent.id));
+        let nested_subtask = task("Nested subtask", Some(subtask.id));
+        let sibling = Task {
+            completed: true,
+            ..task("Sibling", Some(parent.id))
+        };
+        hierarchy::change_tasks(&*store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+            tree.create(parent.clone())?;
+            tree.create(subtask.clone())?;
+            tree.create(sibling.clone())?;
+            tree.create(nested_subtask.clone())
+        })
+        .await
+        .expect(backend);
+        // The subtask still has one, so it's only deleted with its subtree
+        let scope = TaskScope::around([subtask.id]);
+        let deleted = hierarchy::change_tasks(&*store, &username, &scope, subtask.created_at, AUTHOR, |tree| {
+            tree.delete(subtask.id, None, false)
+        })
+        .await;
+        assert!(matches!(deleted, Err(StoreError::HasSubtasks)), "{backend}");
+        let scope = scope.with_subtrees();
+        let deleted = hierarchy::change_tasks(&*store, &username, &scope, subtask.created_at, AUTHOR, |tree| {
+            tree.delete(subtask.id, None, true)
+        })
+        .await;
+        assert!(deleted.expect(backend), "{backend}");
+        let mut task_ids: Vec<_> =
+            store.list_tasks(&username).await.expect(backend).iter().map(|task| task.id).collect();
+        task_ids.sort_unstable();
+        let mut expected_task_ids = [parent.id, sibling.id];
+        expected_task_ids.sort_unstable();
+        assert_eq!(task_ids, expected_task_ids, "{backend}");
+        // Only the completed sibling is left in the parent, which completes it
+        let parent = store.get_task(&username, parent.id).await.expect(backend).expect(backend);
+        assert!(parent.completed, "{backend}");
+    }
+}
+
+#[tokio::test]
+async fn deleting_a_task_on_its_own_checks_its_version() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        let task = task("Task", None);
+        store.create_task(&username, &task).await.expect(backend);
+        let updated = store.update_task(&username, task.id, &retitle("Retitled"), None).await.expect(backend);
+        let version = updated.expect(backend).version;
+        let deleted = store.delete_task(&username, task.id, Some(task.version)).await;
+        assert!(matches!(deleted, Err(StoreError::VersionMismatch(current)) if current == version), "{backend}");
+        assert!(store.delete_task(&username, task.id, Some(version)).await.expect(backend), "{backend}");
+        assert!(!store.delete_task(&username, task.id, None).await.expect(backend), "{backend}");
+        assert!(store.list_tasks(&username).await.expect(backend).is_empty(), "{backend}");
+    }
+}
+
+// Each completion sees the other subtask still open, so the parent only ends up completed if the second one to commit
+// conflicts on it and is worked out again
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrently_completing_the_last_subtasks_completes_their_parent() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        assert!(store.create_user(&user(&username)).await.expect(backend));
+        let parent = task("Parent", None);
+        let subtasks = [task("First", Some(parent.id)), task("Second", Some(parent.id))];
+        hierarchy::change_tasks(&*store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+            tree.create(parent.clone())?;
+            subtasks.iter().try_for_each(|subtask| tree.create(subtask.clone()))
+        })
+        .await
+        .expect(backend);
+        let snapshot = store.list_tasks(&username).await.expect(backend);
+        let [first, second] = subtasks.each_ref().map(|subtask| {
+            let mut tree = TaskTree::new(snapshot.clone(), subtask.created_at, AUTHOR);
+            tree.update(subtask.id, &complete(), None).expect(backend);
+            tree.into_changes()
+        });
+        assert!(store.commit_tasks(&username, &first).await.expect(backend), "{backend}");
+        assert!(!store.commit_tasks(&username, &second).await.expect(backend), "{backend}");
+
+        let parent = task("Parent", None);
+        let subtasks = [task("First", Some(parent.id)), task("Second", Some(parent.id))];
+        hierarchy::change_tasks(&*store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+            tree.create(parent.clone())?;
+            subtasks.iter().try_for_each(|subtask| tree.create(subtask.clone()))
+        })
+        .await
+        .expect(backend);
+        let completions = subtasks.iter().map(|subtask| {
+            let scope = TaskScope::around([subtask.id]).with_subtrees();
+            let store = store.clone();
+            let username = username.clone();
+            let subtask_id = subtask.id;
+            tokio::spawn(async move {
+                hierarchy::change_tasks(&*store, &username, &scope, Utc::now().timestamp(), AUTHOR, |tree| {
+                    tree.update(subtask_id, &complete(), None)
+                })
+                .await
+            })
+        });
+        for completion in future::join_all(completions).await {
+            completion.expect(backend).expect(backend);
+        }
+        let parent = store.get_task(&username, parent.id).await.expect(backend).expect(backend);
+        assert!(parent.completed, "{backend}");
+    }
+}
+
+// A Redis transaction that's aborted by a rejected command, or discarded because a watched key was written meanwhile,
+// must leave the user's keys as they were and publish nothing
+#[tokio::test]
+async fn failed_redis_transactions_write_and_publish_nothing() {
+    let fake_redis = FakeRedis::start().await;
+    let store = RedisStore::connect(fake_redis.url()).await.expect("Fake Redis store");
+    let username = unique_name("alice");
+    assert!(store.create_user(&user(&username)).await.expect("User"));
+    let parent = task("Parent", None);
+    hierarchy::change_tasks(&store, &username, &TaskScope::around([]), parent.created_at, AUTHOR, |tree| {
+        tree.create(parent.clone())?;
+        tree.create(task("Subtask", Some(parent.id)))
+    })
+    .await
+    .expect("Tasks");
+    let (keys, published) = (fake_redis.snapshot(), fake_redis.published());
+
+    fake_redis.fail_next("XADD");
+    let scope = TaskScope::around([parent.id]).with_subtrees();
+    let completed = hierarchy::change_tasks(&store, &username, &scope, Utc::now().timestamp(), AUTHOR, |tree| {
+        tree.update(parent.id, &complete(), None)
+    });
+    assert!(completed.await.is_err());
+    fake_redis.fail_next("SADD");
+    assert!(store.create_task(&username, &task("Another subtask", Some(parent.id))).await.is_err());
+    fake_redis.fail_next("RENAME");
+    assert!(store.rename_user(&username, &user(&unique_name("bob"))).await.is_err());
+    fake_redis.conflict_next(&format!("user:{username}"));
+    assert!(matches!(store.delete_user(&user(&username)).await, Err(StoreError::Conflict)));
+    assert_eq!(fake_redis.snapshot(), keys);
+    assert_eq!(fake_redis.published(), published);
+}
+
+#[tokio::test]
+async fn password_hashes_are_only_replaced_over_the_expected_one() {
+    for (backend, store) in stores().await {
+        let username = unique_name("alice");
+        let oidc_user = User {
+            password_hash: None,
+            ..user(&username)
+        };
+        assert!(store.create_user(&oidc_user).await.expect(backend));
+        assert!(!store.replace_password_hash(&username, Some(""), "first").await.expect(backend), "{backend}");
+        assert!(store.replace_password_hash(&username, None, "first").await.expect(backend), "{backend}");
+        assert!(!store.replace_password_hash(&username, None, "second").await.expect(backend), "{backend}");
+        assert!(store.replace_password_hash(&username, Some("first"), "second").await.expect(backend), "{backend}");
+        let user = store.get_user(&username).await.expect(backend).expect(backend);
+        assert_eq!(user.password_hash.as_deref(), Some("second"), "{backend}");
+    }
+}
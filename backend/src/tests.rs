@@ -0,0 +1,126 @@
+use axum::{
+    body::{self, Body},
+    extract::connect_info::MockConnectInfo,
+    http::Request,
+};
+use futures::future;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+use tower::ServiceExt;
+
+use super::*;
+use crate::store::tests::stores;
+
+const CONCURRENT_REQUESTS: usize = 8;
+const PASSWORD: &str = "Tr0ub4dor&3xyz!";
+
+// The settings not read from the environment are the defaults, JWTs are signed with a fixed secret and every request
+// comes from localhost
+fn app(store: Arc<dyn TaskStore>) -> Router {
+    let router = routes(true).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+    router.with_state(AppState {
+        store,
+        jwt_keys: Arc::new(JwtKeys::hs256("test-secret")),
+        credential_policy: Arc::new(CredentialPolicy::from_env().expect("Credential policy")),
+        password_hashing: Arc::new(PasswordHashing::from_env().expect("Password hashing")),
+        login_throttle: Arc::new(LoginThrottle::from_env().expect("Login throttle")),
+        totp_settings: Arc::new(TotpSettings::from_env().expect("TOTP settings")),
+        oidc_provider: None,
+        trust_forwarded_for: false,
+    })
+}
+
+fn post_json(uri: &str, body: &serde_json::Value) -> Request<Body> {
+    Request::post(uri).header(CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).expect("Request")
+}
+
+fn authorized(mut request: Request<Body>, token: &str) -> Request<Body> {
+    request.headers_mut().insert(AUTHORIZATION, format!("Bearer {token}").parse().expect("Authorization header"));
+    request
+}
+
+// Returns the status and the body, parsed as JSON if it is JSON
+async fn send(app: &Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+    let response = app.clone().oneshot(request).await.expect("Response");
+    let status = response.status();
+    let body = body::to_bytes(response.into_body(), usize::MAX).await.expect("Response body");
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+// Registers a user with a random name and PASSWORD, returns the JWT
+async fn register(app: &Router) -> String {
+    let registration = serde_json::json!({
+        "username": format!("alice{:08x}", rand::random::<u32>()),
+        "password": PASSWORD,
+    });
+    let (status, body) = send(app, post_json("/auth/register", &registration)).await;
+    assert_eq!(status, StatusCode::OK);
+    body["jwt"].as_str().expect("JWT").to_owned()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_registrations_of_a_username_succeed_once() {
+    for (backend, store) in stores().await {
+        let app = app(store);
+        let username = format!("alice{:08x}", rand::random::<u32>());
+        let registration = serde_json::json!({ "username": username, "password": PASSWORD });
+        let responses = future::join_all(
+            (0..CONCURRENT_REQUESTS).map(|_| app.clone().oneshot(post_json("/auth/register", &registration))),
+        )
+        .await;
+        let mut statuses: Vec<_> = responses.into_iter().map(|response| response.expect(backend).status()).collect();
+        statuses.sort_unstable();
+        let mut expected = vec![StatusCode::CONFLICT; CONCURRENT_REQUESTS - 1];
+        expected.insert(0, StatusCode::OK);
+        assert_eq!(statuses, expected, "{backend}");
+    }
+}
+
+#[tokio::test]
+async fn changing_the_password_revokes_api_tokens() {
+    for (backend, store) in stores().await {
+        let app = app(store);
+        let jwt = register(&app).await;
+        let token_request = serde_json::json!({ "name": "CI", "scope": "read_only", "expires_at": null });
+        let (status, api_token) = send(&app, authorized(post_json("/auth/api-tokens", &token_request), &jwt)).await;
+        assert_eq!(status, StatusCode::CREATED, "{backend}");
+        let api_token = api_token["token"].as_str().expect(backend);
+        let list_tasks = || authorized(Request::get("/task").body(Body::empty()).expect("Request"), api_token);
+        assert_eq!(send(&app, list_tasks()).await.0, StatusCode::OK, "{backend}");
+
+        let change = serde_json::json!({ "old_password": PASSWORD, "new_password": "C0rrect-H0rse-Battery" });
+        let (status, tokens) = send(&app, authorized(post_json("/auth/password", &change), &jwt)).await;
+        assert_eq!(status, StatusCode::OK, "{backend}");
+        assert_eq!(send(&app, list_tasks()).await.0, StatusCode::UNAUTHORIZED, "{backend}");
+        let jwt = tokens["jwt"].as_str().expect(backend);
+        let list_api_tokens = Request::get("/auth/api-tokens").body(Body::empty()).expect("Request");
+        assert_eq!(send(&app, authorized(list_api_tokens, jwt)).await.1, serde_json::json!([]), "{backend}");
+    }
+}
+
+#[tokio::test]
+async fn invalid_websocket_messages_are_answered_without_closing_the_socket() {
+    for (backend, store) in stores().await {
+        let app = app(store);
+        let jwt = register(&app).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Listener");
+        let address = listener.local_addr().expect("Listener address");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut request = format!("ws://{address}/websocket").into_client_request().expect("WebSocket request");
+        request.headers_mut().insert(AUTHORIZATION, format!("Bearer {jwt}").parse().expect("Authorization header"));
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.expect("WebSocket");
+        let (mut outgoing, mut incoming) = socket.split();
+        let mut receive = async || {
+            let message = incoming.next().await.expect("Open socket").expect("WebSocket message");
+            serde_json::from_str::<serde_json::Value>(message.to_text().expect("Text message")).expect("JSON message")
+        };
+
+        outgoing.send(tungstenite::Message::text("{\"type\": \"unknown\"}")).await.expect("Sent message");
+        let reply = receive().await;
+        assert_eq!(reply["type"], "error", "{backend}");
+        assert!(reply["message"].as_str().is_some_and(|message| message.starts_with("Invalid message")), "{backend}");
+        let refresh = serde_json::json!({ "type": "refresh_jwt", "jwt": jwt });
+        outgoing.send(tungstenite::Message::text(refresh.to_string())).await.expect("Sent message");
+        assert_eq!(receive().await["type"], "jwt_refreshed", "{backend}");
+    }
+}
@@ -0,0 +1,85 @@
+use std::{error::Error, fmt, net::IpAddr};
+
+use crate::{
+    config::env_parse,
+    store::{StoreResult, TaskStore},
+};
+
+const LOCKOUT_LEVEL_TTL_SECONDS: u64 = 24 * 60 * 60; // Lockouts escalate if they happen again within a day
+
+#[derive(Clone, Copy)]
+pub enum Subject<'a> {
+    Account(&'a str),
+    Ip(IpAddr),
+}
+
+pub struct LoginThrottle {
+    window_seconds: u64,
+    max_account_failures: usize,
+    max_ip_failures: usize,
+    lockout_seconds: u64,
+    max_lockout_seconds: u64,
+}
+
+impl fmt::Display for Subject<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Account(username) => write!(f, "account:{username}"),
+            Self::Ip(ip) => write!(f, "ip:{ip}"),
+        }
+    }
+}
+
+impl LoginThrottle {
+    // LOGIN_WINDOW_SECONDS: Sliding window failed attempts are counted in, defaults to 900
+    // LOGIN_MAX_ACCOUNT_FAILURES: Failed attempts per username within the window, defaults to 5
+    // LOGIN_MAX_IP_FAILURES: Failed attempts per client IP within the window, defaults to 20
+    // LOGIN_LOCKOUT_SECONDS: First lockout duration, doubled on every repeated lockout, defaults to 60
+    // LOGIN_MAX_LOCKOUT_SECONDS: Defaults to 3600
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            window_seconds: env_parse("LOGIN_WINDOW_SECONDS", 900)?,
+            max_account_failures: env_parse("LOGIN_MAX_ACCOUNT_FAILURES", 5)?,
+            max_ip_failures: env_parse("LOGIN_MAX_IP_FAILURES", 20)?,
+            lockout_seconds: env_parse("LOGIN_LOCKOUT_SECONDS", 60)?,
+            max_lockout_seconds: env_parse("LOGIN_MAX_LOCKOUT_SECONDS", 3600)?,
+        })
+    }
+
+    // Returns the remaining lockout in seconds of the most locked out subject
+    pub async fn locked_out(&self, store: &dyn TaskStore, subjects: &[Subject<'_>]) -> StoreResult<Option<u64>> {
+        let subjects: Vec<_> = subjects.iter().map(ToString::to_string).collect();
+        store.lockout_remaining(&subjects).await
+    }
+
+    // Returns the subjects this failure locked out, together with the lockout duration in seconds
+    pub async fn record_failure<'a>(
+        &self,
+        store: &dyn TaskStore,
+        subjects: &[Subject<'a>],
+    ) -> StoreResult<Vec<(Subject<'a>, u64)>> {
+        let mut lockouts = Vec::new();
+        for subject in subjects {
+            let failures = store.add_login_failure(&subject.to_string(), self.window_seconds).await?;
+            let max_failures = match subject {
+                Subject::Account(_) => self.max_account_failures,
+                Subject::Ip(_) => self.max_ip_failures,
+            };
+            if failures < max_failures {
+                continue;
+            }
+            let level = store.escalate_lockout(&subject.to_string(), LOCKOUT_LEVEL_TTL_SECONDS).await?;
+            let seconds = self
+                .lockout_seconds
+                .saturating_mul(2_u64.saturating_pow(level.saturating_sub(1)))
+                .min(self.max_lockout_seconds);
+            store.lock_out(&subject.to_string(), seconds).await?;
+            lockouts.push((*subject, seconds));
+        }
+        Ok(lockouts)
+    }
+
+    pub async fn record_success(&self, store: &dyn TaskStore, username: &str) -> StoreResult<()> {
+        store.clear_login_failures(&Subject::Account(username).to_string()).await
+    }
+}
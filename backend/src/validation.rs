@@ -0,0 +1,177 @@
+use std::{collections::HashSet, env, error::Error, fs};
+
+use serde::Serialize;
+
+use crate::{config::env_parse, store::Task};
+
+#[derive(Serialize)]
+pub struct ValidationFailure {
+    field: &'static str,
+    rule: &'static str,
+    message: String,
+}
+
+const MAX_DUE: i64 = 253_402_300_799; // 9999-12-31T23:59:59Z, the last instant RFC 3339 can express
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+const MAX_TIME_ZONE_LENGTH: usize = 64;
+
+// Usernames end up in Redis keys such as `user:{username}` and `task:{username}:{id}`, so `:` is never allowed,
+// regardless of USERNAME_EXTRA_CHARS.
+const FORBIDDEN_USERNAME_CHARS: &[char] = &[':'];
+
+pub struct CredentialPolicy {
+    username_min_length: usize,
+    username_max_length: usize,
+    username_extra_chars: String,
+    password_min_length: usize,
+    password_max_length: usize,
+    breached_passwords: HashSet<String>,
+}
+
+impl CredentialPolicy {
+    // USERNAME_MIN_LENGTH: Defaults to 3
+    // USERNAME_MAX_LENGTH: Defaults to 32
+    // USERNAME_EXTRA_CHARS: Characters allowed on top of ASCII letters and digits, defaults to "_-."
+    // PASSWORD_MIN_LENGTH: Defaults to 8
+    // PASSWORD_MAX_LENGTH: In bytes, defaults to 72 (bcrypt, which older password hashes use, ignores everything after that)
+    // PASSWORD_BREACHED_LIST_FILE: Optional file with one known breached password per line
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let breached_passwords = match env::var("PASSWORD_BREACHED_LIST_FILE") {
+            Ok(path) => fs::read_to_string(&path)
+                .map_err(|err| format!("Failed to read {path}: {err}"))?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
+        let policy = Self {
+            username_min_length: env_parse("USERNAME_MIN_LENGTH", 3)?,
+            username_max_length: env_parse("USERNAME_MAX_LENGTH", 32)?,
+            username_extra_chars: env::var("USERNAME_EXTRA_CHARS").unwrap_or_else(|_| "_-.".to_owned()),
+            password_min_length: env_parse("PASSWORD_MIN_LENGTH", 8)?,
+            password_max_length: env_parse("PASSWORD_MAX_LENGTH", 72)?,
+            breached_passwords,
+        };
+        if policy.username_min_length == 0 || policy.username_min_length > policy.username_max_length {
+            return Err("USERNAME_MIN_LENGTH must be between 1 and USERNAME_MAX_LENGTH".into());
+        }
+        if policy.password_min_length > policy.password_max_length {
+            return Err("PASSWORD_MIN_LENGTH must not be greater than PASSWORD_MAX_LENGTH".into());
+        }
+        Ok(policy)
+    }
+
+    pub fn validate_username(&self, username: &str, failures: &mut Vec<ValidationFailure>) {
+        let length = username.chars().count();
+        if length < self.username_min_length {
+            failures.push(ValidationFailure {
+                field: "username",
+                rule: "min_length",
+                message: format!("Username must be at least {} characters long", self.username_min_length),
+            });
+        }
+        if length > self.username_max_length {
+            failures.push(ValidationFailure {
+                field: "username",
+                rule: "max_length",
+                message: format!("Username must be at most {} characters long", self.username_max_length),
+            });
+        }
+        if username.chars().any(|char| {
+            FORBIDDEN_USERNAME_CHARS.contains(&char)
+                || !(char.is_ascii_alphanumeric() || self.username_extra_chars.contains(char))
+        }) {
+            failures.push(ValidationFailure {
+                field: "username",
+                rule: "charset",
+                message: format!(
+                    "Username may only contain ASCII letters, digits and {:?}",
+                    self.username_extra_chars.replace(FORBIDDEN_USERNAME_CHARS, "")
+                ),
+            });
+        }
+    }
+
+    pub fn validate_password(&self, password: &str, username: &str, failures: &mut Vec<ValidationFailure>) {
+        if password.chars().count() < self.password_min_length {
+            failures.push(ValidationFailure {
+                field: "password",
+                rule: "min_length",
+                message: format!("Password must be at least {} characters long", self.password_min_length),
+            });
+        }
+        if password.len() > self.password_max_length {
+            failures.push(ValidationFailure {
+                field: "password",
+                rule: "max_length",
+                message: format!("Password must be at most {} bytes long", self.password_max_length),
+            });
+        }
+        if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+            failures.push(ValidationFailure {
+                field: "password",
+                rule: "contains_username",
+                message: "Password must not contain the username".to_owned(),
+            });
+        }
+        if self.breached_passwords.contains(password) {
+            failures.push(ValidationFailure {
+                field: "password",
+                rule: "breached",
+                message: "Password appears in a list of breached passwords".to_owned(),
+            });
+        }
+    }
+}
+
+// Checks the deadline fields of a task as a whole, so it's given the task as it would be stored
+pub fn validate_deadline(task: &Task, failures: &mut Vec<ValidationFailure>) {
+    if task.due.is_some_and(|due| !(0..=MAX_DUE).contains(&due)) {
+        failures.push(ValidationFailure {
+            field: "due",
+            rule: "range",
+            message: "Due date must be between 1970-01-01 and 9999-12-31".to_owned(),
+        });
+    }
+    if task.all_day {
+        match task.due {
+            None => failures.push(ValidationFailure {
+                field: "all_day",
+                rule: "requires_due",
+                message: "All-day deadlines need a due date".to_owned(),
+            }),
+            Some(due) if due % SECONDS_PER_DAY != 0 => failures.push(ValidationFailure {
+                field: "due",
+                rule: "all_day_midnight",
+                message: "All-day deadlines must be due at midnight UTC of their day".to_owned(),
+            }),
+            Some(_) => {}
+        }
+    }
+    if let Some(due_tz) = &task.due_tz {
+        if task.due.is_none() {
+            failures.push(ValidationFailure {
+                field: "due_tz",
+                rule: "requires_due",
+                message: "A deadline time zone needs a due date".to_owned(),
+            });
+        }
+        // Only the shape of the name is checked, the time zone database lives with the clients
+        if due_tz.is_empty()
+            || due_tz.len() > MAX_TIME_ZONE_LENGTH
+            || due_tz.starts_with('/')
+            || due_tz.ends_with('/')
+            || due_tz.contains("//")
+            || !due_tz.chars().all(|char| char.is_ascii_alphanumeric() || "/_+-".contains(char))
+        {
+            failures.push(ValidationFailure {
+                field: "due_tz",
+                rule: "time_zone",
+                message: "Deadline time zone must be an IANA time zone name such as \"Europe/Prague\"".to_owned(),
+            });
+        }
+    }
+}
@@ -1,12 +1,18 @@
 const API_BASE = import.meta.env.VITE_BACKEND_URL || 'http://localhost:6767';
 
 export const authApi = {
+  oidcLoginUrl: `${API_BASE}/auth/oidc/login`,
+
   async register(username, password) {
     const res = await fetch(`${API_BASE}/auth/register`, {
       method: 'POST',
       headers: { 'Content-Type': 'application/json' },
       body: JSON.stringify({ username, password })
     });
+    if (res.status === 422) {
+      const { errors } = await res.json();
+      throw new Error(errors.map((error) => error.message).join('\n'));
+    }
     if (!res.ok) {
       const errorText = await res.text();
       throw new Error(errorText || 'Registration failed');
@@ -27,11 +33,24 @@ export const authApi = {
     return res.json();
   },
 
-  async refreshJWT(jwt) {
-    const res = await fetch(`${API_BASE}/auth/login`, {
+  async loginMfa(mfaToken, code) {
+    const res = await fetch(`${API_BASE}/auth/login/mfa`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ mfa_token: mfaToken, code })
+    });
+    if (!res.ok) {
+      const errorText = await res.text();
+      throw new Error(errorText || 'Login failed');
+    }
+    return res.json();
+  },
+
+  async refresh(refreshToken) {
+    const res = await fetch(`${API_BASE}/auth/refresh`, {
       method: 'POST',
       headers: { 'Content-Type': 'application/json' },
-      body: JSON.stringify({ jwt })
+      body: JSON.stringify({ refresh_token: refreshToken })
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -43,8 +62,7 @@ export const authApi = {
   async logout(jwt) {
     const res = await fetch(`${API_BASE}/auth/logout`, {
       method: 'POST',
-      headers: { 'Content-Type': 'application/json' },
-      body: JSON.stringify({ jwt })
+      headers: { 'Authorization': `Bearer ${jwt}` }
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -1,10 +1,15 @@
 const API_BASE = import.meta.env.VITE_BACKEND_URL || 'http://localhost:6767';
 
+const authHeaders = (jwt) => ({
+  'Content-Type': 'application/json',
+  'Authorization': `Bearer ${jwt}`
+});
+
 export const taskApi = {
   async getAllTasks(jwt) {
-    const res = await fetch(`${API_BASE}/task?jwt=${encodeURIComponent(jwt)}`, {
+    const res = await fetch(`${API_BASE}/task`, {
       method: 'GET',
-      headers: { 'Content-Type': 'application/json' },
+      headers: authHeaders(jwt),
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -16,8 +21,8 @@ export const taskApi = {
   async createTask(jwt, { category, title, text, completed = false, due = null }) {
     const res = await fetch(`${API_BASE}/task`, {
       method: 'POST',
-      headers: { 'Content-Type': 'application/json' },
-      body: JSON.stringify({ jwt, category, title, text, completed, due })
+      headers: authHeaders(jwt),
+      body: JSON.stringify({ category, title, text, completed, due })
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -28,9 +33,9 @@ export const taskApi = {
 
   async updateTask(jwt, id, updates) {
     const res = await fetch(`${API_BASE}/task/${id}`, {
-      method: 'POST',
-      headers: { 'Content-Type': 'application/json' },
-      body: JSON.stringify({ jwt, ...updates })
+      method: 'PATCH',
+      headers: authHeaders(jwt),
+      body: JSON.stringify(updates)
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -42,8 +47,7 @@ export const taskApi = {
   async deleteTask(jwt, id) {
     const res = await fetch(`${API_BASE}/task/${id}`, {
       method: 'DELETE',
-      headers: { 'Content-Type': 'application/json' },
-      body: JSON.stringify({ jwt })
+      headers: authHeaders(jwt)
     });
     if (!res.ok) {
       const errorText = await res.text();
@@ -17,11 +17,26 @@ const authReducer = (state, action) => {
   }
 };
 
+// OIDC logins hand the tokens over in the URL fragment
+const consumeOidcFragment = () => {
+  const params = new URLSearchParams(window.location.hash.slice(1));
+  if (!params.has('jwt')) {
+    return;
+  }
+  localStorage.setItem('jwt', params.get('jwt'));
+  localStorage.setItem('refresh_token', params.get('refresh_token'));
+  localStorage.setItem('username', params.get('username'));
+  window.history.replaceState(null, '', window.location.pathname + window.location.search);
+};
+
 export const AuthProvider = ({ children }) => {
-  const [state, dispatch] = useReducer(authReducer, {
-    token: localStorage.getItem('jwt'),
-    username: localStorage.getItem('username'),
-    isAuthenticated: !!localStorage.getItem('jwt')
+  const [state, dispatch] = useReducer(authReducer, null, () => {
+    consumeOidcFragment();
+    return {
+      token: localStorage.getItem('jwt'),
+      username: localStorage.getItem('username'),
+      isAuthenticated: !!localStorage.getItem('jwt')
+    };
   });
 
   return (
@@ -8,7 +8,7 @@ export const useWebSocket = (token) => {
   useEffect(() => {
     if (!token) return;
 
-    const ws = new WebSocket(`ws://localhost:6767/websocket?jwt=${token}`);
+    const ws = new WebSocket('ws://localhost:6767/websocket', ['bearer', token]);
     wsRef.current = ws;
 
     ws.onopen = () => {
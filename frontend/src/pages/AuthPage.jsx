@@ -108,14 +108,23 @@ export const AuthPage = () => {
       const sanitizedPassword = passwordValidation.sanitized;
       
       if (isLogin) {
-        const data = await authApi.login(sanitizedUsername, sanitizedPassword);
+        let data = await authApi.login(sanitizedUsername, sanitizedPassword);
+        if (data.mfa_token) {
+          const code = window.prompt('Enter the code from your authenticator app or a recovery code');
+          if (!code) {
+            throw new Error('Two-factor code required');
+          }
+          data = await authApi.loginMfa(data.mfa_token, code);
+        }
         dispatch({ type: 'LOGIN', payload: data });
         localStorage.setItem('jwt', data.jwt);
+        localStorage.setItem('refresh_token', data.refresh_token);
         localStorage.setItem('username', data.username);
       } else {
         const data = await authApi.register(sanitizedUsername, sanitizedPassword);
         dispatch({ type: 'LOGIN', payload: { jwt: data.jwt, username: sanitizedUsername } });
         localStorage.setItem('jwt', data.jwt);
+        localStorage.setItem('refresh_token', data.refresh_token);
         localStorage.setItem('username', sanitizedUsername);
       }
     } catch (err) {
@@ -192,6 +201,15 @@ export const AuthPage = () => {
           </button>
         </div>
 
+        {import.meta.env.VITE_OIDC_LOGIN === 'true' && (
+          <a
+            href={authApi.oidcLoginUrl}
+            className="block w-full mt-4 text-center border border-blue-600 text-blue-600 py-2 rounded-md hover:bg-blue-50 transition"
+          >
+            Log in with SSO
+          </a>
+        )}
+
         <button
           onClick={() => {
             setIsLogin(!isLogin);
@@ -33,6 +33,7 @@ export const Dashboard = () => {
     await authApi.logout(authState.token);
     authDispatch({ type: 'LOGOUT' });
     localStorage.removeItem('jwt');
+    localStorage.removeItem('refresh_token');
     localStorage.removeItem('username');
   };
 